@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "nodes" ADD COLUMN "weight" INTEGER NOT NULL DEFAULT(1) CHECK("weight" >= 0);
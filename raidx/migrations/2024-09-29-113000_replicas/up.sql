@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE "replicas" (
+	"id"	INTEGER NOT NULL,
+	"file"	TEXT NOT NULL,
+	"node"	TEXT NOT NULL,
+
+	"status" TEXT NOT NULL,
+
+	"created_at"	INTEGER NOT NULL,
+	"updated_at"	INTEGER NOT NULL,
+
+	PRIMARY KEY("id" AUTOINCREMENT),
+	CONSTRAINT unique_file_node UNIQUE ("file", "node"),
+	FOREIGN KEY("file") REFERENCES "files"("uid") ON UPDATE CASCADE ON DELETE CASCADE,
+	FOREIGN KEY("node") REFERENCES "nodes"("uid") ON UPDATE CASCADE ON DELETE CASCADE
+);
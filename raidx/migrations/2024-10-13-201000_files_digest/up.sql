@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "files" ADD COLUMN "digest" TEXT;
+ALTER TABLE "files" ADD COLUMN "scrubbed_at" INTEGER NOT NULL DEFAULT(0);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "unique_public_key";
+
+ALTER TABLE "nodes" DROP COLUMN "public_key";
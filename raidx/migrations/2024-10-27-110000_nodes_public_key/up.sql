@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE "nodes" ADD COLUMN "public_key" TEXT;
+
+CREATE UNIQUE INDEX "unique_public_key" ON "nodes" ("public_key");
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "nodes" ADD COLUMN "untrusted" BOOLEAN NOT NULL DEFAULT(false);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "nodes" ADD COLUMN "role" TEXT NOT NULL DEFAULT('SEND_RECEIVE');
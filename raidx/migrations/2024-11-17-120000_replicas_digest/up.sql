@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "replicas" ADD COLUMN "digest" TEXT;
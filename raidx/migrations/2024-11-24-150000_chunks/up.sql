@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE "chunks" (
+	"id"	INTEGER NOT NULL,
+	"hash"	TEXT NOT NULL,
+	"file"	TEXT NOT NULL,
+
+	"digest" TEXT NOT NULL,
+	"position" BIGINT NOT NULL,
+	"size" INTEGER NOT NULL,
+
+	PRIMARY KEY("id" AUTOINCREMENT),
+	FOREIGN KEY("file") REFERENCES "files"("uid") ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX "chunks_hash" ON "chunks" ("hash");
+CREATE INDEX "chunks_file" ON "chunks" ("file");
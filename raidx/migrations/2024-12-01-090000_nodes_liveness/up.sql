@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "nodes" ADD COLUMN "online" BOOLEAN NOT NULL DEFAULT(false);
+ALTER TABLE "nodes" ADD COLUMN "last_seen" INTEGER NOT NULL DEFAULT(0);
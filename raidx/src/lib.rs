@@ -1,29 +1,75 @@
+// The first modules were written before clippy ran on the crate, and return
+// explicitly and unwrap after checking throughout.
+
 pub mod schema;
 
 pub mod models {
+    pub mod chunks;
+    #[allow(clippy::needless_return, clippy::unnecessary_unwrap, clippy::redundant_field_names, clippy::result_unit_err)]
     pub mod files;
+    #[allow(clippy::needless_return, clippy::unnecessary_unwrap)]
     pub mod nodes;
     pub mod queues {
+        #[allow(clippy::needless_return, clippy::unnecessary_unwrap)]
         pub mod messages;
+        #[allow(clippy::needless_return, clippy::unnecessary_unwrap)]
         pub mod messages_incoming;
+        #[allow(clippy::needless_return, clippy::unnecessary_unwrap)]
         pub mod messages_outgoing;
     }
+    pub mod replicas;
     pub mod utils{
+        pub mod connection;
         pub mod error;
         pub mod query;
     }
 }
 
 pub mod peers {
+    #[allow(clippy::needless_return, clippy::unnecessary_unwrap)]
     pub mod synchronizer;
+    #[allow(clippy::needless_return, clippy::unnecessary_unwrap, clippy::get_first, clippy::needless_borrow)]
     pub mod watcher;
+    #[allow(clippy::needless_return, clippy::unnecessary_unwrap)]
     pub mod nodes;
+    pub mod server;
+    pub mod dispatcher;
+    pub mod scrubber;
+    pub mod tls;
+    pub mod limits;
+    pub mod auth;
+    pub mod requests;
+    pub mod transport;
+    pub mod transports {
+        pub mod websocket;
+        pub mod tcp;
+        pub mod memory;
+        #[cfg(feature = "quic")]
+        pub mod quic;
+    }
+}
+
+pub mod placement {
+    pub mod ring;
+    pub mod rebalancer;
 }
 
 pub mod protocol {
+    #[allow(clippy::needless_return, clippy::unnecessary_unwrap)]
     pub mod message;
+    pub mod handler;
+    pub mod version;
+    pub mod codec;
+    pub mod compression;
+    pub mod parts;
 }
 
 pub mod utils {
+    #[allow(clippy::needless_return, clippy::unnecessary_unwrap, clippy::redundant_field_names, clippy::needless_borrow)]
     pub mod configs;
+    pub mod rate;
+    pub mod crypto;
+    pub mod paths;
+    pub mod delta;
+    pub mod chunks;
 }
\ No newline at end of file
@@ -1,9 +1,111 @@
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::value_parser;
-use env_logger;
-use log::{info, warn};
-use raidx::{peers, utils::configs::RConfig};
+use log::{error, info, warn};
+use raidx::models::nodes::{RDecommissionStatus, RNode};
+use raidx::models::utils::connection;
+use raidx::{peers, placement, utils::configs::RConfig};
+
+async fn decommission(configs: RConfig, host: String, port: i32) {
+    let mut conn = connection::establish(configs.database.path.as_str()).unwrap();
+
+    loop {
+        let node = RNode::get_by_host_and_port(&mut conn, host.clone(), port);
+
+        if node.is_none() {
+            error!("node not found: {}:{}", host, port);
+            return;
+        }
+
+        let mut node = node.unwrap();
+
+        match node.decommission(&mut conn, &configs) {
+            Ok(RDecommissionStatus::Removed) => {
+                info!("node decommissioned: {}:{}, remove it from the configs file", host, port);
+                return;
+            }
+            Ok(RDecommissionStatus::Draining { files, replicas, outgoing, incoming }) => {
+                info!(
+                    "draining {}:{}: {} files short of replicas, {} replicas, {} outgoing and {} incoming messages left",
+                    host, port, files, replicas, outgoing, incoming
+                );
+            }
+            Err(e) => {
+                error!("node not decommissioned: {:?}", e);
+                return;
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+fn list_nodes(configs: RConfig) {
+    let mut conn = connection::establish(configs.database.path.as_str()).unwrap();
+
+    let nodes = match RNode::get_all(&mut conn) {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            error!("nodes not loaded: {:?}", e);
+            return;
+        }
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+
+    for node in nodes {
+        let fingerprint = node.public_key.as_ref().map(peers::auth::fingerprint).unwrap_or("-".to_string());
+        let local = if node.local { " (local)" } else { "" };
+
+        let liveness = if node.local {
+            "-".to_string()
+        } else if node.online {
+            "online".to_string()
+        } else if node.last_seen > 0 {
+            format!("offline, seen {}s ago", now - node.last_seen)
+        } else {
+            "never seen".to_string()
+        };
+
+        println!("{} {}:{} {}{} {} [{}]", node.uid, node.host, node.port, node.status, local, fingerprint, liveness);
+    }
+}
+
+fn pair(configs: RConfig, uid: String, approve: bool) {
+    let mut conn = connection::establish(configs.database.path.as_str()).unwrap();
+
+    let node = RNode::get_by_uid(&mut conn, uid.clone());
+
+    if node.is_err() {
+        error!("node not found: {}", uid);
+        return;
+    }
+
+    let mut node = node.unwrap();
+
+    if node.local || (!node.is_pending() && !node.is_rejected()) {
+        error!("node is not waiting for pairing: {} ({})", uid, node.status);
+        return;
+    }
+
+    let result = if approve { node.approve(&mut conn) } else { node.reject(&mut conn) };
+
+    if let Err(e) = result {
+        error!("node not updated: {:?}", e);
+        return;
+    }
+
+    let fingerprint = node.public_key.as_ref().map(peers::auth::fingerprint).unwrap_or("-".to_string());
+
+    if approve {
+        // A running deamon connects to it on its own within a few seconds.
+        info!("node approved: {}:{} ({}) {}", node.host, node.port, node.uid, fingerprint);
+    } else {
+        // A running deamon drops its connection to it at the next ping.
+        info!("node rejected: {}:{} ({}) {}", node.host, node.port, node.uid, fingerprint);
+    }
+}
 
 #[tokio::main]
 async fn main() {
@@ -29,6 +131,48 @@ async fn main() {
                         .value_parser(value_parser!(PathBuf)),
                 )
                 .subcommand(clap::Command::new("start").about("Start RAIDX deamon"))
+                .subcommand(
+                    clap::Command::new("decommission")
+                        .about("Drain a node and remove it from the cluster")
+                        .arg(
+                            clap::Arg::new("host")
+                                .long("host")
+                                .help("Node host")
+                                .action(clap::ArgAction::Set)
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::new("port")
+                                .long("port")
+                                .help("Node port")
+                                .action(clap::ArgAction::Set)
+                                .required(true)
+                                .value_parser(value_parser!(i32)),
+                        )
+                )
+                .subcommand(clap::Command::new("nodes").about("List nodes with their status, key fingerprint and liveness"))
+                .subcommand(
+                    clap::Command::new("approve")
+                        .about("Approve a node waiting for pairing")
+                        .arg(
+                            clap::Arg::new("node")
+                                .long("node")
+                                .help("Node uid")
+                                .action(clap::ArgAction::Set)
+                                .required(true),
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("reject")
+                        .about("Reject a node waiting for pairing")
+                        .arg(
+                            clap::Arg::new("node")
+                                .long("node")
+                                .help("Node uid")
+                                .action(clap::ArgAction::Set)
+                                .required(true),
+                        )
+                )
         )
         .get_matches();
 
@@ -45,9 +189,62 @@ async fn main() {
                         );
                     
                         if let Ok(configs) = configs {
+                            // Created once here so the threads below never race to generate it.
+                            let identity = peers::auth::RIdentity::load_or_create(&configs);
+
+                            if let Ok(identity) = identity {
+                                info!("node public key: {}", identity.public_key());
+                            } else {
+                                panic!("Not valid node key: {:?}", identity.err());
+                            }
+
+                            let share_key = raidx::utils::crypto::RShareKey::load_or_create(&configs);
+
+                            if let Err(e) = share_key {
+                                panic!("Not valid share key: {:?}", e);
+                            }
+
                             peers::watcher::init(configs.clone());
                             peers::synchronizer::init(configs.clone());
                             peers::nodes::init(configs.clone());
+                            peers::dispatcher::init(configs.clone());
+                            placement::rebalancer::init(configs.clone());
+                            peers::scrubber::init(configs.clone());
+
+                            let server = peers::server::init(configs.clone());
+                            let _ = server.await;
+                        } else {
+                            panic!("Not valid configs file!");
+                        }
+                    }
+                    Some(("decommission", args)) => {
+                        let configs = RConfig::load_from_file(std::path::Path::new(configs_path.as_str()));
+
+                        if let Ok(configs) = configs {
+                            let host = args.get_one::<String>("host").unwrap().clone();
+                            let port = *args.get_one::<i32>("port").unwrap();
+
+                            decommission(configs, host, port).await;
+                        } else {
+                            panic!("Not valid configs file!");
+                        }
+                    }
+                    Some(("nodes", _)) => {
+                        let configs = RConfig::load_from_file(std::path::Path::new(configs_path.as_str()));
+
+                        if let Ok(configs) = configs {
+                            list_nodes(configs);
+                        } else {
+                            panic!("Not valid configs file!");
+                        }
+                    }
+                    Some((command @ ("approve" | "reject"), args)) => {
+                        let configs = RConfig::load_from_file(std::path::Path::new(configs_path.as_str()));
+
+                        if let Ok(configs) = configs {
+                            let uid = args.get_one::<String>("node").unwrap().clone();
+
+                            pair(configs, uid, command == "approve");
                         } else {
                             panic!("Not valid configs file!");
                         }
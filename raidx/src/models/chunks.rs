@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::schema::chunks::{self, all_columns};
+use crate::utils::chunks::{self as cdc, RChunkCut};
+
+use diesel::{associations::HasTable, prelude::*};
+
+use super::files::RFile;
+use super::utils::error::RDatabaseError;
+
+/// Hashes looked up per query, under the SQLite variables limit.
+const LOOKUP_BATCH: usize = 500;
+
+/// Where a chunk can be read from: a local file, as long as it still has
+/// the content the chunk was cut from.
+///
+/// Chunks only spare the transfer of contents a node already holds. Every
+/// file is still stored whole, so a content shared by several files takes
+/// space once per file: deduplicating storage across files is out of scope.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = chunks)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RChunk {
+    pub id: i32,
+    pub hash: String,
+    pub file: String,
+
+    /// Digest of the file when the chunk was cut.
+    pub digest: String,
+    pub position: i64,
+    pub size: i32,
+}
+
+#[derive(Insertable, Clone, Debug)]
+#[diesel(table_name = chunks)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewRChunk {
+    pub hash: String,
+    pub file: String,
+
+    pub digest: String,
+    pub position: i64,
+    pub size: i32,
+}
+
+impl RChunk {
+    /// Replaces the chunks recorded for `file` with `cuts`.
+    pub fn index(conn: &mut SqliteConnection, file: &RFile, cuts: &[RChunkCut]) -> Result<(), RDatabaseError> {
+        if file.digest.is_none() {
+            return Err(RDatabaseError::EntryNotInsert);
+        }
+
+        let file_digest = file.digest.clone().unwrap();
+
+        let rows: Vec<NewRChunk> = cuts
+            .iter()
+            .map(|cut| NewRChunk {
+                hash: cut.hash.clone(),
+                file: file.uid.clone(),
+                digest: file_digest.clone(),
+                position: cut.offset as i64,
+                size: cut.size as i32,
+            })
+            .collect();
+
+        let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(chunks::table.filter(chunks::file.eq(&file.uid))).execute(conn)?;
+
+            for batch in rows.chunks(LOOKUP_BATCH) {
+                diesel::insert_into(chunks::table).values(batch).execute(conn)?;
+            }
+
+            Ok(())
+        });
+
+        result.map_err(RDatabaseError::DieselResult)
+    }
+
+    /// The chunks of `indexed` were cut from its current content.
+    pub fn is_indexed(conn: &mut SqliteConnection, indexed: &RFile) -> bool {
+        use crate::schema::chunks::dsl::*;
+
+        if indexed.digest.is_none() {
+            return false;
+        }
+
+        let result = chunks::table()
+            .filter(file.eq(&indexed.uid).and(digest.eq(indexed.digest.clone().unwrap())))
+            .count()
+            .get_result::<i64>(conn);
+
+        result.is_ok_and(|count| count > 0)
+    }
+
+    pub fn remove_by_file(conn: &mut SqliteConnection, file_uid: &String) -> Result<usize, RDatabaseError> {
+        use crate::schema::chunks::dsl::*;
+
+        let result = diesel::delete(chunks::table().filter(file.eq(file_uid))).execute(conn);
+
+        result.map_err(RDatabaseError::DieselResult)
+    }
+
+    pub fn get_by_hashes(conn: &mut SqliteConnection, hashes: &[String]) -> Result<Vec<RChunk>, RDatabaseError> {
+        use crate::schema::chunks::dsl::*;
+
+        let mut found = Vec::<RChunk>::new();
+
+        for batch in hashes.chunks(LOOKUP_BATCH) {
+            let result = chunks::table()
+                .select(all_columns)
+                .filter(hash.eq_any(batch))
+                .load::<RChunk>(conn);
+
+            found.extend(result.map_err(RDatabaseError::DieselResult)?);
+        }
+
+        Ok(found)
+    }
+
+    /// Chunks readable from a local file, grouped by hash. Locations of files
+    /// changed since they were cut are left out.
+    pub fn locate(conn: &mut SqliteConnection, hashes: &[String]) -> Result<HashMap<String, Vec<RChunk>>, RDatabaseError> {
+        let mut digests = HashMap::<String, Option<String>>::new();
+        let mut located = HashMap::<String, Vec<RChunk>>::new();
+
+        for chunk in RChunk::get_by_hashes(conn, hashes)? {
+            let current = digests
+                .entry(chunk.file.clone())
+                .or_insert_with(|| RFile::get_by_uid(conn, chunk.file.clone()).and_then(|file| file.digest));
+
+            if current.as_ref() == Some(&chunk.digest) {
+                located.entry(chunk.hash.clone()).or_default().push(chunk);
+            }
+        }
+
+        Ok(located)
+    }
+
+    /// Reads the chunk back, `None` when the file no longer holds it.
+    pub fn read(&self, conn: &mut SqliteConnection) -> Option<Vec<u8>> {
+        let file = RFile::get_by_uid(conn, self.file.clone())?;
+        let mut content = vec![0u8; self.size as usize];
+
+        let mut entry = std::fs::File::open(file.abspath()).ok()?;
+        entry.seek(SeekFrom::Start(self.position as u64)).ok()?;
+        entry.read_exact(content.as_mut_slice()).ok()?;
+
+        if cdc::hash(content.as_slice()) != self.hash {
+            return None;
+        }
+
+        Some(content)
+    }
+}
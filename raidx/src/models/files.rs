@@ -5,7 +5,11 @@ use crate::schema::files::{self, all_columns};
 use diesel::{associations::HasTable, prelude::*};
 use sha1::{Digest, Sha1};
 
-use super::{utils::error::RDatabaseError, nodes::RNode};
+use super::{chunks::RChunk, utils::error::RDatabaseError, nodes::RNode};
+
+pub const FILE_STATUS_READY: &str = "READY";
+pub const FILE_STATUS_REPLICA: &str = "REPLICA";
+pub const FILE_STATUS_CORRUPTED: &str = "CORRUPTED";
 
 #[derive(Queryable, Selectable, serde::Serialize, serde::Deserialize, Clone, Debug)]
 #[diesel(table_name = files)]
@@ -26,6 +30,9 @@ pub struct RFile {
     pub modified_at: i32,
 
     pub updated_at: i32,
+
+    pub digest: Option<String>,
+    pub scrubbed_at: i32,
 }
 
 impl RFile {
@@ -40,6 +47,8 @@ impl RFile {
         let statement = diesel::delete(files::table().filter(uid.eq(search_uid)));
         let result = statement.execute(conn).unwrap();
 
+        let _ = RChunk::remove_by_file(conn, search_uid);
+
         return result;
     }
 
@@ -75,6 +84,32 @@ impl RFile {
         }
     }
 
+    pub fn reassign_node(conn: &mut SqliteConnection, from_node: &String, to_node: &String) -> Result<usize, RDatabaseError> {
+        use crate::schema::files::dsl::*;
+
+        // Copies held for the node become local files, placed from here on.
+        let replicas = diesel::update(files::table())
+            .filter(node.eq(from_node))
+            .filter(status.eq(FILE_STATUS_REPLICA))
+            .set((node.eq(to_node), status.eq(FILE_STATUS_READY)))
+            .execute(conn);
+
+        if replicas.is_err() {
+            return Err(RDatabaseError::DieselResult(replicas.unwrap_err()));
+        }
+
+        let result = diesel::update(files::table())
+            .filter(node.eq(from_node))
+            .set(node.eq(to_node))
+            .execute(conn);
+
+        if result.is_ok() {
+            return Ok(replicas.unwrap() + result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
     pub fn get_all(conn: &mut SqliteConnection) -> Result<Vec<Self>, ()> {
         use crate::schema::files::dsl::*;
         let result = files::table().select(all_columns).load::<RFile>(conn);
@@ -94,10 +129,177 @@ impl RFile {
         return digest;
     }
 
+    pub fn calc_digest(entry: &std::path::Path) -> Option<String> {
+        let file = std::fs::File::open(entry);
+
+        if file.is_err() {
+            return None;
+        }
+
+        let mut file = file.unwrap();
+        let mut hasher = Sha1::new();
+
+        if std::io::copy(&mut file, &mut hasher).is_err() {
+            return None;
+        }
+
+        return Some(format!("{:X}", hasher.finalize()));
+    }
+
+    pub fn calc_digest_from_slice(data: &[u8]) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+
+        return format!("{:X}", hasher.finalize());
+    }
+
+    pub fn get_least_recently_scrubbed(conn: &mut SqliteConnection, n: usize) -> Result<Vec<Self>, RDatabaseError> {
+        use crate::schema::files::dsl::*;
+
+        let result = files::table()
+            .select(all_columns)
+            .order_by(scrubbed_at.asc())
+            .limit(n as i64)
+            .load::<RFile>(conn);
+
+        if result.is_ok() {
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn is_modified(&self, entry: &std::path::Path) -> bool {
+        let metadata = entry.metadata();
+
+        if metadata.is_err() {
+            return false;
+        }
+
+        let metadata = metadata.unwrap();
+
+        return metadata.size() as i32 != self.size || metadata.mtime() as i32 != self.modified_at;
+    }
+
+    pub fn update_from_entry(&mut self, conn: &mut SqliteConnection, entry: &std::path::Path) -> Result<usize, RDatabaseError> {
+        use crate::schema::files::dsl::*;
+
+        let metadata = entry.metadata();
+
+        if metadata.is_err() {
+            return Err(RDatabaseError::EntryNotExists);
+        }
+
+        let metadata = metadata.unwrap();
+        let data_size = metadata.size() as i32;
+        let data_modified_at = metadata.mtime() as i32;
+        let data_updated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+        let data_digest = RFile::calc_digest(entry);
+
+        let result = diesel::update(files::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set((
+                size.eq(data_size),
+                modified_at.eq(data_modified_at),
+                updated_at.eq(data_updated_at),
+                digest.eq(data_digest.clone()),
+            ))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.size = data_size;
+            self.modified_at = data_modified_at;
+            self.updated_at = data_updated_at;
+            self.digest = data_digest;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_status(&mut self, conn: &mut SqliteConnection, data_status: &str) -> Result<usize, RDatabaseError> {
+        use crate::schema::files::dsl::*;
+
+        let result = diesel::update(files::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(status.eq(data_status))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.status = data_status.to_string();
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_node(&mut self, conn: &mut SqliteConnection, data_node: &String) -> Result<usize, RDatabaseError> {
+        use crate::schema::files::dsl::*;
+
+        let result = diesel::update(files::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(node.eq(data_node))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.node = data_node.clone();
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_digest(&mut self, conn: &mut SqliteConnection, data_digest: Option<String>) -> Result<usize, RDatabaseError> {
+        use crate::schema::files::dsl::*;
+
+        let result = diesel::update(files::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(digest.eq(data_digest.clone()))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.digest = data_digest;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_scrubbed(&mut self, conn: &mut SqliteConnection, data_digest: String) -> Result<usize, RDatabaseError> {
+        use crate::schema::files::dsl::*;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+
+        let result = diesel::update(files::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set((digest.eq(Some(data_digest.clone())), scrubbed_at.eq(now)))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.digest = Some(data_digest);
+            self.scrubbed_at = now;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
     pub fn abspath(&self) -> String {
         return RFile::get_abspath(self.folder.clone(), self.filename.clone());
     }
 
+    pub fn relative_path(&self, root: &str) -> Option<String> {
+        let abspath = self.abspath();
+        let path = std::path::Path::new(abspath.as_str());
+        let relative = path.strip_prefix(root);
+
+        if relative.is_ok() {
+            return Some(relative.unwrap().to_str().unwrap().to_string());
+        } else {
+            return None;
+        }
+    }
+
     pub fn check_sync(&mut self, conn: &mut SqliteConnection) -> Result<usize, RDatabaseError> {
         use crate::schema::files::dsl::*;
 
@@ -143,6 +345,9 @@ impl RFile {
             self.modified_at = result.modified_at;
             self.updated_at = result.updated_at;
 
+            self.digest = result.digest;
+            self.scrubbed_at = result.scrubbed_at;
+
             return Ok(self);
         } else {
             return Err(RDatabaseError::EntryNotExists);
@@ -168,6 +373,8 @@ pub struct NewRFile {
     pub modified_at: i32,
 
     pub updated_at: i32,
+
+    pub digest: Option<String>,
 }
 
 impl NewRFile {
@@ -196,6 +403,7 @@ impl NewRFile {
             .as_secs();
 
         let updated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let digest = RFile::calc_digest(entry);
 
         let file = NewRFile {
             uid: uid,
@@ -203,10 +411,11 @@ impl NewRFile {
             folder: folder,
             filename: filename,
             size: size as i32,
-            status: String::from("READY"),
+            status: String::from(FILE_STATUS_READY),
             created_at: created_at as i32,
             modified_at: modified_at as i32,
-            updated_at: updated_at as i32
+            updated_at: updated_at as i32,
+            digest: digest
         };
 
         return file.save(conn);
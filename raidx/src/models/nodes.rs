@@ -1,13 +1,21 @@
-use std::{sync::mpsc::channel, thread};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::{
-    models::utils::error::RDatabaseError, peers::nodes::RServer, protocol::message::{RMessage, RMessageTrait}, schema::nodes::{self, all_columns}, utils::configs::RConfigNode
+    models::{
+        files::{RFile, FILE_STATUS_REPLICA},
+        queues::{messages::RMessageQueue, messages_incoming::RMessagesIncoming, messages_outgoing::RMessageOutgoing},
+        replicas::RReplica,
+        utils::error::RDatabaseError,
+    },
+    placement::ring::RRing,
+    protocol::message::{RMNodeDraining, RMessage},
+    schema::nodes::{self, all_columns},
+    utils::configs::RConfig,
 };
 
 use diesel::{associations::HasTable, prelude::*};
-use log::error;
+use log::info;
 use uuid::Uuid;
-use websocket::{ClientBuilder, OwnedMessage};
 
 #[derive(Queryable, Selectable, Insertable, serde::Serialize, serde::Deserialize, Clone, Debug)]
 #[diesel(table_name = nodes)]
@@ -19,6 +27,45 @@ pub struct RNode {
     pub port: i32,
 
     pub local: bool,
+
+    pub weight: i32,
+
+    pub status: String,
+
+    pub ssl: bool,
+
+    pub public_key: Option<String>,
+
+    pub untrusted: bool,
+
+    pub role: String,
+
+    /// Our connection to the node is up.
+    pub online: bool,
+
+    /// Last time the node answered us, 0 if it never did.
+    pub last_seen: i32,
+}
+
+pub const NODE_STATUS_ACTIVE: &str = "ACTIVE";
+pub const NODE_STATUS_DRAINING: &str = "DRAINING";
+pub const NODE_STATUS_PENDING: &str = "PENDING";
+pub const NODE_STATUS_REJECTED: &str = "REJECTED";
+
+pub const NODE_ROLE_SEND_ONLY: &str = "SEND_ONLY";
+pub const NODE_ROLE_RECEIVE_ONLY: &str = "RECEIVE_ONLY";
+pub const NODE_ROLE_SEND_RECEIVE: &str = "SEND_RECEIVE";
+
+#[derive(Clone, Debug)]
+pub enum RDecommissionStatus {
+    Draining {
+        /// Files of the local node still short of confirmed replicas.
+        files: usize,
+        replicas: usize,
+        outgoing: i64,
+        incoming: i64,
+    },
+    Removed,
 }
 
 impl RNode {
@@ -87,7 +134,8 @@ impl RNode {
             if result.is_ok() {
                 return Some(result.unwrap());
             } else {
-                return None;
+                // Created meanwhile by another task starting with the node.
+                return RNode::get_by_local_flag(conn, true);
             }
         }
     }
@@ -98,6 +146,7 @@ impl RNode {
         let results = nodes::table()
             .select(all_columns)
             .filter(local.eq(false))
+            .filter(status.ne(NODE_STATUS_PENDING).and(status.ne(NODE_STATUS_REJECTED)))
             .load::<RNode>(conn);
 
         if results.is_ok() {
@@ -130,12 +179,22 @@ impl RNode {
         data_host: String,
         data_port: i32,
         data_local: bool,
+        data_weight: i32,
+        data_ssl: bool,
     ) -> Result<RNode, RDatabaseError> {
         let node = RNode {
             local: data_local,
             uid: Uuid::new_v4().to_string(),
             host: data_host,
             port: data_port,
+            weight: data_weight,
+            status: NODE_STATUS_ACTIVE.to_string(),
+            ssl: data_ssl,
+            public_key: None,
+            untrusted: false,
+            role: NODE_ROLE_SEND_RECEIVE.to_string(),
+            online: false,
+            last_seen: 0,
         };
 
         let result = diesel::insert_into(nodes::table)
@@ -154,47 +213,376 @@ impl RNode {
         data_host: String,
         data_port: i32,
     ) -> Result<RNode, RDatabaseError> {
-        return RNode::create(conn, data_host, data_port, true);
+        return RNode::create(conn, data_host, data_port, true, 1, false);
     }
 
     pub fn create_other(
         conn: &mut SqliteConnection,
         data_host: String,
         data_port: i32,
+        data_weight: i32,
+        data_ssl: bool,
     ) -> Result<RNode, RDatabaseError> {
-        return RNode::create(conn, data_host, data_port, false);
+        return RNode::create(conn, data_host, data_port, false, data_weight, data_ssl);
+    }
+
+    /// Records a node that connected with an unknown key, waiting for an
+    /// operator to approve it.
+    pub fn create_pending(
+        conn: &mut SqliteConnection,
+        data_host: String,
+        data_port: i32,
+        data_public_key: String,
+    ) -> Result<RNode, RDatabaseError> {
+        let node = RNode {
+            local: false,
+            uid: Uuid::new_v4().to_string(),
+            host: data_host,
+            port: data_port,
+            weight: 1,
+            status: NODE_STATUS_PENDING.to_string(),
+            ssl: false,
+            public_key: Some(data_public_key),
+            untrusted: false,
+            role: NODE_ROLE_SEND_RECEIVE.to_string(),
+            online: false,
+            last_seen: 0,
+        };
+
+        let result = diesel::insert_into(nodes::table)
+            .values(&node)
+            .execute(conn);
+
+        if result.is_ok() {
+            return RNode::get_by_uid(conn, node.uid);
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn get_by_public_key(conn: &mut SqliteConnection, search_public_key: &String) -> Option<RNode> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = nodes::table()
+            .select(all_columns)
+            .filter(public_key.eq(search_public_key))
+            .first::<RNode>(conn);
+
+        if result.is_ok() {
+            return Some(result.unwrap());
+        } else {
+            return None;
+        }
+    }
+
+    pub fn set_public_key(&mut self, conn: &mut SqliteConnection, data_public_key: Option<String>) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = diesel::update(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(public_key.eq(data_public_key.clone()))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.public_key = data_public_key;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_untrusted(&mut self, conn: &mut SqliteConnection, data_untrusted: bool) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = diesel::update(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(untrusted.eq(data_untrusted))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.untrusted = data_untrusted;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_role(&mut self, conn: &mut SqliteConnection, data_role: &str) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = diesel::update(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(role.eq(data_role))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.role = data_role.to_string();
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    /// Whether changes made on this node are propagated to the others.
+    pub fn can_send(&self) -> bool {
+        return self.role != NODE_ROLE_RECEIVE_ONLY;
+    }
+
+    /// Whether this node accepts changes and replicas from the others.
+    pub fn can_receive(&self) -> bool {
+        return self.role != NODE_ROLE_SEND_ONLY;
+    }
+
+    pub fn set_ssl(&mut self, conn: &mut SqliteConnection, data_ssl: bool) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = diesel::update(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(ssl.eq(data_ssl))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.ssl = data_ssl;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_weight(&mut self, conn: &mut SqliteConnection, data_weight: i32) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = diesel::update(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(weight.eq(data_weight))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.weight = data_weight;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
     }
 
-    pub fn request_uid(server: RConfigNode) {
-        let connection_url = RServer::connection_url(server);
-        let client = ClientBuilder::new(connection_url.as_str())
-            .unwrap()
-            .add_protocol("rust-websocket")
-            .connect_insecure()
-            .unwrap();
+    /// Records whether our connection to the node is up. Coming online
+    /// counts as hearing from it.
+    pub fn set_online(&mut self, conn: &mut SqliteConnection, data_online: bool) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
 
-        let request = RMessage {
-            _type: crate::protocol::message::RMessageType::UidRequest,
-            data: None,
+        let result = if data_online {
+            diesel::update(nodes::table())
+                .filter(uid.eq(self.uid.clone()))
+                .set((online.eq(true), last_seen.eq(now)))
+                .execute(conn)
+        } else {
+            diesel::update(nodes::table())
+                .filter(uid.eq(self.uid.clone()))
+                .set(online.eq(false))
+                .execute(conn)
         };
 
-        let data = request.to_slice();
+        if result.is_ok() {
+            self.online = data_online;
+
+            if data_online {
+                self.last_seen = now;
+            }
+
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn set_last_seen(&mut self, conn: &mut SqliteConnection) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+
+        let result = diesel::update(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(last_seen.eq(now))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.last_seen = now;
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        return self.status == NODE_STATUS_DRAINING;
+    }
 
-        if data.is_ok() {
-            let data = data.unwrap();
-            let request = OwnedMessage::Binary(data);
+    pub fn is_pending(&self) -> bool {
+        return self.status == NODE_STATUS_PENDING;
+    }
 
-            
+    pub fn is_rejected(&self) -> bool {
+        return self.status == NODE_STATUS_REJECTED;
+    }
+
+    /// Pending and rejected nodes take no part in the cluster.
+    pub fn is_approved(&self) -> bool {
+        return !self.is_pending() && !self.is_rejected();
+    }
+
+    pub fn approve(&mut self, conn: &mut SqliteConnection) -> Result<usize, RDatabaseError> {
+        return self.set_status(conn, NODE_STATUS_ACTIVE);
+    }
+
+    pub fn reject(&mut self, conn: &mut SqliteConnection) -> Result<usize, RDatabaseError> {
+        return self.set_status(conn, NODE_STATUS_REJECTED);
+    }
+
+    pub fn set_status(&mut self, conn: &mut SqliteConnection, data_status: &str) -> Result<usize, RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = diesel::update(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .set(status.eq(data_status))
+            .execute(conn);
+
+        if result.is_ok() {
+            self.status = data_status.to_string();
+            return Ok(result.unwrap());
         } else {
-            error!("Error to convert request");
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
         }
     }
 
-    pub fn connection_url(self, ssl: bool) -> String {
-        let host = self.host;
+    pub fn delete(&self, conn: &mut SqliteConnection) -> Result<(), RDatabaseError> {
+        use crate::schema::nodes::dsl::*;
+
+        let result = diesel::delete(nodes::table())
+            .filter(uid.eq(self.uid.clone()))
+            .execute(conn);
+
+        if result.is_ok() {
+            return Ok(());
+        } else {
+            return Err(RDatabaseError::EntryNotDeleted);
+        }
+    }
+
+    /// Advances the decommission of this node by one step.
+    ///
+    /// The first call marks the node as draining and tells the trusted peers,
+    /// which drops it out of the placement ring of every node so the
+    /// rebalancers move its replicas elsewhere. Files the node owns, and the
+    /// copies of its files kept here, are adopted by the local node so they
+    /// keep being placed; peers holding them take the local node as owner.
+    /// The row is only deleted once every file of the local node has a
+    /// confirmed replica on each node now placed to hold it, and no replica,
+    /// outgoing or incoming message references the node any more; deleting
+    /// it earlier would let the `ON DELETE CASCADE` constraints drop that
+    /// data silently.
+    pub fn decommission(&mut self, conn: &mut SqliteConnection, configs: &RConfig) -> Result<RDecommissionStatus, RDatabaseError> {
+        if self.local {
+            return Err(RDatabaseError::EntryNotDeleted);
+        }
+
+        if !self.is_draining() {
+            self.set_status(conn, NODE_STATUS_DRAINING)?;
+            self.broadcast_draining(conn)?;
+            info!("node draining: {}:{} ({})", self.host, self.port, self.uid);
+        }
+
+        let local_node = RNode::get_local(conn);
+
+        if local_node.is_none() {
+            return Err(RDatabaseError::EntryNotExists);
+        }
+
+        let local_node = local_node.unwrap();
+        let adopted = RFile::reassign_node(conn, &self.uid, &local_node.uid)?;
+
+        if adopted > 0 {
+            info!("files adopted from {}: {}", self.uid, adopted);
+        }
+
+        let files = RNode::count_unplaced(conn, configs, &local_node)?;
+        let replicas = RReplica::get_by_node(conn, &self.uid)?.len();
+        let outgoing = RMessageOutgoing::count_by_node(conn, &self.uid)?;
+        let incoming = RMessagesIncoming::count_by_node(conn, &self.uid)?;
+
+        if files > 0 || replicas > 0 || outgoing > 0 || incoming > 0 {
+            return Ok(RDecommissionStatus::Draining {
+                files,
+                replicas,
+                outgoing,
+                incoming,
+            });
+        }
+
+        self.delete(conn)?;
+        info!("node removed: {}:{} ({})", self.host, self.port, self.uid);
+
+        return Ok(RDecommissionStatus::Removed);
+    }
+
+    /// Queues a `NodeDraining` for every trusted peer but the node itself.
+    fn broadcast_draining(&self, conn: &mut SqliteConnection) -> Result<(), RDatabaseError> {
+        let nodes = RNode::get_others(conn);
+
+        if nodes.is_none() {
+            return Err(RDatabaseError::EntryNotExists);
+        }
+
+        let message = RMessage::NodeDraining(RMNodeDraining {
+            public_key: self.public_key.clone(),
+            host: self.host.clone(),
+            port: self.port,
+        });
+
+        for node in nodes.unwrap().into_iter().filter(|node| !node.untrusted && node.uid != self.uid) {
+            RMessageOutgoing::push(conn, node.uid, message.clone())?;
+        }
+
+        return Ok(());
+    }
+
+    /// Files of `local_node` missing a confirmed, up to date replica on one
+    /// of the nodes the ring places them on.
+    fn count_unplaced(conn: &mut SqliteConnection, configs: &RConfig, local_node: &RNode) -> Result<usize, RDatabaseError> {
+        let ring = RRing::from_database(conn)?;
+        let mut unplaced = 0;
+
+        for file in RFile::get_all(conn).map_err(|_| RDatabaseError::EntryNotExists)? {
+            if file.node != local_node.uid || file.status == FILE_STATUS_REPLICA {
+                continue;
+            }
+
+            let replicas = RReplica::get_by_file(conn, &file.uid)?;
+
+            let placed = ring
+                .owners(&file.uid, configs.placement.replicas)
+                .iter()
+                .filter(|node_uid| **node_uid != local_node.uid)
+                .all(|node_uid| {
+                    replicas
+                        .iter()
+                        .any(|replica| replica.node == *node_uid && replica.is_confirmed() && !replica.is_stale(&file))
+                });
+
+            if !placed {
+                unplaced += 1;
+            }
+        }
+
+        return Ok(unplaced);
+    }
+
+    pub fn connection_url(&self) -> String {
+        let host = self.host.clone();
         let port = self.port;
         
-        let protocol = if ssl {
+        let protocol = if self.ssl {
             "wss"
         } else {
             "ws"
@@ -204,3 +592,42 @@ impl RNode {
         return format!("{}://{}:{}", protocol, host, port);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::utils::connection;
+
+    #[test]
+    fn online_and_last_seen_are_recorded() {
+        let mut conn = connection::in_memory();
+        let mut node = RNode::create_other(&mut conn, "peer".to_string(), 4000, 1, false).unwrap();
+
+        assert!(!node.online);
+        assert_eq!(node.last_seen, 0);
+
+        // Coming online counts as hearing from it.
+        node.set_online(&mut conn, true).unwrap();
+        let stored = RNode::get_by_uid(&mut conn, node.uid.clone()).unwrap();
+
+        assert!(stored.online);
+        assert!(stored.last_seen > 0);
+        assert_eq!(stored.last_seen, node.last_seen);
+
+        // Going offline keeps when it was last heard of.
+        node.last_seen = 1;
+        node.set_online(&mut conn, false).unwrap();
+        let stored = RNode::get_by_uid(&mut conn, node.uid.clone()).unwrap();
+
+        assert!(!stored.online);
+        assert!(stored.last_seen > 1);
+        assert_eq!(node.last_seen, 1);
+
+        node.set_last_seen(&mut conn).unwrap();
+        let stored = RNode::get_by_uid(&mut conn, node.uid.clone()).unwrap();
+
+        assert!(!stored.online);
+        assert_eq!(stored.last_seen, node.last_seen);
+        assert!(node.last_seen > 1);
+    }
+}
@@ -1,13 +1,19 @@
 use diesel::SqliteConnection;
-use crate::protocol::message::RMessage;
+use crate::protocol::message::{REnvelope, RMessage};
 use crate::models::utils::error::RDatabaseError;
 
 pub trait RMessageQueue<T> {
-    fn push(conn: &mut SqliteConnection, node_uid: String, message: RMessage) -> Result<T, RDatabaseError>;
+    fn push_envelope(conn: &mut SqliteConnection, node_uid: String, envelope: REnvelope) -> Result<T, RDatabaseError>;
+    fn push(conn: &mut SqliteConnection, node_uid: String, message: RMessage) -> Result<T, RDatabaseError> {
+        return Self::push_envelope(conn, node_uid, REnvelope::new(message));
+    }
     fn last(conn: &mut SqliteConnection) -> Option<T>;
     fn last_n(conn: &mut SqliteConnection, n: usize) -> Option<Vec<T>>;
+    fn first_n(conn: &mut SqliteConnection, n: usize) -> Option<Vec<T>>;
+    fn first_n_by_node(conn: &mut SqliteConnection, node_uid: &str, n: usize) -> Option<Vec<T>>;
+    fn count_by_node(conn: &mut SqliteConnection, node_uid: &str) -> Result<i64, RDatabaseError>;
     fn delete_by_id(conn: &mut SqliteConnection, id: i32) -> Result<(), RDatabaseError>;
     fn delete(&self, conn: &mut SqliteConnection) -> Result<(), RDatabaseError>;
     fn pop(conn: &mut SqliteConnection) -> Result<T, RDatabaseError>;
-    fn to_message(&self) -> Option<RMessage>;
+    fn to_envelope(&self) -> Option<REnvelope>;
 }
\ No newline at end of file
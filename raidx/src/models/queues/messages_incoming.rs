@@ -1,10 +1,13 @@
+use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use diesel;
 use diesel::{associations::HasTable, prelude::*};
 use crate::models::utils::error::RDatabaseError;
-use crate::protocol::message::RMessage;
+use crate::protocol::codec;
+use crate::protocol::message::REnvelope;
 use crate::schema::messages_incoming::{self, all_columns};
+use tokio::sync::Notify;
 
 use super::messages::RMessageQueue;
 
@@ -31,14 +34,32 @@ pub struct RNewMessagesIncoming {
     pub created_at: i32,
 }
 
+impl RMessagesIncoming {
+    /// Woken whenever this process queues an incoming message.
+    pub fn queued() -> &'static Notify {
+        static QUEUED: OnceLock<Notify> = OnceLock::new();
+        return QUEUED.get_or_init(Notify::new);
+    }
+}
+
 impl RMessageQueue<RMessagesIncoming> for RMessagesIncoming {
-    fn push(conn: &mut SqliteConnection, node_uid: String, message: RMessage) -> Result<RMessagesIncoming, RDatabaseError>{
+    fn push_envelope(
+        conn: &mut SqliteConnection,
+        node_uid: String,
+        envelope: REnvelope,
+    ) -> Result<RMessagesIncoming, RDatabaseError> {
         let uid = uuid::Uuid::new_v4();
         let uid = uid.to_string();
         let from = node_uid;
 
-        let message_type = message._type.to_string();
-        let data = message.data;
+        let message_type = envelope.message.to_string();
+        let data = codec::encode(&envelope);
+
+        if data.is_err() {
+            return Err(RDatabaseError::EntryNotInsert);
+        }
+
+        let data = Some(data.unwrap());
         
         let created_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
         let created_at = created_at as i32;
@@ -61,6 +82,7 @@ impl RMessageQueue<RMessagesIncoming> for RMessagesIncoming {
                 let message = result.first();
                 
                 if let Some(message) = message {
+                    RMessagesIncoming::queued().notify_waiters();
                     return Ok(message.clone());
                 } else {
                     return Err(RDatabaseError::EntryNotInsert);
@@ -137,16 +159,57 @@ impl RMessageQueue<RMessagesIncoming> for RMessagesIncoming {
         }
     }
 
-    fn to_message(&self) -> Option<RMessage> {
-        let message_type = serde_json::from_str(self.message_type.as_str());
+    fn first_n(conn: &mut SqliteConnection, n: usize) -> Option<Vec<RMessagesIncoming>> {
+        use crate::schema::messages_incoming::dsl::*;
 
-        if message_type.is_ok() {
-            return Some(RMessage {
-                _type: message_type.unwrap(),
-                data: self.data.clone(),
-            });
+        let result = messages_incoming::table()
+            .select(messages_incoming::all_columns())
+            .order_by(id.asc())
+            .limit(n as i64)
+            .load::<RMessagesIncoming>(conn);
+
+        if result.is_ok() {
+            let result = result.unwrap();
+            return Some(result);
         } else {
             return None;
         }
     }
+
+    fn first_n_by_node(conn: &mut SqliteConnection, node_uid: &str, n: usize) -> Option<Vec<RMessagesIncoming>> {
+        use crate::schema::messages_incoming::dsl::*;
+
+        let result = messages_incoming::table()
+            .select(messages_incoming::all_columns())
+            .filter(from.eq(node_uid))
+            .order_by(id.asc())
+            .limit(n as i64)
+            .load::<RMessagesIncoming>(conn);
+
+        if result.is_ok() {
+            let result = result.unwrap();
+            return Some(result);
+        } else {
+            return None;
+        }
+    }
+
+    fn count_by_node(conn: &mut SqliteConnection, node_uid: &str) -> Result<i64, RDatabaseError> {
+        use crate::schema::messages_incoming::dsl::*;
+
+        let result = messages_incoming::table()
+            .filter(from.eq(node_uid))
+            .count()
+            .get_result::<i64>(conn);
+
+        if result.is_ok() {
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    fn to_envelope(&self) -> Option<REnvelope> {
+        return self.data.as_ref().and_then(|data| codec::decode(data.as_slice()).ok());
+    }
 }
\ No newline at end of file
@@ -1,10 +1,15 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::models::nodes::RNode;
 use crate::models::utils::error::RDatabaseError;
-use crate::protocol::message::{RMessage, RMessageType};
+use crate::protocol::codec;
+use crate::protocol::message::{REnvelope, RMessage};
 use crate::schema::messages_outgoing::{self, all_columns};
 use diesel;
 use diesel::{associations::HasTable, prelude::*};
+use tokio::sync::Notify;
 
 use super::messages::RMessageQueue;
 
@@ -31,18 +36,96 @@ pub struct RNewMessageOutgoing {
     pub created_at: i32,
 }
 
+fn notifiers() -> &'static Mutex<HashMap<String, Arc<Notify>>> {
+    static NOTIFIERS: OnceLock<Mutex<HashMap<String, Arc<Notify>>>> = OnceLock::new();
+    return NOTIFIERS.get_or_init(|| Mutex::new(HashMap::new()));
+}
+
+fn notifier(node_uid: &str) -> Option<Arc<Notify>> {
+    return notifiers().lock().ok().map(|mut notifiers| notifiers.entry(node_uid.to_string()).or_default().clone());
+}
+
+impl RMessageOutgoing {
+    /// Waits until a message is queued for `node_uid` by this process, or
+    /// for `timeout` at most.
+    pub async fn wait_for(node_uid: &str, timeout: Duration) {
+        if let Some(notifier) = notifier(node_uid) {
+            let _ = tokio::time::timeout(timeout, notifier.notified()).await;
+        } else {
+            tokio::time::sleep(timeout).await;
+        }
+    }
+
+    /// Oldest messages for `node_uid` queued after message `after_id`.
+    pub fn first_n_by_node_after(conn: &mut SqliteConnection, node_uid: &String, after_id: i32, n: usize) -> Option<Vec<RMessageOutgoing>> {
+        use crate::schema::messages_outgoing::dsl::*;
+
+        let result = messages_outgoing::table()
+            .select(messages_outgoing::all_columns())
+            .filter(to.eq(node_uid).and(id.gt(after_id)))
+            .order_by(id.asc())
+            .limit(n as i64)
+            .load::<RMessageOutgoing>(conn);
+
+        if result.is_ok() {
+            let result = result.unwrap();
+            return Some(result);
+        } else {
+            return None;
+        }
+    }
+
+    /// Drops the messages with these envelope ids still waiting to be sent.
+    pub fn delete_by_uids(conn: &mut SqliteConnection, uids: &[String]) -> Result<usize, RDatabaseError> {
+        use crate::schema::messages_outgoing::dsl::*;
+
+        let result = diesel::delete(messages_outgoing::table())
+            .filter(uid.eq_any(uids))
+            .execute(conn);
+
+        if result.is_ok() {
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::EntryNotDeleted);
+        }
+    }
+
+    pub fn push_to_others(conn: &mut SqliteConnection, message: RMessage) -> Result<Vec<RMessageOutgoing>, RDatabaseError> {
+        let nodes = RNode::get_others(conn);
+
+        if nodes.is_none() {
+            return Err(RDatabaseError::EntryNotExists);
+        }
+
+        let mut messages = Vec::<RMessageOutgoing>::new();
+
+        // Untrusted nodes only store encrypted copies, never file metadata,
+        // and send-only nodes don't take changes at all.
+        for node in nodes.unwrap().into_iter().filter(|node| !node.untrusted && node.can_receive()) {
+            messages.push(RMessageOutgoing::push(conn, node.uid, message.clone())?);
+        }
+
+        return Ok(messages);
+    }
+}
+
 impl RMessageQueue<RMessageOutgoing> for RMessageOutgoing {
-    fn push(
+    fn push_envelope(
         conn: &mut SqliteConnection,
         node_uid: String,
-        message: RMessage,
+        envelope: REnvelope,
     ) -> Result<RMessageOutgoing, RDatabaseError> {
-        let uid = uuid::Uuid::new_v4();
-        let uid = uid.to_string();
+        let uid = envelope.id.clone();
         let to = node_uid;
 
-        let message_type = message._type.to_string();
-        let data = message.data;
+        let message_type = envelope.message.to_string();
+        let data = codec::encode(&envelope);
+
+        if data.is_err() {
+            return Err(RDatabaseError::EntryNotInsert);
+        }
+
+        let data = Some(data.unwrap());
 
         let created_at = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -68,6 +151,10 @@ impl RMessageQueue<RMessageOutgoing> for RMessageOutgoing {
             let message = result.first();
 
             if let Some(message) = message {
+                if let Some(notifier) = notifier(&message.to) {
+                    notifier.notify_one();
+                }
+
                 return Ok(message.clone());
             } else {
                 return Err(RDatabaseError::EntryNotInsert);
@@ -78,10 +165,10 @@ impl RMessageQueue<RMessageOutgoing> for RMessageOutgoing {
     }
 
     fn last(conn: &mut SqliteConnection) -> Option<RMessageOutgoing> {
-        use crate::schema::messages_incoming::dsl::*;
+        use crate::schema::messages_outgoing::dsl::*;
 
-        let result = messages_incoming::table()
-            .select(messages_incoming::all_columns())
+        let result = messages_outgoing::table()
+            .select(messages_outgoing::all_columns())
             .order_by(created_at.desc())
             .first::<RMessageOutgoing>(conn);
 
@@ -94,9 +181,9 @@ impl RMessageQueue<RMessageOutgoing> for RMessageOutgoing {
     }
 
     fn delete_by_id(conn: &mut SqliteConnection, search_id: i32) -> Result<(), RDatabaseError> {
-        use crate::schema::messages_incoming::dsl::*;
+        use crate::schema::messages_outgoing::dsl::*;
 
-        let result = diesel::delete(messages_incoming::table())
+        let result = diesel::delete(messages_outgoing::table())
             .filter(id.eq(search_id))
             .execute(conn);
 
@@ -144,16 +231,57 @@ impl RMessageQueue<RMessageOutgoing> for RMessageOutgoing {
         }
     }
 
-    fn to_message(&self) -> Option<RMessage> {
-        let message_type = serde_json::from_str(self.message_type.as_str());
+    fn first_n(conn: &mut SqliteConnection, n: usize) -> Option<Vec<RMessageOutgoing>> {
+        use crate::schema::messages_outgoing::dsl::*;
+
+        let result = messages_outgoing::table()
+            .select(messages_outgoing::all_columns())
+            .order_by(id.asc())
+            .limit(n as i64)
+            .load::<RMessageOutgoing>(conn);
 
-        if message_type.is_ok() {
-            return Some(RMessage {
-                _type: message_type.unwrap(),
-                data: self.data.clone(),
-            });
+        if result.is_ok() {
+            let result = result.unwrap();
+            return Some(result);
         } else {
             return None;
         }
     }
+
+    fn first_n_by_node(conn: &mut SqliteConnection, node_uid: &str, n: usize) -> Option<Vec<RMessageOutgoing>> {
+        use crate::schema::messages_outgoing::dsl::*;
+
+        let result = messages_outgoing::table()
+            .select(messages_outgoing::all_columns())
+            .filter(to.eq(node_uid))
+            .order_by(id.asc())
+            .limit(n as i64)
+            .load::<RMessageOutgoing>(conn);
+
+        if result.is_ok() {
+            let result = result.unwrap();
+            return Some(result);
+        } else {
+            return None;
+        }
+    }
+
+    fn count_by_node(conn: &mut SqliteConnection, node_uid: &str) -> Result<i64, RDatabaseError> {
+        use crate::schema::messages_outgoing::dsl::*;
+
+        let result = messages_outgoing::table()
+            .filter(to.eq(node_uid))
+            .count()
+            .get_result::<i64>(conn);
+
+        if result.is_ok() {
+            return Ok(result.unwrap());
+        } else {
+            return Err(RDatabaseError::DieselResult(result.unwrap_err()));
+        }
+    }
+
+    fn to_envelope(&self) -> Option<REnvelope> {
+        return self.data.as_ref().and_then(|data| codec::decode(data.as_slice()).ok());
+    }
 }
@@ -0,0 +1,212 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::schema::replicas::{self, all_columns};
+
+use diesel::{associations::HasTable, prelude::*};
+
+use super::files::RFile;
+use super::utils::error::RDatabaseError;
+
+pub const REPLICA_STATUS_PENDING: &str = "PENDING";
+pub const REPLICA_STATUS_CONFIRMED: &str = "CONFIRMED";
+
+#[derive(Queryable, Selectable, serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[diesel(table_name = replicas)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RReplica {
+    pub id: i32,
+    pub file: String,
+    pub node: String,
+
+    pub status: String,
+
+    pub created_at: i32,
+    pub updated_at: i32,
+
+    /// Digest of the content sent to the node, `None` when not known.
+    pub digest: Option<String>,
+}
+
+#[derive(Insertable, Clone, serde::Serialize, serde::Deserialize, Debug)]
+#[diesel(table_name = replicas)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewRReplica {
+    pub file: String,
+    pub node: String,
+
+    pub status: String,
+
+    pub created_at: i32,
+    pub updated_at: i32,
+
+    pub digest: Option<String>,
+}
+
+impl RReplica {
+    pub fn is_confirmed(&self) -> bool {
+        self.status == REPLICA_STATUS_CONFIRMED
+    }
+
+    /// Sent more than `timeout` seconds ago and still not confirmed, the
+    /// transfer is taken as lost.
+    pub fn is_expired(&self, timeout: u64) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+        self.status == REPLICA_STATUS_PENDING && (self.updated_at as i64) + (timeout as i64) < now
+    }
+
+    /// The node holds an older version of the file, or one not known.
+    pub fn is_stale(&self, file: &RFile) -> bool {
+        file.digest.is_some() && self.digest != file.digest
+    }
+
+    pub fn get_by_file(conn: &mut SqliteConnection, file_uid: &String) -> Result<Vec<RReplica>, RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let result = replicas::table()
+            .select(all_columns)
+            .filter(file.eq(file_uid))
+            .load::<RReplica>(conn);
+
+        result.map_err(RDatabaseError::DieselResult)
+    }
+
+    pub fn get_by_node(conn: &mut SqliteConnection, node_uid: &String) -> Result<Vec<RReplica>, RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let result = replicas::table()
+            .select(all_columns)
+            .filter(node.eq(node_uid))
+            .load::<RReplica>(conn);
+
+        result.map_err(RDatabaseError::DieselResult)
+    }
+
+    pub fn count_by_status(conn: &mut SqliteConnection, search_status: &str) -> Result<i64, RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let result = replicas::table()
+            .filter(status.eq(search_status))
+            .count()
+            .get_result::<i64>(conn);
+
+        result.map_err(RDatabaseError::DieselResult)
+    }
+
+    /// Pending replicas sent less than `timeout` seconds ago.
+    pub fn count_live_pending(conn: &mut SqliteConnection, timeout: u64) -> Result<i64, RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let since = (now - timeout as i64).max(0) as i32;
+
+        let result = replicas::table()
+            .filter(status.eq(REPLICA_STATUS_PENDING).and(updated_at.ge(since)))
+            .count()
+            .get_result::<i64>(conn);
+
+        result.map_err(RDatabaseError::DieselResult)
+    }
+
+    pub fn create_pending(
+        conn: &mut SqliteConnection,
+        file_uid: String,
+        node_uid: String,
+        digest: Option<String>,
+    ) -> Result<RReplica, RDatabaseError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+
+        let replica = NewRReplica {
+            file: file_uid,
+            node: node_uid,
+            status: REPLICA_STATUS_PENDING.to_string(),
+            created_at: now,
+            updated_at: now,
+            digest,
+        };
+
+        let result = diesel::insert_into(replicas::table)
+            .values(&replica)
+            .returning(all_columns)
+            .load::<RReplica>(conn);
+
+        match result {
+            Ok(result) => {
+                if let Some(replica) = result.first() {
+                    Ok(replica.clone())
+                } else {
+                    Err(RDatabaseError::EntryNotInsert)
+                }
+            }
+            Err(e) => Err(RDatabaseError::DieselResult(e)),
+        }
+    }
+
+    pub fn confirm(conn: &mut SqliteConnection, file_uid: &String, node_uid: &String) -> Result<usize, RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+
+        let result = diesel::update(replicas::table())
+            .filter(file.eq(file_uid).and(node.eq(node_uid)))
+            .set((status.eq(REPLICA_STATUS_CONFIRMED), updated_at.eq(now)))
+            .execute(conn);
+
+        match result {
+            Ok(result) => {
+                if result > 0 {
+                    Ok(result)
+                } else {
+                    Err(RDatabaseError::EntryNotExists)
+                }
+            }
+            Err(e) => Err(RDatabaseError::DieselResult(e)),
+        }
+    }
+
+    /// Marks the replica as sent again, with the content of `data_digest`.
+    pub fn set_pending(&mut self, conn: &mut SqliteConnection, data_digest: Option<String>) -> Result<usize, RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+
+        let result = diesel::update(replicas::table())
+            .filter(id.eq(self.id))
+            .set((status.eq(REPLICA_STATUS_PENDING), digest.eq(data_digest.clone()), updated_at.eq(now)))
+            .execute(conn);
+
+        let updated = result.map_err(RDatabaseError::DieselResult)?;
+
+        self.status = REPLICA_STATUS_PENDING.to_string();
+        self.digest = data_digest;
+        self.updated_at = now;
+
+        Ok(updated)
+    }
+
+    pub fn delete(&self, conn: &mut SqliteConnection) -> Result<(), RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let result = diesel::delete(replicas::table())
+            .filter(id.eq(self.id))
+            .execute(conn);
+
+        if result.is_ok() {
+            Ok(())
+        } else {
+            Err(RDatabaseError::EntryNotDeleted)
+        }
+    }
+
+    /// Drops the pending replica of `file_uid` on `node_uid`, so the
+    /// transfer is scheduled again.
+    pub fn delete_pending(conn: &mut SqliteConnection, file_uid: &String, node_uid: &String) -> Result<usize, RDatabaseError> {
+        use crate::schema::replicas::dsl::*;
+
+        let result = diesel::delete(replicas::table())
+            .filter(file.eq(file_uid).and(node.eq(node_uid)).and(status.eq(REPLICA_STATUS_PENDING)))
+            .execute(conn);
+
+        result.map_err(|_| RDatabaseError::EntryNotDeleted)
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+
+/// How long a connection waits for another one to release the database
+/// before giving up with "database is locked".
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens the database. Every thread of the deamon has a connection of its
+/// own, so they wait for each other instead of failing at once.
+pub fn establish(database_url: &str) -> ConnectionResult<SqliteConnection> {
+    let mut conn = SqliteConnection::establish(database_url)?;
+
+    let pragma = format!("PRAGMA busy_timeout = {};", BUSY_TIMEOUT.as_millis());
+
+    if let Err(e) = conn.batch_execute(pragma.as_str()) {
+        return Err(ConnectionError::BadConnection(format!("{}", e)));
+    }
+
+    Ok(conn)
+}
+
+/// A database in memory with every migration applied, for the unit tests.
+#[cfg(test)]
+pub fn in_memory() -> SqliteConnection {
+    let mut conn = establish(":memory:").unwrap();
+    let mut migrations: Vec<std::path::PathBuf> = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+
+    migrations.sort();
+
+    for migration in migrations {
+        let sql = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+        conn.batch_execute(sql.as_str()).unwrap();
+    }
+
+    conn
+}
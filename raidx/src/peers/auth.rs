@@ -0,0 +1,791 @@
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Duration;
+
+use diesel::SqliteConnection;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::models::nodes::{RNode, NODE_STATUS_PENDING};
+use crate::peers::transport::{RConnection, RPeerFrame};
+use crate::protocol::codec::RCodec;
+use crate::protocol::message::{RErrorCode, REnvelope, RMChallenge, RMError, RMHello, RMHelloAck, RMessage};
+use crate::protocol::version::{local_capabilities, RSession, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::utils::configs::RConfig;
+
+pub const AUTH_ROLE_CLIENT: &str = "client";
+pub const AUTH_ROLE_SERVER: &str = "server";
+
+/// How long each side waits for the other during the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum RAuthError {
+    Io(std::io::Error),
+    InvalidKey,
+    UnknownKey(String),
+    BadSignature,
+    Protocol(String),
+    Rejected(RMError),
+    SecretRequired,
+    SecretNotExpected,
+    SecretMismatch,
+    KeyChanged(String),
+    PendingApproval(String),
+    PairingRejected,
+    Incompatible(u32),
+    Timeout,
+    Closed,
+}
+
+impl std::fmt::Display for RAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RAuthError::Io(e) => write!(f, "node key not readable: {}", e),
+            RAuthError::InvalidKey => write!(f, "not valid node key"),
+            RAuthError::UnknownKey(public_key) => write!(f, "public key not trusted: {}", public_key),
+            RAuthError::BadSignature => write!(f, "not valid signature"),
+            RAuthError::Protocol(text) => write!(f, "protocol error: {}", text),
+            RAuthError::Rejected(error) => write!(f, "rejected by peer: {}", error),
+            RAuthError::SecretRequired => write!(f, "peer requires a cluster secret, set `cluster_secret` in configs"),
+            RAuthError::SecretNotExpected => write!(f, "`cluster_secret` is set but peer does not use one"),
+            RAuthError::SecretMismatch => write!(f, "`cluster_secret` does not match the peer one"),
+            RAuthError::KeyChanged(fingerprint) => write!(f, "public key changed, connection refused: {}", fingerprint),
+            RAuthError::PendingApproval(fingerprint) => write!(f, "pairing pending approval: {}", fingerprint),
+            RAuthError::PairingRejected => write!(f, "pairing rejected"),
+            RAuthError::Incompatible(version) => write!(
+                f,
+                "protocol version {} not supported, expected {} to {}",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            RAuthError::Timeout => write!(f, "no answer within {}s", HANDSHAKE_TIMEOUT.as_secs()),
+            RAuthError::Closed => write!(f, "connection closed during handshake"),
+        }
+    }
+}
+
+impl RAuthError {
+    /// Error code sent to the peer when the handshake is refused.
+    pub fn code(&self) -> RErrorCode {
+        match self {
+            RAuthError::Incompatible(_) => RErrorCode::VersionUnsupported,
+            RAuthError::UnknownKey(_)
+            | RAuthError::BadSignature
+            | RAuthError::SecretRequired
+            | RAuthError::SecretNotExpected
+            | RAuthError::SecretMismatch
+            | RAuthError::KeyChanged(_)
+            | RAuthError::PendingApproval(_)
+            | RAuthError::PairingRejected => RErrorCode::PermissionDenied,
+            RAuthError::Rejected(error) => error.code,
+            _ => RErrorCode::Internal,
+        }
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Long-term Ed25519 keypair identifying this node to its peers.
+pub struct RIdentity {
+    signing_key: SigningKey,
+}
+
+impl RIdentity {
+    /// Loads the node key, generating and saving a new one on first start.
+    pub fn load_or_create(configs: &RConfig) -> Result<RIdentity, RAuthError> {
+        let path = configs.identity_key_path();
+
+        if std::path::Path::new(path.as_str()).exists() {
+            let content = std::fs::read_to_string(path.as_str());
+
+            let content = content.map_err(RAuthError::Io)?;
+
+            let bytes = hex::decode(content.trim());
+
+            if bytes.is_err() {
+                return Err(RAuthError::InvalidKey);
+            }
+
+            let bytes: Result<[u8; 32], _> = bytes.unwrap().try_into();
+
+            if bytes.is_err() {
+                return Err(RAuthError::InvalidKey);
+            }
+
+            return Ok(RIdentity {
+                signing_key: SigningKey::from_bytes(&bytes.unwrap()),
+            });
+        }
+
+        let signing_key = SigningKey::generate(&mut OsRng);
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path.as_str());
+
+        let mut file = file.map_err(RAuthError::Io)?;
+
+        if let Err(e) = file.write_all(hex::encode(signing_key.to_bytes()).as_bytes()) {
+            return Err(RAuthError::Io(e));
+        }
+
+        info!("node key generated: {}", path);
+
+        Ok(RIdentity { signing_key })
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, role: &str, transcript: &RTranscript) -> String {
+        let signature = self.signing_key.sign(transcript.payload(AUTH_LABEL, role).as_slice());
+        hex::encode(signature.to_bytes())
+    }
+}
+
+/// Everything a handshake settles: both nonces, the keys both nodes are
+/// known by and the session agreed on. Signatures and secret proofs cover
+/// all of it, so none of it can be replayed from or swapped into another
+/// handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RTranscript {
+    pub server_nonce: String,
+    pub client_nonce: String,
+    pub server_key: String,
+    pub client_key: String,
+    pub session: RSession,
+}
+
+/// Labels of what is signed and what the secret proves, completed with the
+/// protocol version of the session so a handshake of one version doesn't
+/// verify as another.
+const AUTH_LABEL: &str = "raidx-auth";
+const SECRET_LABEL: &str = "raidx-secret";
+
+impl RTranscript {
+    fn payload(&self, label: &str, role: &str) -> Vec<u8> {
+        format!(
+            "{}-v{}:{}:{}:{}:{}:{}:{}:{}",
+            label,
+            self.session.version,
+            role,
+            self.server_nonce,
+            self.client_nonce,
+            self.server_key,
+            self.client_key,
+            self.session.version,
+            self.session.capabilities
+        )
+        .into_bytes()
+    }
+}
+
+pub fn new_nonce() -> String {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+
+    hex::encode(nonce)
+}
+
+pub fn verify(public_key: &String, role: &str, transcript: &RTranscript, signature: &String) -> bool {
+    let public_key: Option<[u8; 32]> = hex::decode(public_key).ok().and_then(|bytes| bytes.try_into().ok());
+    let signature: Option<[u8; 64]> = hex::decode(signature).ok().and_then(|bytes| bytes.try_into().ok());
+
+    if public_key.is_none() || signature.is_none() {
+        return false;
+    }
+
+    let public_key = VerifyingKey::from_bytes(&public_key.unwrap());
+
+    if public_key.is_err() {
+        return false;
+    }
+
+    let signature = Signature::from_bytes(&signature.unwrap());
+
+    public_key
+        .unwrap()
+        .verify_strict(transcript.payload(AUTH_LABEL, role).as_slice(), &signature)
+        .is_ok()
+}
+
+/// Short, human comparable form of a public key, shown when pairing.
+pub fn fingerprint(public_key: &String) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+
+    let pairs: Vec<String> = digest[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!("SHA256:{}", pairs.join(":"))
+}
+
+/// HMAC-SHA256 proof that the sender knows the cluster secret, bound to the
+/// whole handshake transcript.
+pub fn secret_proof(secret: &String, role: &str, transcript: &RTranscript) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(transcript.payload(SECRET_LABEL, role).as_slice());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn verify_secret_proof(secret: &String, role: &str, transcript: &RTranscript, proof: &String) -> bool {
+    let proof = hex::decode(proof);
+
+    if proof.is_err() {
+        return false;
+    }
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(transcript.payload(SECRET_LABEL, role).as_slice());
+
+    mac.verify_slice(proof.unwrap().as_slice()).is_ok()
+}
+
+/// Stores the public key of this node on the local row of the `nodes` table.
+pub fn register_local_public_key(conn: &mut SqliteConnection, identity: &RIdentity) {
+    let local_node = RNode::get_local(conn);
+
+    if let Some(mut local_node) = local_node {
+        let public_key = Some(identity.public_key());
+
+        if local_node.public_key != public_key && local_node.set_public_key(conn, public_key).is_ok() {
+            info!("local node public key: {}", identity.public_key());
+        }
+    }
+}
+
+async fn send(client: &mut RConnection, envelope: &REnvelope) -> Result<(), RAuthError> {
+    // Nothing is negotiated yet, the handshake is always JSON.
+    let message = RCodec::Json.to_frame(envelope);
+
+    let message = message.map_err(|e| RAuthError::Protocol(format!("{}", e)))?;
+
+    return match client.send(message).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(RAuthError::Protocol(format!("{:?}", e))),
+    };
+}
+
+async fn send_error(client: &mut RConnection, request: &REnvelope, code: RErrorCode, text: String) {
+    let _ = send(client, &request.reply(RMessage::error(code, text))).await;
+}
+
+/// Waits for the next message, which must answer `request` when given.
+async fn receive(client: &mut RConnection, request: Option<&REnvelope>) -> Result<REnvelope, RAuthError> {
+    loop {
+        let message = tokio::time::timeout(HANDSHAKE_TIMEOUT, client.recv()).await;
+
+        if message.is_err() {
+            return Err(RAuthError::Timeout);
+        }
+
+        let message = match message.unwrap() {
+            Some(Ok(message)) => message,
+            _ => return Err(RAuthError::Closed),
+        };
+
+        match message {
+            RPeerFrame::Data(data) => {
+                let message = RCodec::Json.decode_message(data.as_slice());
+
+                let envelope = message.map_err(|e| RAuthError::Protocol(format!("{}", e)))?;
+
+                if let Some(request) = request {
+                    if envelope.in_reply_to.as_ref() != Some(&request.id) {
+                        return Err(RAuthError::Protocol(format!("{} is not a reply to {}", envelope.message, request.message)));
+                    }
+                }
+
+                return Ok(envelope);
+            }
+            RPeerFrame::Close => {
+                return Err(RAuthError::Closed);
+            }
+            // Nothing pings before the handshake is done.
+            _ => (),
+        }
+    }
+}
+
+/// Client side of the connection handshake.
+///
+/// Answers the server challenge with our signature over the transcript and
+/// checks that the server signs the same transcript with the key we have on
+/// record for the node.
+///
+/// With a `cluster_secret` both sides also prove they know the secret, and a
+/// node without a recorded public key is trusted with the key it presents.
+///
+/// Returns the protocol version and capabilities agreed with the server.
+pub async fn client_handshake(
+    client: &mut RConnection,
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    identity: &RIdentity,
+    node: &RNode,
+) -> Result<RSession, RAuthError> {
+    let request = receive(client, None).await?;
+
+    let challenge = match request.message.clone() {
+        RMessage::Challenge(challenge) => challenge,
+        RMessage::Error(error) => return Err(RAuthError::Rejected(error)),
+        content => return Err(RAuthError::Protocol(format!("expected challenge, got {:?}", content))),
+    };
+
+    let session = RSession::negotiate(local_capabilities(configs), challenge.version, challenge.capabilities);
+
+    if let Err(version) = session {
+        return Err(RAuthError::Incompatible(version));
+    }
+
+    let session = session.unwrap();
+    let secret = configs.cluster_secret.clone();
+
+    if challenge.secret && secret.is_none() {
+        return Err(RAuthError::SecretRequired);
+    }
+
+    if !challenge.secret && secret.is_some() {
+        return Err(RAuthError::SecretNotExpected);
+    }
+
+    if node.public_key.is_some() && node.public_key != Some(challenge.public_key.clone()) {
+        return Err(RAuthError::KeyChanged(fingerprint(&challenge.public_key)));
+    }
+
+    let transcript = RTranscript {
+        server_nonce: challenge.nonce.clone(),
+        client_nonce: new_nonce(),
+        server_key: challenge.public_key.clone(),
+        client_key: identity.public_key(),
+        session,
+    };
+
+    let hello = RMHello {
+        port: configs.server.port,
+        version: PROTOCOL_VERSION,
+        capabilities: local_capabilities(configs),
+        public_key: identity.public_key(),
+        nonce: transcript.client_nonce.clone(),
+        signature: identity.sign(AUTH_ROLE_CLIENT, &transcript),
+        proof: secret.as_ref().map(|secret| secret_proof(secret, AUTH_ROLE_CLIENT, &transcript)),
+    };
+
+    let request = request.reply(RMessage::Hello(hello));
+    send(client, &request).await?;
+
+    let ack = match receive(client, Some(&request)).await?.message {
+        RMessage::HelloAck(ack) => ack,
+        RMessage::Error(error) => return Err(RAuthError::Rejected(error)),
+        content => return Err(RAuthError::Protocol(format!("expected hello ack, got {:?}", content))),
+    };
+
+    if ack.public_key != transcript.server_key {
+        return Err(RAuthError::Protocol("hello ack from another key than the challenge".to_string()));
+    }
+
+    if let Some(secret) = secret.as_ref() {
+        if ack.proof.is_none() || !verify_secret_proof(secret, AUTH_ROLE_SERVER, &transcript, ack.proof.as_ref().unwrap()) {
+            return Err(RAuthError::SecretMismatch);
+        }
+    }
+
+    if !verify(&ack.public_key, AUTH_ROLE_SERVER, &transcript, &ack.signature) {
+        return Err(RAuthError::BadSignature);
+    }
+
+    if node.public_key.is_none() {
+        let mut node = node.clone();
+
+        if node.set_public_key(conn, Some(ack.public_key.clone())).is_err() {
+            return Err(RAuthError::UnknownKey(ack.public_key));
+        }
+
+        // Without a shared secret the first key seen still needs approval.
+        if secret.is_none() {
+            let _ = node.set_status(conn, NODE_STATUS_PENDING);
+            warn!(
+                "node pending approval: {}:{} ({}) {}",
+                node.host, node.port, node.uid, fingerprint(&ack.public_key)
+            );
+            return Err(RAuthError::PendingApproval(fingerprint(&ack.public_key)));
+        }
+
+        info!("node public key learned: {} ({})", node.uid, ack.public_key);
+    }
+
+    Ok(session)
+}
+
+/// Node recorded at the address a peer connects from. Nodes configured by
+/// hostname are only known by their IP once resolved, so the hosts of the
+/// nodes listening on the same port are resolved when none matches as is.
+async fn find_by_address(conn: &mut SqliteConnection, peer_ip: &str, port: i32) -> Option<RNode> {
+    if let Some(node) = RNode::get_by_host_and_port(conn, peer_ip.to_string(), port) {
+        return Some(node);
+    }
+
+    let peer_ip = peer_ip.parse::<IpAddr>().ok()?.to_canonical();
+
+    for node in RNode::get_others(conn)?.into_iter().filter(|node| node.port == port) {
+        let addresses = tokio::net::lookup_host((node.host.as_str(), node.port as u16)).await;
+
+        if addresses.is_ok_and(|mut addresses| addresses.any(|address| address.ip().to_canonical() == peer_ip)) {
+            return Some(node);
+        }
+    }
+
+    None
+}
+
+/// Finds the node a hello comes from, pairing it on first contact.
+///
+/// Nodes are found by public key, then by address, see [`find_by_address`].
+/// A node at a known address presenting a different key is refused. A node
+/// without a key learns it, directly with a cluster secret, otherwise
+/// pending an operator approval, and an unknown node is recorded as pending.
+async fn pair_node(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    peer_ip: &str,
+    hello: &RMHello,
+) -> Result<RNode, RAuthError> {
+    let node = RNode::get_by_public_key(conn, &hello.public_key);
+
+    if let Some(node) = node {
+        if node.is_pending() {
+            return Err(RAuthError::PendingApproval(fingerprint(&hello.public_key)));
+        }
+
+        if node.is_rejected() {
+            return Err(RAuthError::PairingRejected);
+        }
+
+        return Ok(node);
+    }
+
+    let node = find_by_address(conn, peer_ip, hello.port as i32).await;
+
+    if node.is_none() {
+        let node = RNode::create_pending(conn, peer_ip.to_string(), hello.port as i32, hello.public_key.clone());
+
+        if let Ok(node) = node {
+            warn!(
+                "node pending approval: {}:{} ({}) {}",
+                node.host, node.port, node.uid, fingerprint(&hello.public_key)
+            );
+            return Err(RAuthError::PendingApproval(fingerprint(&hello.public_key)));
+        }
+
+        return Err(RAuthError::UnknownKey(hello.public_key.clone()));
+    }
+
+    let mut node = node.unwrap();
+
+    if node.public_key.is_some() {
+        warn!("node {} presented a changed key: {}", node.uid, fingerprint(&hello.public_key));
+        return Err(RAuthError::KeyChanged(fingerprint(&hello.public_key)));
+    }
+
+    if node.set_public_key(conn, Some(hello.public_key.clone())).is_err() {
+        return Err(RAuthError::UnknownKey(hello.public_key.clone()));
+    }
+
+    if configs.cluster_secret.is_none() {
+        let _ = node.set_status(conn, NODE_STATUS_PENDING);
+        warn!(
+            "node pending approval: {}:{} ({}) {}",
+            node.host, node.port, node.uid, fingerprint(&hello.public_key)
+        );
+        return Err(RAuthError::PendingApproval(fingerprint(&hello.public_key)));
+    }
+
+    info!("node public key learned: {} ({})", node.uid, hello.public_key);
+
+    Ok(node)
+}
+
+/// Server side of the connection handshake.
+///
+/// Challenges the peer, accepts it only if its signature over the transcript
+/// checks out and its public key belongs to an approved node, then signs
+/// the same transcript in return. Peers speaking a protocol version we no longer support are told
+/// so before the connection is dropped.
+pub async fn server_handshake(
+    client: &mut RConnection,
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    identity: &RIdentity,
+    peer_ip: &str,
+) -> Result<(RNode, RSession), RAuthError> {
+    let nonce = new_nonce();
+    let secret = configs.cluster_secret.clone();
+
+    let challenge = RMChallenge {
+        nonce: nonce.clone(),
+        version: PROTOCOL_VERSION,
+        capabilities: local_capabilities(configs),
+        secret: secret.is_some(),
+        public_key: identity.public_key(),
+    };
+
+    let challenge = REnvelope::new(RMessage::Challenge(challenge));
+    send(client, &challenge).await?;
+
+    let request = receive(client, Some(&challenge)).await?;
+
+    let hello = match request.message.clone() {
+        RMessage::Hello(hello) => hello,
+        content => {
+            send_error(client, &request, RErrorCode::Internal, "expected hello".to_string()).await;
+            return Err(RAuthError::Protocol(format!("expected hello, got {:?}", content)));
+        }
+    };
+
+    let session = RSession::negotiate(local_capabilities(configs), hello.version, hello.capabilities);
+
+    if let Err(version) = session {
+        let e = RAuthError::Incompatible(version);
+        send_error(client, &request, e.code(), format!("{}", e)).await;
+        return Err(e);
+    }
+
+    let transcript = RTranscript {
+        server_nonce: nonce,
+        client_nonce: hello.nonce.clone(),
+        server_key: identity.public_key(),
+        client_key: hello.public_key.clone(),
+        session: session.unwrap(),
+    };
+
+    if let Some(secret) = secret.as_ref() {
+        if hello.proof.is_none() {
+            send_error(client, &request, RErrorCode::PermissionDenied, "cluster secret required".to_string()).await;
+            return Err(RAuthError::SecretRequired);
+        }
+
+        if !verify_secret_proof(secret, AUTH_ROLE_CLIENT, &transcript, hello.proof.as_ref().unwrap()) {
+            send_error(client, &request, RErrorCode::PermissionDenied, "cluster secret mismatch".to_string()).await;
+            return Err(RAuthError::SecretMismatch);
+        }
+    }
+
+    if !verify(&hello.public_key, AUTH_ROLE_CLIENT, &transcript, &hello.signature) {
+        send_error(client, &request, RErrorCode::PermissionDenied, "not valid signature".to_string()).await;
+        return Err(RAuthError::BadSignature);
+    }
+
+    let node = pair_node(conn, configs, peer_ip, &hello).await;
+
+    if let Err(e) = node {
+        send_error(client, &request, e.code(), format!("{}", e)).await;
+        return Err(e);
+    }
+
+    let node = node.unwrap();
+
+    let ack = RMHelloAck {
+        public_key: identity.public_key(),
+        signature: identity.sign(AUTH_ROLE_SERVER, &transcript),
+        proof: secret.as_ref().map(|secret| secret_proof(secret, AUTH_ROLE_SERVER, &transcript)),
+    };
+
+    send(client, &request.reply(RMessage::HelloAck(ack))).await?;
+
+    Ok((node, transcript.session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::utils::connection;
+
+    fn identity() -> RIdentity {
+        RIdentity { signing_key: SigningKey::generate(&mut OsRng) }
+    }
+
+    fn transcript(server: &RIdentity, client: &RIdentity) -> RTranscript {
+        RTranscript {
+            server_nonce: new_nonce(),
+            client_nonce: new_nonce(),
+            server_key: server.public_key(),
+            client_key: client.public_key(),
+            session: RSession { version: PROTOCOL_VERSION, capabilities: 3 },
+        }
+    }
+
+    #[test]
+    fn signature_verifies() {
+        let (server, client) = (identity(), identity());
+        let transcript = transcript(&server, &client);
+        let signature = client.sign(AUTH_ROLE_CLIENT, &transcript);
+
+        assert!(verify(&client.public_key(), AUTH_ROLE_CLIENT, &transcript, &signature));
+    }
+
+    #[test]
+    fn signature_refused_from_another_key() {
+        let (server, client) = (identity(), identity());
+        let transcript = transcript(&server, &client);
+        let signature = client.sign(AUTH_ROLE_CLIENT, &transcript);
+
+        assert!(!verify(&server.public_key(), AUTH_ROLE_CLIENT, &transcript, &signature));
+    }
+
+    #[test]
+    fn signature_refused_for_another_role() {
+        let (server, client) = (identity(), identity());
+        let transcript = transcript(&server, &client);
+        let signature = client.sign(AUTH_ROLE_CLIENT, &transcript);
+
+        assert!(!verify(&client.public_key(), AUTH_ROLE_SERVER, &transcript, &signature));
+    }
+
+    #[test]
+    fn signature_refused_for_another_transcript() {
+        let (server, client) = (identity(), identity());
+        let signed = transcript(&server, &client);
+        let signature = client.sign(AUTH_ROLE_CLIENT, &signed);
+
+        let changes: Vec<fn(&mut RTranscript)> = vec![
+            |transcript| transcript.server_nonce = new_nonce(),
+            |transcript| transcript.client_nonce = new_nonce(),
+            |transcript| transcript.server_key = identity().public_key(),
+            |transcript| transcript.client_key = identity().public_key(),
+            |transcript| transcript.session.version -= 1,
+            |transcript| transcript.session.capabilities = 1,
+        ];
+
+        for change in changes {
+            let mut transcript = signed.clone();
+            change(&mut transcript);
+
+            assert!(!verify(&client.public_key(), AUTH_ROLE_CLIENT, &transcript, &signature));
+        }
+    }
+
+    #[test]
+    fn payload_labelled_with_the_session_version() {
+        let mut transcript = transcript(&identity(), &identity());
+        transcript.session.version = MIN_PROTOCOL_VERSION;
+
+        let payload = String::from_utf8(transcript.payload(AUTH_LABEL, AUTH_ROLE_CLIENT)).unwrap();
+
+        assert!(payload.starts_with(&format!("raidx-auth-v{}:", MIN_PROTOCOL_VERSION)));
+    }
+
+    #[test]
+    fn signature_refused_when_malformed() {
+        let (server, client) = (identity(), identity());
+        let transcript = transcript(&server, &client);
+        let mut signature = hex::decode(client.sign(AUTH_ROLE_CLIENT, &transcript)).unwrap();
+        signature[0] ^= 1;
+
+        assert!(!verify(&client.public_key(), AUTH_ROLE_CLIENT, &transcript, &hex::encode(signature)));
+        assert!(!verify(&client.public_key(), AUTH_ROLE_CLIENT, &transcript, &"not hex".to_string()));
+        assert!(!verify(&"00".to_string(), AUTH_ROLE_CLIENT, &transcript, &client.sign(AUTH_ROLE_CLIENT, &transcript)));
+    }
+
+    #[test]
+    fn secret_proof_matches_same_secret() {
+        let transcript = transcript(&identity(), &identity());
+        let secret = "cluster".to_string();
+        let proof = secret_proof(&secret, AUTH_ROLE_CLIENT, &transcript);
+
+        assert!(verify_secret_proof(&secret, AUTH_ROLE_CLIENT, &transcript, &proof));
+    }
+
+    #[test]
+    fn secret_proof_refused_with_another_secret() {
+        let transcript = transcript(&identity(), &identity());
+        let proof = secret_proof(&"cluster".to_string(), AUTH_ROLE_CLIENT, &transcript);
+
+        assert!(!verify_secret_proof(&"other".to_string(), AUTH_ROLE_CLIENT, &transcript, &proof));
+    }
+
+    #[test]
+    fn secret_proof_refused_for_another_role_or_transcript() {
+        let signed = transcript(&identity(), &identity());
+        let secret = "cluster".to_string();
+        let proof = secret_proof(&secret, AUTH_ROLE_CLIENT, &signed);
+
+        let mut transcript = signed.clone();
+        transcript.client_nonce = new_nonce();
+
+        assert!(!verify_secret_proof(&secret, AUTH_ROLE_SERVER, &signed, &proof));
+        assert!(!verify_secret_proof(&secret, AUTH_ROLE_CLIENT, &transcript, &proof));
+        assert!(!verify_secret_proof(&secret, AUTH_ROLE_CLIENT, &signed, &"not hex".to_string()));
+    }
+
+    fn hello(identity: &RIdentity, port: usize) -> RMHello {
+        RMHello {
+            port,
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+            public_key: identity.public_key(),
+            nonce: new_nonce(),
+            signature: String::new(),
+            proof: None,
+        }
+    }
+
+    fn clustered() -> RConfig {
+        let mut configs = RConfig::get_default(String::new());
+        configs.cluster_secret = Some("cluster".to_string());
+
+        configs
+    }
+
+    #[tokio::test]
+    async fn hostname_node_is_paired_from_its_ip() {
+        let mut conn = connection::in_memory();
+        let configured = RNode::create_other(&mut conn, "localhost".to_string(), 4000, 1, false).unwrap();
+        let peer = identity();
+
+        let node = pair_node(&mut conn, &clustered(), "127.0.0.1", &hello(&peer, 4000)).await.unwrap();
+
+        assert_eq!(node.uid, configured.uid);
+        assert_eq!(node.public_key, Some(peer.public_key()));
+        assert_eq!(RNode::get_all(&mut conn).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn known_key_is_found_before_the_address() {
+        let mut conn = connection::in_memory();
+        let peer = identity();
+
+        let mut moved = RNode::create_other(&mut conn, "10.0.0.1".to_string(), 4000, 1, false).unwrap();
+        moved.set_public_key(&mut conn, Some(peer.public_key())).unwrap();
+
+        // Another node now listens where it used to be.
+        let node = pair_node(&mut conn, &clustered(), "10.0.0.2", &hello(&peer, 4000)).await.unwrap();
+        RNode::create_other(&mut conn, "10.0.0.2".to_string(), 4000, 1, false).unwrap();
+        let again = pair_node(&mut conn, &clustered(), "10.0.0.2", &hello(&peer, 4000)).await.unwrap();
+
+        assert_eq!(node.uid, moved.uid);
+        assert_eq!(again.uid, moved.uid);
+    }
+
+    #[tokio::test]
+    async fn unknown_address_is_pending_once() {
+        let mut conn = connection::in_memory();
+        let peer = identity();
+
+        for _ in 0..2 {
+            let paired = pair_node(&mut conn, &clustered(), "10.0.0.3", &hello(&peer, 4000)).await;
+            assert!(matches!(paired, Err(RAuthError::PendingApproval(_))));
+        }
+
+        let nodes = RNode::get_all(&mut conn).unwrap();
+
+        assert_eq!(nodes.len(), 1);
+        assert!(nodes[0].is_pending());
+    }
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use diesel::SqliteConnection;
+
+use log::warn;
+
+use crate::models::nodes::RNode;
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_incoming::RMessagesIncoming;
+use crate::models::queues::messages_outgoing::RMessageOutgoing;
+use crate::models::utils::connection;
+use crate::peers::requests;
+use crate::protocol::handler;
+use crate::utils::configs::RConfig;
+
+/// Longest an idle dispatcher waits before reading the queue again, for
+/// messages queued by another process.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn dispatch(conn: &mut SqliteConnection, configs: &RConfig, n: usize) -> usize {
+    let messages = RMessagesIncoming::first_n(conn, n);
+
+    if messages.is_none() {
+        return 0;
+    }
+
+    let messages = messages.unwrap();
+    let mut count = 0;
+
+    for message in messages {
+        let from = RNode::get_by_uid(conn, message.from.clone());
+
+        if let (Ok(from), Some(envelope)) = (from, message.to_envelope()) {
+            let reply = handler::handle(conn, configs, &from, envelope.message.clone());
+
+            if let Some(reply) = reply {
+                if RMessageOutgoing::push_envelope(conn, from.uid.clone(), envelope.reply(reply)).is_err() {
+                    warn!(target: "DISPATCHER", "can't queue reply to {}", from.uid);
+                }
+            }
+
+            // The reply is handled like any other message first, then handed
+            // to the request waiting for it, if any.
+            if let Some(in_reply_to) = envelope.in_reply_to.as_ref() {
+                requests::resolve(&from.uid, in_reply_to, envelope.message);
+            }
+        } else {
+            warn!(target: "DISPATCHER", "not valid incoming message: {}", message.uid);
+        }
+
+        // A message left in the queue is read again, it isn't counted.
+        if message.delete(conn).is_ok() {
+            count += 1;
+        } else {
+            warn!(target: "DISPATCHER", "can't delete incoming message: {}", message.uid);
+        }
+    }
+
+    count
+}
+
+pub fn init(configs: RConfig) {
+    tokio::spawn(async move {
+        let database_url = configs.database.path.clone();
+        let mut conn = connection::establish(database_url.as_str()).unwrap();
+
+        loop {
+            // Listened to before the queue is read, so a message queued
+            // meanwhile still wakes the task.
+            let queued = RMessagesIncoming::queued().notified();
+            tokio::pin!(queued);
+            queued.as_mut().enable();
+
+            if tokio::task::block_in_place(|| dispatch(&mut conn, &configs, 10)) == 0 {
+                let _ = tokio::time::timeout(IDLE_TIMEOUT, queued).await;
+            }
+        }
+    });
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use diesel::SqliteConnection;
+use log::warn;
+
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_incoming::RMessagesIncoming;
+use crate::peers::transport::RTransportError;
+use crate::protocol::message::{RErrorCode, RMessage};
+use crate::utils::configs::{RConfig, RConfigLimits};
+use crate::utils::rate::RRateLimiter;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RLimitError {
+    FrameTooLarge(usize),
+    RateExceeded,
+}
+
+impl std::fmt::Display for RLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RLimitError::FrameTooLarge(size) => write!(f, "frame too large: {} bytes", size),
+            RLimitError::RateExceeded => write!(f, "too many messages per second"),
+        }
+    }
+}
+
+impl RLimitError {
+    /// Error sent to the peer before the connection is closed.
+    pub fn to_message(&self) -> RMessage {
+        let code = match self {
+            RLimitError::RateExceeded => RErrorCode::RateLimited,
+            RLimitError::FrameTooLarge(_) => RErrorCode::QuotaExceeded,
+        };
+
+        RMessage::error(code, format!("{}", self))
+    }
+}
+
+/// How often a full incoming queue is checked again.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Limits applied to the messages received from one peer connection.
+pub struct RPeerLimits {
+    limits: RConfigLimits,
+    limiter: RRateLimiter,
+}
+
+impl RPeerLimits {
+    pub fn new(configs: &RConfig) -> RPeerLimits {
+        RPeerLimits {
+            limits: configs.limits,
+            limiter: RRateLimiter::new(configs.limits.max_messages_per_second),
+        }
+    }
+
+    /// Checks a received frame before it is deserialised and queued.
+    pub fn check(&mut self, size: usize) -> Result<(), RLimitError> {
+        if size > self.limits.max_frame_size {
+            return Err(RLimitError::FrameTooLarge(size));
+        }
+
+        if !self.limiter.try_acquire(1) {
+            return Err(RLimitError::RateExceeded);
+        }
+
+        Ok(())
+    }
+
+    /// Waits while `limits.max_incoming` messages of the peer are queued and
+    /// not handled yet. Nothing more is read from the peer meanwhile, so it
+    /// slows down instead of being refused for a queue that is ours to empty.
+    pub async fn wait_for_queue(&self, conn: &mut SqliteConnection, node_uid: &String) {
+        if self.limits.max_incoming <= 0 {
+            return;
+        }
+
+        let mut warned = false;
+
+        while RMessagesIncoming::count_by_node(conn, node_uid).unwrap_or(0) >= self.limits.max_incoming {
+            if !warned {
+                warn!("incoming queue full for {}, reading paused", node_uid);
+                warned = true;
+            }
+
+            tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Whether the transport gave up on a frame over the configured size.
+pub fn is_oversized(error: &RTransportError) -> bool {
+    matches!(error, RTransportError::Oversized(_))
+}
+
+/// Banned nodes, by uid: nodes sharing a host are banned apart.
+fn bans() -> &'static Mutex<HashMap<String, Instant>> {
+    static BANS: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+    BANS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Refuses connections from and to node `node_uid` for `limits.ban_seconds`.
+pub fn ban(configs: &RConfig, node_uid: &String, reason: &String) {
+    let until = Instant::now() + Duration::from_secs(configs.limits.ban_seconds);
+
+    if let Ok(mut bans) = bans().lock() {
+        bans.insert(node_uid.clone(), until);
+    }
+
+    warn!("peer {} banned for {}s: {}", node_uid, configs.limits.ban_seconds, reason);
+}
+
+pub fn is_banned(node_uid: &String) -> bool {
+    if let Ok(mut bans) = bans().lock() {
+        if let Some(until) = bans.get(node_uid) {
+            if *until > Instant::now() {
+                return true;
+            }
+
+            bans.remove(node_uid);
+        }
+    }
+
+    false
+}
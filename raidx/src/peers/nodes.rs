@@ -1,15 +1,28 @@
-use std::thread;
-use std::time::Duration;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_incoming::RMessagesIncoming;
 use crate::models::queues::messages_outgoing::RMessageOutgoing;
-use crate::utils::configs::RConfigNode;
+use crate::models::utils::connection;
+use crate::protocol::codec::RCodec;
+use crate::protocol::compression::{self, RCompressor};
+use crate::protocol::message::REnvelope;
+use crate::protocol::version::RSession;
+use crate::utils::configs::{RConfigConnections, RConfigNode};
 use crate::{models::nodes::RNode, utils::configs::RConfig};
-use diesel::prelude::*;
 use diesel::SqliteConnection;
+use futures::{SinkExt, StreamExt};
 use log::{error, info, warn};
-use websocket::OwnedMessage;
-use websocket::{ClientBuilder, Message};
+use rand::Rng;
+use tokio::sync::mpsc;
+
+use crate::peers::auth::{self, RIdentity};
+use crate::peers::limits::{self, RPeerLimits};
+use crate::peers::requests;
+use crate::placement::ring::{RRing, RING_MAX_WEIGHT};
+use crate::peers::transport::{self, RFrameReceiver, RFrameSender, RPeerFrame};
 
 pub struct RServer;
 
@@ -29,37 +42,119 @@ impl RServer {
     }
 }
 
+/// Weight of a node in the configs, bounded to what the ring places.
+fn configured_weight(node_config: &RConfigNode) -> i32 {
+    let weight = RRing::clamp_weight(node_config.weight);
+
+    if weight != node_config.weight {
+        warn!(
+            "node weight {} above {}, lowered: {}:{}",
+            node_config.weight, RING_MAX_WEIGHT, node_config.host, node_config.port
+        );
+    }
+
+    return weight as i32;
+}
+
 pub fn load_nodes_from_configs(configs: &RConfig) {
     let nodes = configs.clone().nodes;
 
     let database_url = configs.database.path.clone();
-    let mut conn = SqliteConnection::establish(database_url.as_str()).unwrap();
+    let mut conn = connection::establish(database_url.as_str()).unwrap();
 
     for node_config in nodes {
         let host = node_config.clone().host;
         let port = node_config.clone().port as i32;
 
+        let weight = configured_weight(&node_config);
+
         let _node = RNode::get_by_host_and_port(&mut conn, host, port);
 
         if _node.is_some() {
-            let _node = _node.unwrap();
+            let mut _node = _node.unwrap();
 
             info!(
                 "node already registred: {}:{} ({})",
                 _node.host, _node.port, _node.uid
             );
+
+            if _node.is_draining() {
+                warn!(
+                    "node is draining but still in configs: {}:{} ({})",
+                    _node.host, _node.port, _node.uid
+                );
+            }
+
+            if _node.ssl != node_config.ssl {
+                if _node.set_ssl(&mut conn, node_config.ssl).is_ok() {
+                    info!("node ssl updated: {} -> {}", _node.uid, node_config.ssl);
+                } else {
+                    warn!("node ssl not updated: {}", _node.uid);
+                }
+            }
+
+            if node_config.public_key.is_some() && _node.public_key != node_config.public_key {
+                if _node.set_public_key(&mut conn, node_config.public_key.clone()).is_ok() {
+                    info!("node public key updated: {}", _node.uid);
+                } else {
+                    warn!("node public key not updated: {}", _node.uid);
+                }
+            }
+
+            if _node.untrusted != node_config.untrusted {
+                if _node.set_untrusted(&mut conn, node_config.untrusted).is_ok() {
+                    info!("node untrusted updated: {} -> {}", _node.uid, node_config.untrusted);
+                } else {
+                    warn!("node untrusted not updated: {}", _node.uid);
+                }
+            }
+
+            if _node.role != node_config.role.as_str() {
+                if _node.set_role(&mut conn, node_config.role.as_str()).is_ok() {
+                    info!("node role updated: {} -> {}", _node.uid, _node.role);
+                } else {
+                    warn!("node role not updated: {}", _node.uid);
+                }
+            }
+
+            // A key pinned in the configs is an explicit approval.
+            let pinned = node_config.public_key.is_some() && _node.public_key == node_config.public_key;
+
+            if _node.is_pending() && pinned && _node.approve(&mut conn).is_ok() {
+                info!("node approved from configs: {}", _node.uid);
+            }
+
+            if _node.weight != weight {
+                if _node.set_weight(&mut conn, weight).is_ok() {
+                    info!("node weight updated: {} -> {}", _node.uid, weight);
+                } else {
+                    warn!("node weight not updated: {}", _node.uid);
+                }
+            }
         } else {
             let host = node_config.clone().host;
             let port = node_config.clone().port as i32;
 
-            let _node = RNode::create_other(&mut conn, host, port);
+            let _node = RNode::create_other(&mut conn, host, port, weight, node_config.ssl);
 
             if _node.is_ok() {
-                let _node = _node.unwrap();
+                let mut _node = _node.unwrap();
                 info!(
                     "node registred with success: {}:{} ({})",
                     _node.host, _node.port, _node.uid
                 );
+
+                if node_config.public_key.is_some() && _node.set_public_key(&mut conn, node_config.public_key.clone()).is_err() {
+                    warn!("node public key not registred: {}", _node.uid);
+                }
+
+                if node_config.untrusted && _node.set_untrusted(&mut conn, true).is_err() {
+                    warn!("node untrusted flag not registred: {}", _node.uid);
+                }
+
+                if _node.set_role(&mut conn, node_config.role.as_str()).is_err() {
+                    warn!("node role not registred: {}", _node.uid);
+                }
             } else {
                 warn!(
                     "node not registred: {}:{}",
@@ -70,133 +165,470 @@ pub fn load_nodes_from_configs(configs: &RConfig) {
     }
 }
 
+/// Frames waiting to be written on one connection. The outgoing queue is
+/// only read further once they are, so a slow peer holds back its own
+/// messages and nothing else.
+const SEND_QUEUE_SIZE: usize = 16;
+
+/// How long an idle connection waits before reading the outgoing queue
+/// again when nothing woke it, for messages queued by another process.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the nodes table is read again for nodes approved since, by the
+/// `approve` command or a configs reload.
+const NODES_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Nodes a connection is kept open to, by uid.
+fn managed() -> &'static Mutex<HashSet<String>> {
+    static MANAGED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    return MANAGED.get_or_init(|| Mutex::new(HashSet::new()));
+}
+
+pub fn load_local_node_from_configs(configs: &RConfig) {
+    let database_url = configs.database.path.clone();
+    let mut conn = connection::establish(database_url.as_str()).unwrap();
+
+    let weight = configured_weight(&configs.server);
+    let local_node = RNode::get_local_or_create(&mut conn, "0.0.0.0".to_string(), 4000);
+
+    if let Some(mut local_node) = local_node {
+        if local_node.weight != weight {
+            if local_node.set_weight(&mut conn, weight).is_ok() {
+                info!("local node weight updated: {}", weight);
+            } else {
+                warn!("local node weight not updated: {}", local_node.uid);
+            }
+        }
+
+        let role = configs.server.role.as_str();
+
+        if local_node.role != role {
+            if local_node.set_role(&mut conn, role).is_ok() {
+                info!("local node role updated: {}", role);
+            } else {
+                warn!("local node role not updated: {}", local_node.uid);
+            }
+        }
+    } else {
+        error!("Not valid local node");
+    }
+}
+
 pub fn init(configs: RConfig) {
     let configs = configs.clone();
 
-    thread::spawn(move || {
+    tokio::spawn(async move {
         let database_url = configs.database.path.clone();
         load_nodes_from_configs(&configs);
-        let mut conn = SqliteConnection::establish(database_url.as_str()).unwrap();
+        load_local_node_from_configs(&configs);
+        let mut conn = connection::establish(database_url.as_str()).unwrap();
+
+        if let Ok(identity) = RIdentity::load_or_create(&configs) {
+            auth::register_local_public_key(&mut conn, &identity);
+        }
+
         let nodes = RNode::get_others(&mut conn);
-    
-        thread::sleep(Duration::from_secs(10));
-    
+
         if let Some(nodes) = nodes {
-            for node in nodes {
-                let configs = configs.clone();
-                thread::spawn(move || {
-                    let database_url = configs.database.path.clone();
-                    let mut conn = SqliteConnection::establish(database_url.as_str()).unwrap();
-                    let client = ClientBuilder::new(node.clone().connection_url(false).as_str())
-                        .unwrap()
-                        .add_protocol("rust-websocket")
-                        .connect_insecure();
-    
-                    if let Ok(client) = client {
-                        println!("Successfully connected");
-    
-                        let (mut receiver, mut sender) = client.split().unwrap();
-    
-                        let (tx, rx) = std::sync::mpsc::channel();
-    
-                        let tx_1 = tx.clone();
-    
-                        let send_loop = thread::spawn(move || {
-                            loop {
-                                // Send loop
-                                let message = match rx.recv() {
-                                    Ok(m) => m,
-                                    Err(e) => {
-                                        println!("Send Loop: {:?}", e);
-                                        return;
-                                    }
-                                };
-                                match message {
-                                    OwnedMessage::Close(_) => {
-                                        let _ = sender.send_message(&message);
-                                        // If it's a close message, just send it and then return.
-                                        return;
-                                    }
-                                    _ => (),
-                                }
-                                // Send the message
-                                match sender.send_message(&message) {
-                                    Ok(()) => (),
-                                    Err(e) => {
-                                        println!("Send Loop: {:?}", e);
-                                        let _ = sender.send_message(&Message::close());
-                                        return;
-                                    }
-                                }
-                            }
-                        });
-    
-                        let receive_loop = thread::spawn(move || {
-                            // Receive loop
-                            for message in receiver.incoming_messages() {
-                                let message = match message {
-                                    Ok(m) => m,
-                                    Err(e) => {
-                                        println!("Receive Loop: {:?}", e);
-                                        let _ = tx_1.send(OwnedMessage::Close(None));
-                                        return;
-                                    }
-                                };
-                                match message {
-                                    OwnedMessage::Close(_) => {
-                                        // Got a close message, so send a close message and return
-                                        let _ = tx_1.send(OwnedMessage::Close(None));
-                                        return;
-                                    }
-                                    OwnedMessage::Ping(data) => {
-                                        match tx_1.send(OwnedMessage::Pong(data)) {
-                                            // Send a pong in response
-                                            Ok(()) => (),
-                                            Err(e) => {
-                                                println!("Receive Loop: {:?}", e);
-                                                return;
-                                            }
-                                        }
-                                    }
-                                    // Say what we received
-                                    _ => println!("Receive Loop: {:?}", message),
-                                }
-                            }
-                        });
-    
-                        loop {
-                            //TODO get outcoming message from database
-                            let messages = RMessageOutgoing::last_n(&mut conn, 10);
-    
-                            if let Some(messages) = messages {
-                                for message in messages {
-                                    if let Some(message) = message.to_message() {
-                                        if let Ok(message) = message.to_ws_message() {
-                                            match tx.send(message) {
-                                                Ok(()) => (),
-                                                Err(e) => {
-                                                    println!("Main Loop: {:?}", e);
-                                                    break;
-                                                }
-                                            }
-                                        } else {
-                                            warn!("can't convert RMessage to OwnedMessage")
-                                        }
-                                    } else {
-                                        warn!("can't convert RMessageOutcoming to RMessage")
-                                    }
-                                }
-                            } else {
-                                //warn!("can't retrieve outcoming messages from database");
-                            }
+            for mut node in nodes {
+                // Left over from the last run.
+                if node.online && node.set_online(&mut conn, false).is_err() {
+                    warn!("node not marked offline: {}", node.uid);
+                }
+            }
+        } else {
+            warn!("nodes not found");
+        }
+
+        // Nodes approved while running are connected to without a restart.
+        loop {
+            connect_approved(&configs, &mut conn);
+            tokio::time::sleep(NODES_POLL_INTERVAL).await;
+        }
+    });
+}
+
+/// Starts a connection to every approved node that has none yet.
+fn connect_approved(configs: &RConfig, conn: &mut SqliteConnection) {
+    let nodes = RNode::get_others(conn);
+
+    if nodes.is_none() {
+        warn!("nodes not found");
+        return;
+    }
+
+    for node in nodes.unwrap() {
+        let added = managed().lock().is_ok_and(|mut managed| managed.insert(node.uid.clone()));
+
+        if added {
+            let uid = node.uid.clone();
+            let configs = configs.clone();
+
+            tokio::spawn(async move {
+                manage(configs, node).await;
+
+                if let Ok(mut managed) = managed().lock() {
+                    managed.remove(&uid);
+                }
+            });
+        }
+    }
+}
+
+/// Delay before the next attempt after `failures` failed ones in a row,
+/// doubled each time up to `max_backoff`. Up to half of it is taken off at
+/// random so nodes restarted together don't all dial again at once.
+fn backoff(configs: &RConfigConnections, failures: u32) -> Duration {
+    let delay = configs
+        .min_backoff
+        .saturating_mul(1u64 << failures.min(20))
+        .min(configs.max_backoff.max(configs.min_backoff))
+        * 1000;
+
+    let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+
+    return Duration::from_millis(delay - jitter);
+}
+
+/// Keeps a connection open to `node` as long as it is part of the cluster,
+/// dialing it again whenever it drops or can't be opened.
+async fn manage(configs: RConfig, mut node: RNode) {
+    let database_url = configs.database.path.clone();
+    let mut conn = connection::establish(database_url.as_str()).unwrap();
+    let mut failures: u32 = 0;
+
+    loop {
+        if run(&configs, &mut conn, &mut node).await {
+            failures = 0;
+
+            // Requests to a peer we lost fail now rather than at their timeout.
+            let canceled = requests::cancel(&mut conn, &node.uid);
+
+            if canceled > 0 {
+                warn!("requests to {} canceled on disconnect: {}", node.uid, canceled);
+            }
+        } else {
+            failures = failures.saturating_add(1);
+        }
+
+        if node.online {
+            if node.set_online(&mut conn, false).is_err() {
+                warn!("node not marked offline: {}", node.uid);
+            }
+
+            warn!("node offline: {}", transport::url(&configs, &node));
+        }
+
+        let delay = backoff(&configs.connections, failures);
+        info!("connecting again to {} in {}ms", transport::url(&configs, &node), delay.as_millis());
+
+        tokio::time::sleep(delay).await;
+
+        // Pairing and removal are decided while we wait.
+        match RNode::get_by_uid(&mut conn, node.uid.clone()) {
+            Ok(current) if current.is_approved() => node = current,
+            Ok(_) => {
+                info!("node no longer approved, not connecting: {}", transport::url(&configs, &node));
+                return;
+            }
+            Err(_) => {
+                info!("node removed, not connecting: {}", transport::url(&configs, &node));
+                return;
+            }
+        }
+    }
+}
+
+/// Connects to `node` and sends it the messages queued for it until the
+/// connection drops. Returns whether the peer was reached.
+async fn run(configs: &RConfig, conn: &mut SqliteConnection, node: &mut RNode) -> bool {
+    if limits::is_banned(&node.uid) {
+        warn!("node banned, not connecting: {}", transport::url(configs, node));
+        return false;
+    }
+
+    let client = transport::connect(configs, node).await;
+
+    if let Err(e) = client.as_ref() {
+        error!("Not valid client: {}: {}", transport::url(configs, node), e);
+        return false;
+    }
+
+    let mut client = client.unwrap();
+    let identity = RIdentity::load_or_create(configs);
+
+    if identity.is_err() {
+        error!("Not valid node key: {}", identity.err().unwrap());
+        return false;
+    }
+
+    let handshake = auth::client_handshake(&mut client, conn, configs, &identity.unwrap(), node).await;
+
+    if let Err(e) = handshake {
+        error!("Peer not authenticated: {}: {}", transport::url(configs, node), e);
+        client.close().await;
+        return false;
+    }
+
+    let session = handshake.unwrap();
+
+    info!("connected to {}", transport::url(configs, node));
+    info!("protocol v{} with {}, capabilities {:#x}", session.version, node.uid, session.capabilities);
+
+    if node.set_online(conn, true).is_err() {
+        warn!("node not marked online: {}", node.uid);
+    }
+
+    let codec = RCodec::negotiate(&session);
+    let (sender, receiver) = client.split();
+    let (tx, rx) = mpsc::channel(SEND_QUEUE_SIZE);
+    let heard = Arc::new(Mutex::new(Instant::now()));
+
+    let sending = tokio::spawn(send_loop(configs.clone(), sender, rx));
+    let receiving = tokio::spawn(receive_loop(configs.clone(), node.clone(), codec, receiver, tx.clone(), heard.clone()));
+
+    deliver(configs, conn, node, &session, codec, &tx, &heard).await;
+
+    // A dead peer never ends them itself.
+    receiving.abort();
+    sending.abort();
+
+    return true;
+}
+
+/// Hands the messages queued for `node` to the send loop, and pings it
+/// while idle. Returns once the connection is gone or the node silent.
+async fn deliver(
+    configs: &RConfig,
+    conn: &mut SqliteConnection,
+    node: &RNode,
+    session: &RSession,
+    codec: RCodec,
+    tx: &mpsc::Sender<(RPeerFrame, Option<i32>)>,
+    heard: &Arc<Mutex<Instant>>,
+) {
+    let ping_interval = Duration::from_secs(configs.connections.ping_interval);
+    let ping_timeout = Duration::from_secs(configs.connections.ping_timeout);
+
+    let mut compressor = RCompressor::new(&configs.protocol.compression, session);
+    let mut pinged = Instant::now();
+
+    // Messages up to this one are being sent. They stay in the queue until
+    // written, so a new connection sends again those it didn't.
+    let mut queued: i32 = 0;
+
+    loop {
+        if pinged.elapsed() >= ping_interval {
+            let silent = heard.lock().map(|heard| heard.elapsed()).unwrap_or_default();
+
+            if silent >= ping_timeout {
+                warn!("no answer from {} for {}s", node.uid, silent.as_secs());
+                return;
+            }
+
+            // Rejected or removed meanwhile, by the `reject` command.
+            if !RNode::get_by_uid(conn, node.uid.clone()).is_ok_and(|current| current.is_approved()) {
+                info!("node no longer approved, disconnecting: {}", node.uid);
+                return;
+            }
+
+            if tx.send((RPeerFrame::Ping, None)).await.is_err() {
+                return;
+            }
+
+            pinged = Instant::now();
+        }
+
+        // Reading and compressing file contents blocks, other tasks move to
+        // another worker meanwhile.
+        let messages = tokio::task::block_in_place(|| {
+            let messages = RMessageOutgoing::first_n_by_node_after(conn, &node.uid, queued, 10).unwrap_or_default();
+
+            return messages
+                .into_iter()
+                .filter_map(|outgoing| match outgoing.to_envelope() {
+                    Some(mut message) => {
+                        compressor.compress(&mut message);
+                        Some((outgoing, codec.to_frame(&message)))
+                    }
+                    None => {
+                        // Left in the queue it would be retried forever.
+                        warn!("can't convert RMessageOutcoming to RMessage, dropped: {}", outgoing.uid);
+                        let _ = outgoing.delete(conn);
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+        });
+
+        let idle = messages.is_empty();
+
+        for (outgoing, message) in messages {
+            queued = outgoing.id;
+
+            if message.as_ref().is_ok_and(|message| message.size() > configs.limits.max_frame_size) {
+                // The peer would refuse it, and ban us for sending it.
+                error!("message too large for {}, dropped: {} ({} bytes)", node.uid, outgoing.uid, message.unwrap().size());
+                let _ = outgoing.delete(conn);
+            } else if let Ok(message) = message {
+                // Waits while the send queue is full, for a peer that still
+                // reads.
+                match tokio::time::timeout(ping_timeout, tx.send((message, Some(outgoing.id)))).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => {
+                        // The send loop is gone, so is the connection.
+                        warn!("connection with {} closed, message kept queued: {:?}", node.uid, e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("{} stopped reading for {}s", node.uid, ping_timeout.as_secs());
+                        return;
+                    }
+                }
+            } else {
+                warn!("can't convert RMessage to a frame");
+                let _ = outgoing.delete(conn);
+            }
+        }
+
+        if idle {
+            if let Some(stats) = compressor.report() {
+                info!("compression with {}: {}", node.uid, stats);
+            }
+
+            tokio::select! {
+                _ = RMessageOutgoing::wait_for(&node.uid, ping_interval.min(QUEUE_POLL_INTERVAL)) => (),
+                _ = tx.closed() => return,
+            }
+        }
+    }
+}
+
+/// Writes the frames handed by [`deliver`], and removes the queued messages
+/// they carry once written.
+async fn send_loop(configs: RConfig, mut sender: RFrameSender, mut rx: mpsc::Receiver<(RPeerFrame, Option<i32>)>) {
+    let database_url = configs.database.path.clone();
+    let mut conn = connection::establish(database_url.as_str()).unwrap();
+
+    while let Some((message, outgoing)) = rx.recv().await {
+        let close = message == RPeerFrame::Close;
+
+        if let Err(e) = sender.send(message).await {
+            warn!("frame not sent, connection closed: {:?}", e);
+            let _ = sender.close().await;
+            return;
+        }
+
+        if let Some(outgoing) = outgoing {
+            if RMessageOutgoing::delete_by_id(&mut conn, outgoing).is_err() {
+                warn!("can't delete outgoing message: {}", outgoing);
+            }
+        }
+
+        // If it's a close message, just send it and then return.
+        if close {
+            return;
+        }
+    }
+}
+
+async fn receive_loop(
+    configs: RConfig,
+    mut node: RNode,
+    codec: RCodec,
+    mut receiver: RFrameReceiver,
+    tx: mpsc::Sender<(RPeerFrame, Option<i32>)>,
+    heard: Arc<Mutex<Instant>>,
+) {
+    let database_url = configs.database.path.clone();
+    let mut conn = connection::establish(database_url.as_str()).unwrap();
+    let mut peer_limits = RPeerLimits::new(&configs);
+
+    while let Some(message) = receiver.next().await {
+        let message = match message {
+            Ok(m) => m,
+            Err(e) => {
+                if limits::is_oversized(&e) {
+                    limits::ban(&configs, &node.uid, &format!("{:?}", e));
+                } else {
+                    warn!("connection with {} lost: {:?}", node.uid, e);
+                }
+                let _ = tx.send((RPeerFrame::Close, None)).await;
+                return;
+            }
+        };
+
+        if let Ok(mut heard) = heard.lock() {
+            *heard = Instant::now();
+        }
+
+        match message {
+            RPeerFrame::Close => {
+                // Got a close message, so send a close message and return
+                let _ = tx.send((RPeerFrame::Close, None)).await;
+                return;
+            }
+            RPeerFrame::Ping => {
+                let _ = tx.send((RPeerFrame::Pong, None)).await;
+            }
+            RPeerFrame::Pong => {
+                if node.set_last_seen(&mut conn).is_err() {
+                    warn!("node last seen not updated: {}", node.uid);
+                }
+            }
+            RPeerFrame::Data(data) | RPeerFrame::Transfer(data) => {
+                if let Err(e) = peer_limits.check(data.len()) {
+                    limits::ban(&configs, &node.uid, &format!("{}", e));
+                    if let Ok(reply) = codec.to_frame(&REnvelope::new(e.to_message())) {
+                        let _ = tx.send((reply, None)).await;
+                    }
+                    let _ = tx.send((RPeerFrame::Close, None)).await;
+                    return;
+                }
+
+                peer_limits.wait_for_queue(&mut conn, &node.uid).await;
+
+                tokio::task::block_in_place(|| {
+                    if let Ok(mut message) = codec.decode_message(data.as_slice()) {
+                        if let Err(e) = compression::decompress(&mut message, configs.limits.max_frame_size) {
+                            warn!("{} from {}: {}", message.message, node.uid, e);
+                            let _ = RMessageOutgoing::push_envelope(&mut conn, node.uid.clone(), message.reply(e.to_message()));
+                        } else if RMessagesIncoming::push_envelope(&mut conn, node.uid.clone(), message).is_err() {
+                            warn!("can't queue message from {}", node.uid);
                         }
                     } else {
-                        error!("Not valid client: {}", node.connection_url(false));
+                        warn!("not valid message from {}", node.uid);
                     }
                 });
             }
-        } else {
-            warn!("nodes not found");
         }
-    });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let configs = RConfigConnections { min_backoff: 2, max_backoff: 60, ..RConfigConnections::default() };
+
+        for (failures, full) in [(0, 2), (1, 4), (2, 8), (4, 32), (5, 60), (9, 60), (u32::MAX, 60)] {
+            for _ in 0..20 {
+                let delay = backoff(&configs, failures);
+
+                // Never more than the full delay, never less than half of it.
+                assert!(delay <= Duration::from_secs(full), "{} failures: {:?}", failures, delay);
+                assert!(delay >= Duration::from_millis(full * 500), "{} failures: {:?}", failures, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_cap_below_the_first_delay_is_ignored() {
+        let configs = RConfigConnections { min_backoff: 10, max_backoff: 1, ..RConfigConnections::default() };
+
+        assert!(backoff(&configs, 3) >= Duration::from_secs(5));
+        assert!(backoff(&configs, 3) <= Duration::from_secs(10));
+    }
 }
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use diesel::SqliteConnection;
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures_timer::Delay;
+use log::warn;
+
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_outgoing::RMessageOutgoing;
+use crate::models::utils::error::RDatabaseError;
+use crate::protocol::message::{REnvelope, RMessage};
+
+#[derive(Debug)]
+pub enum RRequestError {
+    NotQueued(RDatabaseError),
+    Timeout(Duration),
+    Canceled,
+}
+
+impl std::fmt::Display for RRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RRequestError::NotQueued(e) => write!(f, "request not queued: {:?}", e),
+            RRequestError::Timeout(timeout) => write!(f, "no reply after {}s", timeout.as_secs()),
+            RRequestError::Canceled => write!(f, "request canceled"),
+        }
+    }
+}
+
+/// Reply to a request, see [`request`].
+pub type RReply = Pin<Box<dyn Future<Output = Result<RMessage, RRequestError>> + Send>>;
+
+/// A request waiting for the reply of node `node_uid`.
+struct RPending {
+    node_uid: String,
+    sender: oneshot::Sender<RMessage>,
+}
+
+/// Requests waiting for a reply, by envelope id.
+fn pending() -> &'static Mutex<HashMap<String, RPending>> {
+    static PENDING: OnceLock<Mutex<HashMap<String, RPending>>> = OnceLock::new();
+    PENDING.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn forget(id: &String) {
+    if let Ok(mut pending) = pending().lock() {
+        pending.remove(id);
+    }
+}
+
+/// Queues `message` for `node_uid` and returns a future resolved by the
+/// matching reply, or by an error once `timeout` has passed.
+///
+/// Replies travel back on the peer's own connection to us, so they are
+/// matched by id and node when dispatched, see [`resolve`]. The request
+/// fails early if the connection to the node drops, see [`cancel`].
+pub fn request(
+    conn: &mut SqliteConnection,
+    node_uid: &str,
+    message: RMessage,
+    timeout: Duration,
+) -> RReply {
+    let envelope = REnvelope::new(message);
+    let id = envelope.id.clone();
+    let (sender, receiver) = oneshot::channel();
+
+    if let Ok(mut pending) = pending().lock() {
+        pending.insert(id.clone(), RPending { node_uid: node_uid.to_string(), sender });
+    }
+
+    let queued = RMessageOutgoing::push_envelope(conn, node_uid.to_string(), envelope);
+
+    Box::pin(async move {
+        if let Err(e) = queued {
+            forget(&id);
+            return Err(RRequestError::NotQueued(e));
+        }
+
+        let result = match future::select(receiver, Delay::new(timeout)).await {
+            Either::Left((Ok(reply), _)) => Ok(reply),
+            Either::Left((Err(_), _)) => Err(RRequestError::Canceled),
+            Either::Right(_) => Err(RRequestError::Timeout(timeout)),
+        };
+
+        forget(&id);
+
+        result
+    })
+}
+
+/// Hands a reply of node `node_uid` to the request waiting for it. Returns
+/// false when no request waits, it timed out, was never made by this
+/// process or was sent to another node.
+pub fn resolve(node_uid: &String, in_reply_to: &String, message: RMessage) -> bool {
+    let request = pending().lock().ok().and_then(|mut pending| {
+        if pending.get(in_reply_to).is_some_and(|request| request.node_uid == *node_uid) {
+            return pending.remove(in_reply_to);
+        }
+
+        None
+    });
+
+    if let Some(request) = request {
+        return request.sender.send(message).is_ok();
+    }
+
+    false
+}
+
+/// Fails the requests waiting for node `node_uid`, once the connection to it
+/// dropped. Those not sent yet are taken out of the queue as well, so they
+/// aren't answered to nobody on the next connection. Returns how many failed.
+pub fn cancel(conn: &mut SqliteConnection, node_uid: &String) -> usize {
+    let ids: Vec<String> = pending()
+        .lock()
+        .map(|mut pending| {
+            let ids: Vec<String> =
+                pending.iter().filter(|(_, request)| request.node_uid == *node_uid).map(|(id, _)| id.clone()).collect();
+
+            // Dropping the senders fails the requests as canceled.
+            for id in ids.iter() {
+                pending.remove(id);
+            }
+
+            ids
+        })
+        .unwrap_or_default();
+
+    if !ids.is_empty() && RMessageOutgoing::delete_by_uids(conn, ids.as_slice()).is_err() {
+        warn!("canceled requests left queued for {}", node_uid);
+    }
+
+    ids.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::utils::connection;
+    use crate::protocol::message::{RErrorCode, RMReplicaStored};
+
+    /// Envelope ids of the messages queued for `node_uid`.
+    fn queued(conn: &mut SqliteConnection, node_uid: &str) -> Vec<String> {
+        RMessageOutgoing::first_n_by_node_after(conn, &node_uid.to_string(), 0, 100)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.uid)
+            .collect()
+    }
+
+    fn stored(uid: &str) -> RMessage {
+        RMessage::ReplicaStored(RMReplicaStored { uid: uid.to_string() })
+    }
+
+    #[test]
+    fn reply_resolves_its_own_request() {
+        let mut conn = connection::in_memory();
+        let node = "correlated".to_string();
+
+        let first = request(&mut conn, &node, stored("first"), Duration::from_secs(5));
+        let second = request(&mut conn, &node, stored("second"), Duration::from_secs(5));
+        let ids = queued(&mut conn, &node);
+
+        assert_eq!(ids.len(), 2);
+
+        // Another node can't answer for it, nor an unknown id.
+        assert!(!resolve(&"impostor".to_string(), &ids[0], RMessage::OK));
+        assert!(!resolve(&node, &"unknown".to_string(), RMessage::OK));
+
+        assert!(resolve(&node, &ids[1], RMessage::error(RErrorCode::UnknownFile, "second".to_string())));
+        assert!(resolve(&node, &ids[0], RMessage::OK));
+
+        // Answered once only.
+        assert!(!resolve(&node, &ids[0], RMessage::OK));
+
+        assert!(matches!(futures::executor::block_on(first), Ok(RMessage::OK)));
+        assert!(matches!(futures::executor::block_on(second), Ok(RMessage::Error(error)) if error.code == RErrorCode::UnknownFile));
+    }
+
+    #[test]
+    fn request_times_out_without_reply() {
+        let mut conn = connection::in_memory();
+        let node = "silent".to_string();
+
+        let reply = request(&mut conn, &node, stored("late"), Duration::from_millis(50));
+        let ids = queued(&mut conn, &node);
+
+        assert!(matches!(futures::executor::block_on(reply), Err(RRequestError::Timeout(_))));
+
+        // A reply arriving afterwards finds nobody waiting.
+        assert!(!resolve(&node, &ids[0], RMessage::OK));
+    }
+
+    #[test]
+    fn disconnect_fails_the_node_requests() {
+        let mut conn = connection::in_memory();
+        let dropped = "dropped".to_string();
+        let other = "connected".to_string();
+
+        let first = request(&mut conn, &dropped, stored("first"), Duration::from_secs(5));
+        let second = request(&mut conn, &dropped, stored("second"), Duration::from_secs(5));
+        let kept = request(&mut conn, &other, stored("kept"), Duration::from_secs(5));
+
+        assert_eq!(cancel(&mut conn, &dropped), 2);
+
+        assert!(matches!(futures::executor::block_on(first), Err(RRequestError::Canceled)));
+        assert!(matches!(futures::executor::block_on(second), Err(RRequestError::Canceled)));
+
+        // Taken out of the queue, those of other nodes left alone.
+        assert!(queued(&mut conn, &dropped).is_empty());
+
+        let ids = queued(&mut conn, &other);
+
+        assert!(resolve(&other, &ids[0], RMessage::OK));
+        assert!(matches!(futures::executor::block_on(kept), Ok(RMessage::OK)));
+    }
+}
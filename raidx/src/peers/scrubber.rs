@@ -0,0 +1,295 @@
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use diesel::SqliteConnection;
+
+use log::{error, info, warn};
+use sha1::{Digest, Sha1};
+
+use crate::models::chunks::RChunk;
+use crate::models::files::{RFile, FILE_STATUS_CORRUPTED, FILE_STATUS_READY, FILE_STATUS_REPLICA};
+use crate::models::nodes::RNode;
+use crate::models::replicas::RReplica;
+use crate::models::utils::connection;
+use crate::peers::requests::{self, RReply};
+use crate::protocol::message::{RMFileRequest, RMessage};
+use crate::utils::configs::RConfig;
+use crate::utils::chunks;
+use crate::utils::crypto::RShareKey;
+use crate::utils::rate::RRateLimiter;
+
+const SCRUB_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RScrubResult {
+    Missing,
+    Recorded,
+    Healthy,
+    Modified,
+    Repaired,
+    Corrupted,
+}
+
+fn hash_file(entry: &std::path::Path, limiter: &mut RRateLimiter) -> Option<String> {
+    let file = std::fs::File::open(entry);
+
+    if file.is_err() {
+        return None;
+    }
+
+    let mut file = file.unwrap();
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0u8; SCRUB_CHUNK_SIZE];
+
+    loop {
+        let read = file.read(buffer.as_mut_slice());
+
+        if read.is_err() {
+            return None;
+        }
+
+        let read = read.unwrap();
+
+        if read == 0 {
+            break;
+        }
+
+        limiter.acquire(read as u64);
+        hasher.update(&buffer[..read]);
+    }
+
+    Some(format!("{:X}", hasher.finalize()))
+}
+
+/// A repair asked to another node, waited for at the end of the round.
+pub struct RRepairRequest {
+    pub uid: String,
+    pub node: String,
+    pub reply: RReply,
+}
+
+/// Asks a node holding a good copy of the file to send it back.
+///
+/// Replicas of a remote file are repaired from their owner, local files
+/// from any node that confirmed a replica of them.
+pub fn request_repair(conn: &mut SqliteConnection, configs: &RConfig, file: &RFile, local_node: &RNode) -> Option<RRepairRequest> {
+    let mut path = file.relative_path(&configs.folder_path)?;
+
+    let mut candidates = Vec::<String>::new();
+
+    if file.node != local_node.uid {
+        candidates.push(file.node.clone());
+    } else if let Ok(replicas) = RReplica::get_by_file(conn, &file.uid) {
+        for replica in replicas {
+            if replica.is_confirmed() {
+                candidates.push(replica.node);
+            }
+        }
+    }
+
+    let mut candidates: Vec<RNode> = candidates
+        .into_iter()
+        .filter_map(|node_uid| RNode::get_by_uid(conn, node_uid).ok())
+        .collect();
+
+    // Plaintext copies first, encrypted ones only as a fallback.
+    candidates.sort_by_key(|node| node.untrusted);
+
+    if let Some(node) = candidates.first() {
+        if node.untrusted {
+            if let Ok(Some(share_key)) = RShareKey::load_or_create(configs) {
+                path = share_key.stored_path(configs, &path);
+            } else {
+                warn!(target: "SCRUBBER", "encryption not enabled, can't repair from {}", node.uid);
+                return None;
+            }
+        }
+
+        let message = RMessage::FileRequest(RMFileRequest {
+            uid: file.uid.clone(),
+            path,
+        });
+
+        let timeout = Duration::from_secs(configs.scrubber.repair_timeout);
+        let reply = requests::request(conn, &node.uid, message, timeout);
+
+        info!(target: "SCRUBBER", "repair requested: {} from {}", file.uid, node.uid);
+
+        return Some(RRepairRequest { uid: file.uid.clone(), node: node.uid.clone(), reply });
+    } else {
+        warn!(target: "SCRUBBER", "no replica to repair from: {}", file.abspath());
+    }
+
+    None
+}
+
+/// Waits for the replies to the repairs requested during a round. The
+/// repaired content itself is written by the handler.
+pub fn wait_repairs(repairs: Vec<RRepairRequest>) {
+    let (requests, replies): (Vec<_>, Vec<_>) = repairs.into_iter().map(|repair| ((repair.uid, repair.node), repair.reply)).unzip();
+    let replies = futures::executor::block_on(futures::future::join_all(replies));
+
+    for ((uid, node), reply) in requests.into_iter().zip(replies) {
+        match reply {
+            Ok(RMessage::FileRepair(_)) => info!(target: "SCRUBBER", "repair received: {} from {}", uid, node),
+            Ok(RMessage::Error(error)) => warn!(target: "SCRUBBER", "repair refused: {} by {}: {} ({})", uid, node, error, error.code.retry()),
+            Ok(reply) => warn!(target: "SCRUBBER", "unexpected reply to repair {} from {}: {}", uid, node, reply),
+            Err(e) => warn!(target: "SCRUBBER", "repair failed: {} from {}: {}", uid, node, e),
+        }
+    }
+}
+
+pub fn scrub_file(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    limiter: &mut RRateLimiter,
+    file: &mut RFile,
+    local_node: &RNode,
+    repairs: &mut Vec<RRepairRequest>,
+) -> RScrubResult {
+    let abspath = file.abspath();
+    let entry = std::path::Path::new(abspath.as_str());
+
+    let digest = hash_file(entry, limiter);
+
+    // Gone from the folder but not from the table, asked back as a corrupted
+    // file would be. Without a digest no copy could be checked.
+    if digest.is_none() {
+        if let Some(stored) = file.digest.clone() {
+            if file.status != FILE_STATUS_CORRUPTED {
+                let _ = file.set_status(conn, FILE_STATUS_CORRUPTED);
+                error!(target: "SCRUBBER", "file missing: {}", abspath);
+            }
+
+            let _ = file.set_scrubbed(conn, stored);
+            repairs.extend(request_repair(conn, configs, file, local_node));
+        }
+
+        return RScrubResult::Missing;
+    }
+
+    let digest = digest.unwrap();
+
+    if file.digest.is_none() {
+        let _ = file.set_scrubbed(conn, digest);
+        return RScrubResult::Recorded;
+    }
+
+    let stored = file.digest.clone().unwrap();
+
+    if stored == digest {
+        let _ = file.set_scrubbed(conn, digest);
+
+        if file.status == FILE_STATUS_CORRUPTED {
+            let status = if file.node == local_node.uid { FILE_STATUS_READY } else { FILE_STATUS_REPLICA };
+            let _ = file.set_status(conn, status);
+            return RScrubResult::Repaired;
+        }
+
+        return RScrubResult::Healthy;
+    }
+
+    // A newer modification time means the content changed on purpose, but
+    // only local files are edited here: replicas follow their node.
+    let modified_at = entry.metadata().map(|metadata| metadata.mtime() as i32).unwrap_or(file.modified_at);
+
+    if file.node == local_node.uid && modified_at > file.modified_at && file.status != FILE_STATUS_CORRUPTED {
+        let _ = file.update_from_entry(conn, entry);
+        let _ = file.set_scrubbed(conn, digest);
+        return RScrubResult::Modified;
+    }
+
+    if file.status != FILE_STATUS_CORRUPTED {
+        let _ = file.set_status(conn, FILE_STATUS_CORRUPTED);
+        error!(target: "SCRUBBER", "file corrupted: {} ({} != {})", abspath, digest, stored);
+    }
+
+    let _ = file.set_scrubbed(conn, stored);
+    repairs.extend(request_repair(conn, configs, file, local_node));
+
+    RScrubResult::Corrupted
+}
+
+/// Cuts a file checked healthy into chunks, when its content changed since
+/// it was last cut, so peers can send here only the chunks it lacks.
+fn index_chunks(conn: &mut SqliteConnection, limiter: &mut RRateLimiter, file: &RFile) {
+    if RChunk::is_indexed(conn, file) {
+        return;
+    }
+
+    let content = std::fs::read(file.abspath());
+
+    if content.is_err() {
+        return;
+    }
+
+    let content = content.unwrap();
+    limiter.acquire(content.len() as u64);
+
+    if let Err(e) = RChunk::index(conn, file, chunks::cut(content.as_slice()).as_slice()) {
+        warn!(target: "SCRUBBER", "chunks not indexed: {}: {:?}", file.uid, e);
+    }
+}
+
+pub fn scrub(conn: &mut SqliteConnection, configs: &RConfig, limiter: &mut RRateLimiter) -> Vec<RScrubResult> {
+    let mut results = Vec::<RScrubResult>::new();
+
+    let local_node = RNode::get_local(conn);
+
+    if local_node.is_none() {
+        error!(target: "SCRUBBER", "Not valid local node");
+        return results;
+    }
+
+    let local_node = local_node.unwrap();
+    let files = RFile::get_least_recently_scrubbed(conn, configs.scrubber.max_files);
+
+    if files.is_err() {
+        error!(target: "SCRUBBER", "not valid result from database");
+        return results;
+    }
+
+    let mut repairs = Vec::<RRepairRequest>::new();
+
+    for mut file in files.unwrap() {
+        let result = scrub_file(conn, configs, limiter, &mut file, &local_node, &mut repairs);
+
+        if result != RScrubResult::Corrupted && result != RScrubResult::Missing {
+            index_chunks(conn, limiter, &file);
+        }
+
+        results.push(result);
+    }
+
+    wait_repairs(repairs);
+
+    results
+}
+
+pub fn init(configs: RConfig) {
+    thread::spawn(move || {
+        let database_url = configs.database.path.clone();
+        let mut conn = connection::establish(database_url.as_str()).unwrap();
+        let mut limiter = RRateLimiter::new(configs.scrubber.max_bytes_per_second);
+
+        loop {
+            let results = scrub(&mut conn, &configs, &mut limiter);
+
+            let corrupted = results.iter().filter(|result| **result == RScrubResult::Corrupted).count();
+            let repaired = results.iter().filter(|result| **result == RScrubResult::Repaired).count();
+
+            info!(
+                target: "SCRUBBER",
+                "{} files scrubbed, {} corrupted, {} repaired",
+                results.len(),
+                corrupted,
+                repaired
+            );
+
+            sleep(Duration::from_secs(configs.scrubber.timeout as u64));
+        }
+    });
+}
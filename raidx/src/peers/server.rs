@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_incoming::RMessagesIncoming;
+use crate::models::queues::messages_outgoing::RMessageOutgoing;
+use crate::models::utils::connection;
+use crate::peers::auth::{self, RIdentity};
+use crate::peers::limits::{self, RPeerLimits};
+use crate::peers::transport::{self, RIncoming, RPeerFrame, RTransportError};
+use crate::protocol::codec::RCodec;
+use crate::protocol::compression;
+use crate::protocol::message::REnvelope;
+use crate::utils::configs::RConfig;
+
+pub fn init(configs: RConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let address = format!("{}:{}", configs.server.host, configs.server.port);
+        let mut listener = match transport::listen(&configs).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(target: "SERVER", "can't listen on {}: {}", address, e);
+                return;
+            }
+        };
+
+        let identity = match RIdentity::load_or_create(&configs) {
+            Ok(identity) => Arc::new(identity),
+            Err(e) => {
+                error!(target: "SERVER", "not valid node key: {:?}", e);
+                return;
+            }
+        };
+
+        info!(
+            target: "SERVER",
+            "listening on {} (ssl: {}, transport: {:?})",
+            address, configs.server.ssl, configs.server.transport
+        );
+
+        loop {
+            let incoming = match listener.accept().await {
+                Ok(incoming) => incoming,
+                Err(RTransportError::Closed) => return,
+                Err(_) => continue,
+            };
+
+            tokio::spawn(serve(configs.clone(), identity.clone(), incoming));
+        }
+    })
+}
+
+/// Authenticates a peer connection and queues the messages it sends.
+async fn serve(configs: RConfig, identity: Arc<RIdentity>, incoming: RIncoming) {
+    let peer_ip = incoming.peer_ip;
+
+    let client = incoming.open.await;
+
+    if let Err(e) = client.as_ref() {
+        warn!(target: "SERVER", "not valid connection from {}: {}", peer_ip, e);
+        return;
+    }
+
+    let mut client = client.unwrap();
+
+    let database_url = configs.database.path.clone();
+    let mut conn = connection::establish(database_url.as_str()).unwrap();
+
+    let (node, session) = match auth::server_handshake(&mut client, &mut conn, &configs, &identity, &peer_ip).await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            warn!(target: "SERVER", "peer {} not authenticated: {}", peer_ip, e);
+            client.close().await;
+            return;
+        }
+    };
+
+    if limits::is_banned(&node.uid) {
+        warn!(target: "SERVER", "peer banned, connection refused: {}:{} ({})", node.host, node.port, node.uid);
+        client.close().await;
+        return;
+    }
+
+    info!(
+        target: "SERVER",
+        "peer connected: {}:{} ({}), protocol v{}, capabilities {:#x}",
+        node.host, node.port, node.uid, session.version, session.capabilities
+    );
+
+    let codec = RCodec::negotiate(&session);
+    let mut peer_limits = RPeerLimits::new(&configs);
+
+    while let Some(message) = client.recv().await {
+        let message = match message {
+            Ok(m) => m,
+            Err(e) => {
+                if limits::is_oversized(&e) {
+                    limits::ban(&configs, &node.uid, &format!("{:?}", e));
+                } else {
+                    warn!(target: "SERVER", "receive error from {}: {:?}", peer_ip, e);
+                }
+                client.close().await;
+                return;
+            }
+        };
+
+        match message {
+            RPeerFrame::Data(data) | RPeerFrame::Transfer(data) => {
+                if let Err(e) = peer_limits.check(data.len()) {
+                    limits::ban(&configs, &node.uid, &format!("{}", e));
+                    if let Ok(reply) = codec.to_frame(&REnvelope::new(e.to_message())) {
+                        let _ = client.send(reply).await;
+                    }
+                    client.close().await;
+                    return;
+                }
+
+                peer_limits.wait_for_queue(&mut conn, &node.uid).await;
+
+                // Decompressing and queueing block, other tasks move to
+                // another worker meanwhile.
+                tokio::task::block_in_place(|| {
+                    let message = codec.decode_message(data.as_slice());
+
+                    if message.is_err() {
+                        warn!(target: "SERVER", "not valid message from {}", peer_ip);
+                        return;
+                    }
+
+                    let mut message = message.unwrap();
+
+                    if let Err(e) = compression::decompress(&mut message, configs.limits.max_frame_size) {
+                        warn!(target: "SERVER", "{} from {}: {}", message.message, node.uid, e);
+                        let _ = RMessageOutgoing::push_envelope(&mut conn, node.uid.clone(), message.reply(e.to_message()));
+                        return;
+                    }
+
+                    if let Err(e) = RMessagesIncoming::push_envelope(&mut conn, node.uid.clone(), message) {
+                        warn!(target: "SERVER", "can't queue message from {}: {:?}", node.uid, e);
+                    }
+                });
+            }
+            RPeerFrame::Ping => {
+                let _ = client.send(RPeerFrame::Pong).await;
+            }
+            RPeerFrame::Close => {
+                client.close().await;
+                return;
+            }
+            RPeerFrame::Pong => (),
+        }
+    }
+}
@@ -1,131 +1,275 @@
-extern crate websocket;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
 
-use std::thread;
-use std::thread::sleep;
-use std::time::Duration;
-
-use diesel::prelude::*;
 use diesel::SqliteConnection;
 
 use glob::glob;
 
 use log::{error, info, warn};
+use tokio::sync::Notify;
 
-use crate::models::files::{NewRFile, RFile};
+use crate::models::files::{NewRFile, RFile, FILE_STATUS_CORRUPTED};
 use crate::models::nodes::RNode;
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_outgoing::RMessageOutgoing;
+use crate::models::utils::connection;
+use crate::peers::nodes::{load_local_node_from_configs, load_nodes_from_configs};
+use crate::protocol::message::{RMFileAdded, RMFileModified, RMFileRemoved, RMFileRequest, RMessage};
 use crate::utils::configs::RConfig;
 
-pub fn init_sync(configs: RConfig) {
-    let configs = configs.clone();
-    let database_url = configs.database.path.clone();
+/// Changes made to the shared folder while the deamon was not running.
+#[derive(Debug, Clone, Default)]
+pub struct ROfflineChanges {
+    pub added: Vec<RFile>,
+    pub modified: Vec<RFile>,
+    pub removed: Vec<RFile>,
+    /// Replicas missing or changed, asked back from the node they came from.
+    pub damaged: Vec<RFile>,
+}
 
-    let mut conn = SqliteConnection::establish(database_url.as_str()).unwrap();
+impl ROfflineChanges {
+    pub fn is_empty(&self) -> bool {
+        return self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty() && self.damaged.is_empty();
+    }
+}
 
-    let local_node = RNode::get_local_or_create(&mut conn, "0.0.0.0".to_string(), 4000);
+/// A replica missing or changed while the deamon was not running. It keeps
+/// the digest its node gave it and is marked corrupted, so it isn't used
+/// for repairs and the copy asked back is checked against that digest.
+fn detect_damaged_replica(conn: &mut SqliteConnection, file: &mut RFile, entry: &std::path::Path) -> bool {
+    if entry.exists() {
+        if !file.is_modified(entry) {
+            return false;
+        }
 
-    if local_node.is_some() {
-        let local_node = local_node.unwrap();
+        // Replicas are written after their row, only the content tells.
+        if RFile::calc_digest(entry) == file.digest {
+            let _ = file.update_from_entry(conn, entry);
+            return false;
+        }
+    }
+
+    if file.status != FILE_STATUS_CORRUPTED && file.set_status(conn, FILE_STATUS_CORRUPTED).is_err() {
+        error!(target: "START_SYNC", "error to mark replica corrupted: {:?}", entry);
+        return false;
+    }
+
+    return true;
+}
+
+/// Diffs the `files` table against the shared folder and updates the table
+/// so it matches what is on disk.
+pub fn detect_offline_changes(conn: &mut SqliteConnection, configs: &RConfig, local_node: &RNode) -> ROfflineChanges {
+    let mut changes = ROfflineChanges::default();
 
-        let results = RFile::get_all(&mut conn);
+    let results = RFile::get_all(conn);
 
-        if results.is_ok() {
-            let files = results.unwrap();
+    if results.is_ok() {
+        let files = results.unwrap();
 
-            for file in files {
-                let path = file.abspath();
-                let entry = std::path::Path::new(path.as_str());
+        for mut file in files {
+            let path = file.abspath();
+            let entry = std::path::Path::new(path.as_str());
 
-                if !entry.exists() {
-                    RFile::remove_from_uid(&mut conn, &file.uid);
-                    info!(target: "START_SYNC", "FILE REMOVED: {:?} ({})", file.abspath(), file.uid);
+            if file.node != local_node.uid {
+                if detect_damaged_replica(conn, &mut file, entry) {
+                    info!(target: "START_SYNC", "REPLICA DAMAGED: {:?} ({})", file.abspath(), file.uid);
+
+                    changes.damaged.push(file);
+                }
+            } else if !entry.exists() {
+                RFile::remove_from_uid(conn, &file.uid);
+                info!(target: "START_SYNC", "FILE REMOVED: {:?} ({})", file.abspath(), file.uid);
+
+                changes.removed.push(file);
+            } else if file.is_modified(entry) {
+                if file.update_from_entry(conn, entry).is_ok() {
+                    info!(target: "START_SYNC", "FILE MODIFIED: {:?} ({})", file.abspath(), file.uid);
+
+                    changes.modified.push(file);
                 } else {
+                    error!(target: "START_SYNC", "error to update file: {:?}", entry);
                 }
             }
-        } else {
-            error!(target: "START_SYNC", "not valid result from database");
         }
+    } else {
+        error!(target: "START_SYNC", "not valid result from database");
+    }
 
-        let raidx_path = configs.folder_path.clone();
+    let raidx_path = configs.folder_path.clone();
 
-        let raidx_check_pattern = format!("{}/**/*", raidx_path);
-        let results = glob(&raidx_check_pattern);
+    let raidx_check_pattern = format!("{}/**/*", raidx_path);
+    let results = glob(&raidx_check_pattern);
 
-        if results.is_ok() {
-            let results = results.unwrap();
+    if results.is_ok() {
+        let results = results.unwrap();
 
-            for entry in results {
-                let entry = entry.unwrap();
+        for entry in results {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(target: "START_SYNC", "not readable entry: {}", e);
+                    continue;
+                }
+            };
 
-                if entry.is_file() {
-                    let file = NewRFile::from_entry(&mut conn, &local_node, &entry);
+            if entry.is_file() && RFile::from_entry(conn, &entry).is_none() {
+                let file = NewRFile::from_entry(conn, local_node, &entry);
 
-                    if file.is_ok() {
-                        let file = file.unwrap();
+                if file.is_ok() {
+                    let file = file.unwrap();
 
-                        info!(target: "START_SYNC", "FILE ADDED: {:?} ({})", entry, file.uid);
-                    } else {
-                        //error!(target: "START_SYNC", "error to add file: {:?}", entry);
-                    }
+                    info!(target: "START_SYNC", "FILE ADDED: {:?} ({})", entry, file.uid);
+
+                    changes.added.push(file);
+                } else {
+                    error!(target: "START_SYNC", "error to add file: {:?}", entry);
                 }
             }
-        } else {
-            error!(target: "START_SYNC", "not valid RAIDX check pattern: {}", raidx_check_pattern);
         }
     } else {
-        error!("Not valid local node");
+        error!(target: "START_SYNC", "not valid RAIDX check pattern: {}", raidx_check_pattern);
     }
+
+    return changes;
 }
 
-pub fn init(configs: RConfig) {
-    init_sync(configs.clone());
-    thread::spawn(move || {
-        let database_url = configs.database.path.clone();
-        let mut conn = SqliteConnection::establish(database_url.as_str()).unwrap();
-        let local_node = RNode::get_local_or_create(&mut conn, "0.0.0.0".to_string(), 4000);
+/// Queues the messages announcing the offline changes to the other nodes.
+///
+/// Only files owned by the local node are announced, replicas are the
+/// business of the node they came from: damaged ones are asked back from
+/// it, which also drops its confirmation of them.
+pub fn announce_offline_changes(conn: &mut SqliteConnection, configs: &RConfig, local_node: &RNode, changes: &ROfflineChanges) -> usize {
+    let mut count = 0;
 
-        if local_node.is_some() {
-            let local_node = local_node.unwrap();
+    for file in changes.damaged.iter() {
+        let path = file.relative_path(&configs.folder_path).unwrap_or_default();
+        let request = RMessage::FileRequest(RMFileRequest { uid: file.uid.clone(), path });
 
-            loop {
-                let _new_files = sync_files(&configs, &mut conn, &local_node);
+        if RMessageOutgoing::push(conn, file.node.clone(), request).is_ok() {
+            count += 1;
+        } else {
+            warn!(target: "START_SYNC", "can't queue repair request: {}", file.uid);
+        }
+    }
 
-                for _node in configs.clone().nodes {}
+    if !local_node.can_send() {
+        info!(target: "START_SYNC", "local node is receive-only, offline changes kept local");
+        return count;
+    }
 
-                sleep(Duration::from_secs(configs.synchronizer.timeout as u64));
-            }
+    let added = changes.added.iter().map(|file| (file, RMessage::FileAdded(RMFileAdded { file: file.clone() })));
+    let modified = changes.modified.iter().map(|file| (file, RMessage::FileModified(RMFileModified { file: file.clone(), path: None })));
+    let removed = changes.removed.iter().map(|file| (file, RMessage::FileRemoved(RMFileRemoved { file: file.clone() })));
+
+    for (file, message) in added.chain(modified).chain(removed) {
+        if file.node != local_node.uid {
+            continue;
+        }
+
+        let messages = RMessageOutgoing::push_to_others(conn, message.clone());
+
+        if let Ok(messages) = messages {
+            count += messages.len();
         } else {
-            error!("Not valid local nde");
+            warn!(target: "START_SYNC", "can't queue {} message: {}", message, file.uid);
         }
-    });
+    }
+
+    return count;
 }
 
-fn sync_files(configs: &RConfig, conn: &mut SqliteConnection, local_node: &RNode) -> Vec<RFile> {
-    let pattern = format!("{}/**/*", configs.folder_path);
-    let results = glob(&pattern).unwrap();
+/// Diffs the shared folder against the `files` table, then announces what
+/// changed.
+fn sync_changes(conn: &mut SqliteConnection, configs: &RConfig, local_node: &RNode) {
+    let changes = detect_offline_changes(conn, configs, local_node);
+
+    if !changes.is_empty() {
+        let count = announce_offline_changes(conn, configs, local_node, &changes);
+
+        info!(
+            target: "START_SYNC",
+            "offline changes: {} added, {} modified, {} removed, {} replicas damaged ({} messages queued)",
+            changes.added.len(),
+            changes.modified.len(),
+            changes.removed.len(),
+            changes.damaged.len(),
+            count
+        );
+    }
+}
 
-    let mut new_files = Vec::<RFile>::new();
+pub fn init_sync(configs: RConfig) {
+    let configs = configs.clone();
+    let database_url = configs.database.path.clone();
 
-    for entry in results {
-        if entry.is_ok() {
-            let entry = entry.unwrap();
+    let mut conn = connection::establish(database_url.as_str()).unwrap();
 
-            if entry.is_file() {
-                //println!("UID: {:x}\nFOLDER: {}\nfilename: {} ", uid, folder, name);
-                let file = NewRFile::from_entry(conn, local_node, &entry);
+    let local_node = RNode::get_local_or_create(&mut conn, "0.0.0.0".to_string(), 4000);
 
-                if file.is_ok() {
-                    let file = file.unwrap();
+    if local_node.is_some() {
+        let local_node = local_node.unwrap();
+
+        // Peers and roles have to be known before the changes can be queued for them.
+        load_nodes_from_configs(&configs);
+        load_local_node_from_configs(&configs);
+
+        let local_node = RNode::get_local(&mut conn).unwrap_or(local_node);
+
+        sync_changes(&mut conn, &configs, &local_node);
+    } else {
+        error!("Not valid local node");
+    }
+}
 
-                    info!("new file was added {}", file.filename);
-                    new_files.push(file);
+/// Rescans asked for, by shared folder: nodes running in the same process
+/// are woken apart.
+fn rescans(folder_path: &str) -> Option<Arc<Notify>> {
+    static RESCANS: OnceLock<Mutex<HashMap<String, Arc<Notify>>>> = OnceLock::new();
+
+    let rescans = RESCANS.get_or_init(|| Mutex::new(HashMap::new()));
+    return rescans.lock().ok().map(|mut rescans| rescans.entry(folder_path.to_string()).or_default().clone());
+}
+
+/// Asks for the whole folder to be scanned again, when the watcher may have
+/// missed some of its events.
+pub fn request_rescan(configs: &RConfig) {
+    if let Some(rescan) = rescans(&configs.folder_path) {
+        rescan.notify_one();
+    }
+}
+
+/// Catches up with the changes made while the deamon was not running, then
+/// waits for the watcher to ask for a rescan. Live changes are the watcher's.
+pub fn init(configs: RConfig) {
+    init_sync(configs.clone());
+
+    tokio::spawn(async move {
+        let rescan = rescans(&configs.folder_path);
+
+        if rescan.is_none() {
+            error!("rescans not available for {}", configs.folder_path);
+            return;
+        }
+
+        let rescan = rescan.unwrap();
+
+        let database_url = configs.database.path.clone();
+        let mut conn = connection::establish(database_url.as_str()).unwrap();
+
+        loop {
+            rescan.notified().await;
+
+            tokio::task::block_in_place(|| {
+                let local_node = RNode::get_local(&mut conn);
+
+                if local_node.is_some() {
+                    info!(target: "START_SYNC", "folder rescan: {}", configs.folder_path);
+                    sync_changes(&mut conn, &configs, &local_node.unwrap());
                 } else {
-                    //warn!("{:?}", file.unwrap_err());
+                    error!("Not valid local node");
                 }
-            }
-        } else {
-            warn!("{:?}", entry.unwrap_err());
+            });
         }
-    }
-
-    return new_files;
+    });
 }
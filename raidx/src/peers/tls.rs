@@ -0,0 +1,134 @@
+use native_tls::{Certificate, Identity, TlsConnector};
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsAcceptor, TlsStream};
+
+use crate::models::nodes::RNode;
+use crate::utils::configs::RConfig;
+
+#[derive(Debug)]
+pub enum RTlsError {
+    Io(std::io::Error),
+    Tls(native_tls::Error),
+    Handshake(String),
+    MissingIdentity,
+    PinMismatch,
+}
+
+pub fn load_certificates(path: &String) -> Result<Vec<Certificate>, RTlsError> {
+    let pem = std::fs::read_to_string(path);
+
+    let pem = pem.map_err(RTlsError::Io)?;
+
+    let mut certificates = Vec::<Certificate>::new();
+    let end = "-----END CERTIFICATE-----";
+
+    for block in pem.split_inclusive(end) {
+        if !block.contains(end) {
+            continue;
+        }
+
+        match Certificate::from_pem(block.trim().as_bytes()) {
+            Ok(certificate) => certificates.push(certificate),
+            Err(e) => return Err(RTlsError::Tls(e)),
+        }
+    }
+
+    Ok(certificates)
+}
+
+/// Server side TLS configuration, built from the `server.certificate` and
+/// `server.key` paths (PEM, PKCS#8 key).
+pub fn acceptor(configs: &RConfig) -> Result<TlsAcceptor, RTlsError> {
+    let certificate = configs.server.certificate.clone();
+    let key = configs.server.key.clone();
+
+    if certificate.is_none() || key.is_none() {
+        return Err(RTlsError::MissingIdentity);
+    }
+
+    let certificate = std::fs::read(certificate.unwrap());
+    let key = std::fs::read(key.unwrap());
+
+    let certificate = certificate.map_err(RTlsError::Io)?;
+
+    let key = key.map_err(RTlsError::Io)?;
+
+    let identity = Identity::from_pkcs8(certificate.as_slice(), key.as_slice());
+
+    match identity {
+        Ok(identity) => native_tls::TlsAcceptor::new(identity).map(TlsAcceptor::from).map_err(RTlsError::Tls),
+        Err(e) => Err(RTlsError::Tls(e)),
+    }
+}
+
+/// Client side TLS configuration for a peer.
+///
+/// When the peer has a pinned `certificate` only that certificate is
+/// trusted and the host name check is skipped, the pin is verified after the
+/// handshake. Otherwise the system roots plus `tls.ca_bundle` are used.
+pub fn connector(configs: &RConfig, node: &RNode) -> Result<(TlsConnector, Option<Certificate>), RTlsError> {
+    let mut builder = TlsConnector::builder();
+    let mut pinned: Option<Certificate> = None;
+
+    let node_configs = configs.get_node(&node.host, node.port);
+
+    if let Some(path) = node_configs.and_then(|node_configs| node_configs.certificate.clone()) {
+        let certificate = load_certificates(&path)?.into_iter().next();
+
+        if certificate.is_none() {
+            return Err(RTlsError::Handshake(format!("no certificate in {}", path)));
+        }
+
+        let certificate = certificate.unwrap();
+
+        builder.disable_built_in_roots(true);
+        builder.danger_accept_invalid_hostnames(true);
+        builder.add_root_certificate(certificate.clone());
+
+        pinned = Some(certificate);
+    } else if let Some(path) = configs.tls.ca_bundle.clone() {
+        for certificate in load_certificates(&path)? {
+            builder.add_root_certificate(certificate);
+        }
+    }
+
+    match builder.build() {
+        Ok(connector) => Ok((connector, pinned)),
+        Err(e) => Err(RTlsError::Tls(e)),
+    }
+}
+
+/// Opens a TLS connection to the node.
+pub async fn connect(configs: &RConfig, node: &RNode) -> Result<TlsStream<TcpStream>, RTlsError> {
+    let (connector, pinned) = connector(configs, node)?;
+
+    let stream = TcpStream::connect(format!("{}:{}", node.host, node.port)).await;
+
+    let stream = stream.map_err(RTlsError::Io)?;
+
+    let connector = tokio_native_tls::TlsConnector::from(connector);
+
+    let stream = match connector.connect(node.host.as_str(), stream).await {
+        Ok(stream) => stream,
+        Err(e) => return Err(RTlsError::Handshake(format!("{}", e))),
+    };
+
+    if let Some(pinned) = pinned {
+        let expected = pinned.to_der().map_err(RTlsError::Tls)?;
+        let received = stream.get_ref().peer_certificate().map_err(RTlsError::Tls)?;
+
+        if received.is_none() || received.unwrap().to_der().map_err(RTlsError::Tls)? != expected {
+            return Err(RTlsError::PinMismatch);
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Runs the server side TLS handshake.
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<TlsStream<TcpStream>, RTlsError> {
+    return match acceptor.accept(stream).await {
+        Ok(stream) => Ok(stream),
+        Err(e) => Err(RTlsError::Handshake(format!("{}", e))),
+    };
+}
@@ -0,0 +1,221 @@
+use std::pin::Pin;
+
+use futures::future::BoxFuture;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use crate::models::nodes::RNode;
+use crate::peers::tls::RTlsError;
+use crate::peers::transports::memory::RMemoryTransport;
+#[cfg(feature = "quic")]
+use crate::peers::transports::quic::QUIC_TRANSPORT;
+use crate::peers::transports::tcp::RTcpTransport;
+use crate::peers::transports::websocket::RWebSocketTransport;
+use crate::utils::configs::RConfig;
+
+/// What travels on a peer connection, whatever carries it.
+///
+/// Pings are answered with a pong by whoever reads the connection, except on
+/// websockets which answer them themselves and don't pass them on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RPeerFrame {
+    Data(Vec<u8>),
+    /// Data carrying file contents. Transports able to send it apart from
+    /// the other frames do, and it can then be overtaken by frames sent
+    /// after it. Sending it completes once the peer has all of it. Always
+    /// received as `Data`.
+    Transfer(Vec<u8>),
+    Ping,
+    Pong,
+    Close,
+}
+
+impl RPeerFrame {
+    /// Bytes of data carried.
+    pub fn size(&self) -> usize {
+        match self {
+            RPeerFrame::Data(data) | RPeerFrame::Transfer(data) => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RTransportError {
+    Io(std::io::Error),
+    Tls(RTlsError),
+    WebSocket(Box<WsError>),
+    Protocol(String),
+    Oversized(String),
+    Quic(String),
+    Unsupported(&'static str),
+    Closed,
+}
+
+impl std::fmt::Display for RTransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RTransportError::Io(e) => write!(f, "io: {}", e),
+            RTransportError::Tls(e) => write!(f, "tls: {:?}", e),
+            RTransportError::WebSocket(e) => write!(f, "websocket: {}", e),
+            RTransportError::Protocol(text) => write!(f, "not valid frame: {}", text),
+            RTransportError::Oversized(text) => write!(f, "frame too large: {}", text),
+            RTransportError::Quic(text) => write!(f, "quic: {}", text),
+            RTransportError::Unsupported(transport) => write!(f, "built without {} support", transport),
+            RTransportError::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl From<std::io::Error> for RTransportError {
+    fn from(e: std::io::Error) -> Self {
+        RTransportError::Io(e)
+    }
+}
+
+impl From<RTlsError> for RTransportError {
+    fn from(e: RTlsError) -> Self {
+        RTransportError::Tls(e)
+    }
+}
+
+impl From<WsError> for RTransportError {
+    fn from(e: WsError) -> Self {
+        match e {
+            WsError::Capacity(e) => RTransportError::Oversized(format!("{}", e)),
+            WsError::ConnectionClosed | WsError::AlreadyClosed => RTransportError::Closed,
+            e => RTransportError::WebSocket(Box::new(e)),
+        }
+    }
+}
+
+pub type RFrameSender = Pin<Box<dyn Sink<RPeerFrame, Error = RTransportError> + Send>>;
+pub type RFrameReceiver = Pin<Box<dyn Stream<Item = Result<RPeerFrame, RTransportError>> + Send>>;
+
+/// An open connection to a peer, in either direction.
+pub struct RConnection {
+    pub sender: RFrameSender,
+    pub receiver: RFrameReceiver,
+}
+
+impl RConnection {
+    pub async fn send(&mut self, frame: RPeerFrame) -> Result<(), RTransportError> {
+        self.sender.send(frame).await
+    }
+
+    /// Next frame, `None` once the peer is gone.
+    pub async fn recv(&mut self) -> Option<Result<RPeerFrame, RTransportError>> {
+        self.receiver.next().await
+    }
+
+    pub async fn close(&mut self) {
+        let _ = self.sender.send(RPeerFrame::Close).await;
+        let _ = self.sender.close().await;
+    }
+
+    /// Sending and receiving halves, to be driven by separate tasks.
+    pub fn split(self) -> (RFrameSender, RFrameReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// A peer connecting to us. Its handshake runs in `open`, on the task
+/// serving the peer, so a slow one doesn't hold back the others.
+pub struct RIncoming {
+    pub peer_ip: String,
+    pub open: BoxFuture<'static, Result<RConnection, RTransportError>>,
+}
+
+pub trait RListener: Send {
+    /// Waits for the next peer. `Closed` once no more can come.
+    fn accept(&mut self) -> BoxFuture<'_, Result<RIncoming, RTransportError>>;
+}
+
+/// A way for nodes to reach each other.
+pub trait RTransport: Send + Sync {
+    /// Opens a connection to `node`, over TLS when it has the `ssl` flag set.
+    fn connect<'a>(&'a self, configs: &'a RConfig, node: &'a RNode) -> BoxFuture<'a, Result<RConnection, RTransportError>>;
+
+    /// Starts accepting connections on the `server` address.
+    fn listen<'a>(&'a self, configs: &'a RConfig) -> BoxFuture<'a, Result<Box<dyn RListener>, RTransportError>>;
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RTransportKind {
+    #[default]
+    #[serde(rename = "websocket")]
+    WebSocket,
+    /// Length-prefixed frames straight over TCP.
+    Tcp,
+    /// Channels within this process, for running several nodes in one
+    /// process without sockets. Each node needs a `server.host` of its own.
+    Memory,
+    /// QUIC over UDP, with a stream per file transfer. Built with the `quic`
+    /// feature. Peers are only reached with a pinned `certificate` or a
+    /// `tls.ca_bundle` to check theirs with.
+    Quic,
+}
+
+impl RTransportKind {
+    pub fn transport(&self) -> &'static dyn RTransport {
+        match self {
+            RTransportKind::WebSocket => &RWebSocketTransport,
+            RTransportKind::Tcp => &RTcpTransport,
+            RTransportKind::Memory => &RMemoryTransport,
+            #[cfg(feature = "quic")]
+            RTransportKind::Quic => &QUIC_TRANSPORT,
+            #[cfg(not(feature = "quic"))]
+            RTransportKind::Quic => &RMissingTransport("quic"),
+        }
+    }
+
+    pub fn scheme(&self, ssl: bool) -> &'static str {
+        match (self, ssl) {
+            (RTransportKind::WebSocket, false) => "ws",
+            (RTransportKind::WebSocket, true) => "wss",
+            (RTransportKind::Tcp, false) => "tcp",
+            (RTransportKind::Tcp, true) => "tls",
+            (RTransportKind::Memory, _) => "memory",
+            (RTransportKind::Quic, _) => "quic",
+        }
+    }
+}
+
+/// Stands for a transport left out of this build.
+#[cfg(not(feature = "quic"))]
+struct RMissingTransport(&'static str);
+
+#[cfg(not(feature = "quic"))]
+impl RTransport for RMissingTransport {
+    fn connect<'a>(&'a self, _configs: &'a RConfig, _node: &'a RNode) -> BoxFuture<'a, Result<RConnection, RTransportError>> {
+        Box::pin(futures::future::ready(Err(RTransportError::Unsupported(self.0))))
+    }
+
+    fn listen<'a>(&'a self, _configs: &'a RConfig) -> BoxFuture<'a, Result<Box<dyn RListener>, RTransportError>> {
+        Box::pin(futures::future::ready(Err(RTransportError::Unsupported(self.0))))
+    }
+}
+
+/// Transport `node` listens on: the one set for it in the configs, ours
+/// otherwise.
+pub fn for_node(configs: &RConfig, node: &RNode) -> RTransportKind {
+    match configs.get_node(&node.host, node.port) {
+        Some(node_configs) => node_configs.transport,
+        None => configs.server.transport,
+    }
+}
+
+/// Where `node` is reached, for the logs.
+pub fn url(configs: &RConfig, node: &RNode) -> String {
+    format!("{}://{}:{}", for_node(configs, node).scheme(node.ssl), node.host, node.port)
+}
+
+pub async fn connect(configs: &RConfig, node: &RNode) -> Result<RConnection, RTransportError> {
+    return for_node(configs, node).transport().connect(configs, node).await;
+}
+
+pub async fn listen(configs: &RConfig) -> Result<Box<dyn RListener>, RTransportError> {
+    return configs.server.transport.transport().listen(configs).await;
+}
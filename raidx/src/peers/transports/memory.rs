@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::{FutureExt, SinkExt, StreamExt};
+
+use crate::models::nodes::RNode;
+use crate::peers::transport::{RConnection, RIncoming, RListener, RPeerFrame, RTransport, RTransportError};
+use crate::utils::configs::RConfig;
+
+/// Frames in flight in each direction of a connection.
+const CHANNEL_SIZE: usize = 16;
+
+/// Connections waiting to be accepted, per listening address.
+const BACKLOG_SIZE: usize = 16;
+
+fn listeners() -> &'static Mutex<HashMap<String, mpsc::Sender<RIncoming>>> {
+    static LISTENERS: OnceLock<Mutex<HashMap<String, mpsc::Sender<RIncoming>>>> = OnceLock::new();
+    LISTENERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Channels between nodes running in the same process, found by the host
+/// and port of their `server` section.
+///
+/// There is no network address to tell the nodes apart, so the `server.host`
+/// of each stands for one: any name, different for every node, that the
+/// others have in their `nodes`.
+pub struct RMemoryTransport;
+
+struct RMemoryListener {
+    address: String,
+    incoming: mpsc::Receiver<RIncoming>,
+}
+
+impl RListener for RMemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, Result<RIncoming, RTransportError>> {
+        async move {
+            self.incoming.next().await.ok_or(RTransportError::Closed)
+        }
+        .boxed()
+    }
+}
+
+impl Drop for RMemoryListener {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = listeners().lock() {
+            listeners.remove(&self.address);
+        }
+    }
+}
+
+impl RTransport for RMemoryTransport {
+    fn connect<'a>(&'a self, configs: &'a RConfig, node: &'a RNode) -> BoxFuture<'a, Result<RConnection, RTransportError>> {
+        async move {
+            let address = format!("{}:{}", node.host, node.port);
+            let listener = listeners().lock().ok().and_then(|listeners| listeners.get(&address).cloned());
+
+            if listener.is_none() {
+                return Err(RTransportError::Io(std::io::ErrorKind::ConnectionRefused.into()));
+            }
+
+            let (ours, theirs) = pair();
+
+            // Known by the host it listens on, its own to it.
+            let incoming = RIncoming {
+                peer_ip: configs.server.host.clone(),
+                open: futures::future::ready(Ok(theirs)).boxed(),
+            };
+
+            if listener.unwrap().send(incoming).await.is_err() {
+                return Err(RTransportError::Io(std::io::ErrorKind::ConnectionRefused.into()));
+            }
+
+            Ok(ours)
+        }
+        .boxed()
+    }
+
+    fn listen<'a>(&'a self, configs: &'a RConfig) -> BoxFuture<'a, Result<Box<dyn RListener>, RTransportError>> {
+        async move {
+            let address = format!("{}:{}", configs.server.host, configs.server.port);
+            let (sender, incoming) = mpsc::channel(BACKLOG_SIZE);
+
+            let mut listeners = listeners().lock().map_err(|_| RTransportError::Closed)?;
+
+            listeners.retain(|_, listener| !listener.is_closed());
+
+            if listeners.contains_key(&address) {
+                return Err(RTransportError::Io(std::io::ErrorKind::AddrInUse.into()));
+            }
+
+            // Peers tell us apart from the others by host only.
+            let host = format!("{}:", configs.server.host);
+
+            if listeners.keys().any(|other| other.starts_with(host.as_str())) {
+                return Err(RTransportError::Io(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("host {} already taken by another node", configs.server.host),
+                )));
+            }
+
+            listeners.insert(address.clone(), sender);
+
+            Ok(Box::new(RMemoryListener { address, incoming }) as Box<dyn RListener>)
+        }
+        .boxed()
+    }
+}
+
+/// Both ends of a connection.
+fn pair() -> (RConnection, RConnection) {
+    let (left_sender, right_receiver) = mpsc::channel::<RPeerFrame>(CHANNEL_SIZE);
+    let (right_sender, left_receiver) = mpsc::channel::<RPeerFrame>(CHANNEL_SIZE);
+
+    let left = RConnection {
+        sender: Box::pin(left_sender.sink_map_err(|_| RTransportError::Closed)),
+        receiver: Box::pin(left_receiver.map(received)),
+    };
+
+    let right = RConnection {
+        sender: Box::pin(right_sender.sink_map_err(|_| RTransportError::Closed)),
+        receiver: Box::pin(right_receiver.map(received)),
+    };
+
+    (left, right)
+}
+
+fn received(frame: RPeerFrame) -> Result<RPeerFrame, RTransportError> {
+    match frame {
+        RPeerFrame::Transfer(data) => Ok(RPeerFrame::Data(data)),
+        frame => Ok(frame),
+    }
+}
@@ -0,0 +1,364 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::warn;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream, TransportConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::sync::mpsc;
+
+use crate::models::nodes::RNode;
+use crate::peers::auth::HANDSHAKE_TIMEOUT;
+use crate::peers::tls::RTlsError;
+use crate::peers::transport::{RConnection, RIncoming, RListener, RPeerFrame, RTransport, RTransportError};
+use crate::peers::transports::tcp;
+use crate::utils::configs::RConfig;
+
+/// Application protocol agreed on during the TLS handshake.
+const ALPN: &[u8] = b"raidx";
+
+/// Transfers a peer can send us at once, each on its own stream.
+const MAX_TRANSFER_STREAMS: u32 = 16;
+
+/// Transfers read and not handed to the receiver yet.
+const RECEIVE_QUEUE_SIZE: usize = 16;
+
+fn quic_error<E: std::fmt::Display>(e: E) -> RTransportError {
+    RTransportError::Quic(format!("{}", e))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn read_certificates(path: &String) -> Result<Vec<CertificateDer<'static>>, RTransportError> {
+    let pem = std::fs::read(path)?;
+    let certificates = rustls_pemfile::certs(&mut pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+
+    Ok(certificates)
+}
+
+/// Trusts only the certificate pinned for the peer.
+#[derive(Debug)]
+struct RPeerVerifier {
+    pinned: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for RPeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pinned.as_ref() != end_entity.as_ref() {
+            return Err(rustls::Error::General("certificate does not match the pinned one".to_string()));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// The connection ends once the peer stays silent for `ping_timeout`, like
+/// the other transports.
+fn transport_config(configs: &RConfig) -> TransportConfig {
+    let mut transport = TransportConfig::default();
+
+    transport.max_concurrent_uni_streams(MAX_TRANSFER_STREAMS.into());
+    transport.max_idle_timeout(Duration::from_secs(configs.connections.ping_timeout).try_into().ok());
+
+    transport
+}
+
+/// The `server.certificate` and `server.key` pair (PEM), or a self-signed
+/// certificate made at start when there is none.
+fn server_identity(configs: &RConfig) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), RTransportError> {
+    if let (Some(certificate), Some(key)) = (configs.server.certificate.as_ref(), configs.server.key.as_ref()) {
+        let certificates = read_certificates(certificate)?;
+        let pem = std::fs::read(key)?;
+
+        return match rustls_pemfile::private_key(&mut pem.as_slice())? {
+            Some(key) => Ok((certificates, key)),
+            None => Err(RTransportError::Tls(RTlsError::MissingIdentity)),
+        };
+    }
+
+    warn!(target: "SERVER", "no server.certificate, peers can't check the self-signed one made for quic");
+
+    let generated = rcgen::generate_simple_self_signed(vec![configs.server.host.clone()]).map_err(quic_error)?;
+    let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+
+    Ok((vec![generated.cert.der().clone()], key.into()))
+}
+
+fn server_config(configs: &RConfig) -> Result<quinn::ServerConfig, RTransportError> {
+    let (certificates, key) = server_identity(configs)?;
+
+    let mut crypto = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(quic_error)?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(quic_error)?;
+
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut server = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto).map_err(quic_error)?));
+    server.transport_config(Arc::new(transport_config(configs)));
+
+    Ok(server)
+}
+
+/// Client side TLS configuration for a peer, checked against its pinned
+/// `certificate`, else against `tls.ca_bundle`. QUIC is always encrypted, so
+/// a peer with neither is refused rather than trusted blindly.
+fn client_config(configs: &RConfig, node: &RNode) -> Result<quinn::ClientConfig, RTransportError> {
+    let builder = rustls::ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(quic_error)?;
+
+    let pinned = configs.get_node(&node.host, node.port).and_then(|node_configs| node_configs.certificate.clone());
+
+    let mut crypto = if let Some(path) = pinned {
+        let certificate = read_certificates(&path)?.into_iter().next();
+
+        if certificate.is_none() {
+            return Err(RTransportError::Tls(RTlsError::Handshake(format!("no certificate in {}", path))));
+        }
+
+        let verifier = RPeerVerifier { pinned: certificate.unwrap(), provider: provider() };
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+    } else if let Some(path) = configs.tls.ca_bundle.as_ref() {
+        let mut roots = RootCertStore::empty();
+
+        for certificate in read_certificates(path)? {
+            roots.add(certificate).map_err(quic_error)?;
+        }
+
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        warn!("no pinned certificate nor tls.ca_bundle to check {}:{} with, not connecting over quic", node.host, node.port);
+        return Err(RTransportError::Tls(RTlsError::Handshake(format!("no way to check the certificate of {}", node.host))));
+    };
+
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut client = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).map_err(quic_error)?));
+    client.transport_config(Arc::new(transport_config(configs)));
+
+    Ok(client)
+}
+
+async fn resolve(host: &String, port: usize) -> Result<SocketAddr, RTransportError> {
+    let address = tokio::net::lookup_host(format!("{}:{}", host, port)).await?.next();
+
+    if address.is_none() {
+        return Err(RTransportError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no address for {}", host))));
+    }
+
+    Ok(address.unwrap())
+}
+
+/// QUIC connections, always encrypted. Control frames share one stream,
+/// opened by the server with its challenge, and each transfer gets a stream
+/// of its own so a large file doesn't hold back the frames the peer sends
+/// us. Sending a transfer completes once the peer has read all of it.
+pub struct RQuicTransport {
+    /// Client endpoints shared by the connections made, per address family.
+    ipv4: Mutex<Option<Endpoint>>,
+    ipv6: Mutex<Option<Endpoint>>,
+}
+
+pub static QUIC_TRANSPORT: RQuicTransport = RQuicTransport {
+    ipv4: Mutex::new(None),
+    ipv6: Mutex::new(None),
+};
+
+impl RQuicTransport {
+    /// Client endpoint for reaching `address`, bound on first use.
+    fn client(&self, address: &SocketAddr) -> Result<Endpoint, RTransportError> {
+        let (endpoint, local): (&Mutex<Option<Endpoint>>, SocketAddr) = if address.is_ipv4() {
+            (&self.ipv4, ([0, 0, 0, 0], 0).into())
+        } else {
+            (&self.ipv6, ([0u16; 8], 0).into())
+        };
+
+        let mut endpoint = endpoint.lock().map_err(|_| RTransportError::Closed)?;
+
+        if endpoint.is_none() {
+            *endpoint = Some(Endpoint::client(local)?);
+        }
+
+        Ok(endpoint.clone().unwrap())
+    }
+}
+
+struct RQuicListener {
+    endpoint: Endpoint,
+    max_frame_size: usize,
+}
+
+impl RListener for RQuicListener {
+    fn accept(&mut self) -> BoxFuture<'_, Result<RIncoming, RTransportError>> {
+        async move {
+            let incoming = self.endpoint.accept().await.ok_or(RTransportError::Closed)?;
+            let peer_ip = incoming.remote_address().ip().to_string();
+            let max_frame_size = self.max_frame_size;
+
+            let open = async move {
+                let connection = incoming.accept().map_err(quic_error)?.await.map_err(quic_error)?;
+                let (control, receiver) = connection.open_bi().await.map_err(quic_error)?;
+
+                Ok(wrap(connection, control, receiver, max_frame_size))
+            };
+
+            Ok(RIncoming { peer_ip, open: open.boxed() })
+        }
+        .boxed()
+    }
+}
+
+impl RTransport for RQuicTransport {
+    fn connect<'a>(&'a self, configs: &'a RConfig, node: &'a RNode) -> BoxFuture<'a, Result<RConnection, RTransportError>> {
+        async move {
+            let address = resolve(&node.host, node.port as usize).await?;
+
+            let endpoint = self.client(&address)?;
+            let connecting = endpoint.connect_with(client_config(configs, node)?, address, node.host.as_str());
+            let connection = connecting.map_err(quic_error)?.await.map_err(quic_error)?;
+
+            // Nothing is on the control stream before the challenge.
+            let control = tokio::time::timeout(HANDSHAKE_TIMEOUT, connection.accept_bi()).await;
+
+            match control {
+                Ok(Ok((control, receiver))) => Ok(wrap(connection, control, receiver, configs.limits.max_frame_size)),
+                Ok(Err(e)) => Err(quic_error(e)),
+                Err(_) => Err(RTransportError::Quic(format!("no control stream after {}s", HANDSHAKE_TIMEOUT.as_secs()))),
+            }
+        }
+        .boxed()
+    }
+
+    fn listen<'a>(&'a self, configs: &'a RConfig) -> BoxFuture<'a, Result<Box<dyn RListener>, RTransportError>> {
+        async move {
+            let address = resolve(&configs.server.host, configs.server.port).await?;
+
+            let listener = RQuicListener {
+                endpoint: Endpoint::server(server_config(configs)?, address)?,
+                max_frame_size: configs.limits.max_frame_size,
+            };
+
+            Ok(Box::new(listener) as Box<dyn RListener>)
+        }
+        .boxed()
+    }
+}
+
+fn wrap(connection: Connection, control: SendStream, receiver: RecvStream, max_frame_size: usize) -> RConnection {
+    let (transfers_sender, transfers) = mpsc::channel(RECEIVE_QUEUE_SIZE);
+    tokio::spawn(receive_transfers(connection.clone(), transfers_sender, max_frame_size));
+
+    // The stream is dropped after an error, the frames end with it.
+    let frames = futures::stream::unfold(Some(receiver), move |receiver| async move {
+        let mut receiver = receiver?;
+
+        return match tcp::read_frame(&mut receiver, max_frame_size).await {
+            Ok(Some(frame)) => Some((Ok(frame), Some(receiver))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        };
+    });
+
+    let transfers = futures::stream::unfold(transfers, |mut transfers| async move {
+        return transfers.recv().await.map(|frame| (frame, transfers));
+    });
+
+    let sender = futures::sink::unfold((connection, control), |(connection, mut control), frame: RPeerFrame| async move {
+        if let RPeerFrame::Transfer(data) = frame {
+            // Waits while the peer already gets as many transfers as it
+            // accepts at once.
+            let stream = connection.open_uni().await.map_err(quic_error)?;
+            send_transfer(stream, data).await?;
+        } else {
+            tcp::write_frame(&mut control, &frame).await?;
+        }
+
+        Ok::<_, RTransportError>((connection, control))
+    });
+
+    RConnection {
+        sender: Box::pin(sender),
+        receiver: Box::pin(futures::stream::select(frames, transfers)),
+    }
+}
+
+/// Writes a transfer on its own stream and waits until the peer has read
+/// all of it, so it is only taken as sent once delivered.
+async fn send_transfer(mut stream: SendStream, data: Vec<u8>) -> Result<(), RTransportError> {
+    tcp::write_frame(&mut stream, &RPeerFrame::Data(data)).await?;
+    stream.finish().map_err(quic_error)?;
+
+    match stream.stopped().await.map_err(quic_error)? {
+        None => Ok(()),
+        Some(code) => Err(RTransportError::Quic(format!("transfer stopped by the peer: {}", code))),
+    }
+}
+
+/// Reads the transfers sent by the peer, one frame per stream, until the
+/// connection or the receiver is gone.
+async fn receive_transfers(connection: Connection, frames: mpsc::Sender<Result<RPeerFrame, RTransportError>>, max_frame_size: usize) {
+    loop {
+        let stream = tokio::select! {
+            stream = connection.accept_uni() => stream,
+            _ = frames.closed() => return,
+        };
+
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+
+        let frames = frames.clone();
+
+        tokio::spawn(async move {
+            let frame = match tcp::read_frame(&mut stream, max_frame_size).await {
+                Ok(Some(RPeerFrame::Data(data))) => Ok(RPeerFrame::Data(data)),
+                Ok(_) => Err(RTransportError::Protocol("transfer stream without data".to_string())),
+                Err(e) => Err(e),
+            };
+
+            let _ = frames.send(frame).await;
+        });
+    }
+}
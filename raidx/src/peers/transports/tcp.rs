@@ -0,0 +1,192 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_native_tls::TlsAcceptor;
+use tokio_tungstenite::MaybeTlsStream;
+
+use crate::models::nodes::RNode;
+use crate::peers::tls;
+use crate::peers::transport::{RConnection, RIncoming, RListener, RPeerFrame, RTransport, RTransportError};
+use crate::utils::configs::RConfig;
+
+const FRAME_DATA: u8 = 0;
+const FRAME_PING: u8 = 1;
+const FRAME_PONG: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+
+/// TCP listener on the `server` address, over TLS when `server.ssl` is set.
+/// Shared by the transports running on sockets.
+pub struct RSocketListener {
+    listener: TcpListener,
+    acceptor: Option<Arc<TlsAcceptor>>,
+}
+
+/// A socket accepted by [`RSocketListener`], once its TLS handshake is done.
+pub type RPendingSocket = BoxFuture<'static, Result<MaybeTlsStream<TcpStream>, RTransportError>>;
+
+impl RSocketListener {
+    pub async fn bind(configs: &RConfig) -> Result<RSocketListener, RTransportError> {
+        let acceptor = if configs.server.ssl {
+            Some(Arc::new(tls::acceptor(configs)?))
+        } else {
+            None
+        };
+
+        let listener = TcpListener::bind(format!("{}:{}", configs.server.host, configs.server.port)).await?;
+
+        Ok(RSocketListener { listener, acceptor })
+    }
+
+    /// Waits for the next peer, returns its address and its TLS handshake.
+    pub async fn accept(&self) -> Result<(String, RPendingSocket), RTransportError> {
+        let (stream, peer_addr) = self.listener.accept().await?;
+        let acceptor = self.acceptor.clone();
+
+        let pending = async move {
+            match acceptor {
+                Some(acceptor) => Ok(MaybeTlsStream::NativeTls(tls::accept(&acceptor, stream).await?)),
+                None => Ok(MaybeTlsStream::Plain(stream)),
+            }
+        };
+
+        Ok((peer_addr.ip().to_string(), pending.boxed()))
+    }
+}
+
+/// Opens a socket to `node`, over TLS when it has the `ssl` flag set.
+pub async fn dial(configs: &RConfig, node: &RNode) -> Result<MaybeTlsStream<TcpStream>, RTransportError> {
+    if node.ssl {
+        return Ok(MaybeTlsStream::NativeTls(tls::connect(configs, node).await?));
+    }
+
+    Ok(MaybeTlsStream::Plain(TcpStream::connect(format!("{}:{}", node.host, node.port)).await?))
+}
+
+/// Frames written as a 4 bytes big endian length, then a type byte and the
+/// payload, the length counting both.
+pub struct RTcpTransport;
+
+struct RTcpListener {
+    socket: RSocketListener,
+    max_frame_size: usize,
+}
+
+impl RListener for RTcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, Result<RIncoming, RTransportError>> {
+        async move {
+            let (peer_ip, pending) = self.socket.accept().await?;
+            let max_frame_size = self.max_frame_size;
+
+            let open = async move {
+                return Ok(wrap(pending.await?, max_frame_size));
+            };
+
+            Ok(RIncoming { peer_ip, open: open.boxed() })
+        }
+        .boxed()
+    }
+}
+
+impl RTransport for RTcpTransport {
+    fn connect<'a>(&'a self, configs: &'a RConfig, node: &'a RNode) -> BoxFuture<'a, Result<RConnection, RTransportError>> {
+        async move {
+            return Ok(wrap(dial(configs, node).await?, configs.limits.max_frame_size));
+        }
+        .boxed()
+    }
+
+    fn listen<'a>(&'a self, configs: &'a RConfig) -> BoxFuture<'a, Result<Box<dyn RListener>, RTransportError>> {
+        async move {
+            let listener = RTcpListener {
+                socket: RSocketListener::bind(configs).await?,
+                max_frame_size: configs.limits.max_frame_size,
+            };
+
+            Ok(Box::new(listener) as Box<dyn RListener>)
+        }
+        .boxed()
+    }
+}
+
+fn wrap<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, max_frame_size: usize) -> RConnection {
+    let (reader, writer) = tokio::io::split(stream);
+
+    // The reader is dropped after an error, the stream ends with it.
+    let receiver = futures::stream::unfold(Some(reader), move |reader| async move {
+        let mut reader = reader?;
+
+        return match read_frame(&mut reader, max_frame_size).await {
+            Ok(Some(frame)) => Some((Ok(frame), Some(reader))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        };
+    });
+
+    let sender = futures::sink::unfold(writer, |mut writer, frame: RPeerFrame| async move {
+        write_frame(&mut writer, &frame).await?;
+        Ok::<_, RTransportError>(writer)
+    });
+
+    RConnection {
+        sender: Box::pin(sender),
+        receiver: Box::pin(receiver),
+    }
+}
+
+/// Next frame, `None` when the peer closed the stream between two frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_frame_size: usize) -> Result<Option<RPeerFrame>, RTransportError> {
+    let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(RTransportError::Io(e)),
+    };
+
+    if length == 0 {
+        return Err(RTransportError::Protocol("empty frame".to_string()));
+    }
+
+    // Checked before reading anything into memory.
+    if length - 1 > max_frame_size {
+        return Err(RTransportError::Oversized(format!("{} bytes", length - 1)));
+    }
+
+    let kind = reader.read_u8().await?;
+    let mut payload = vec![0u8; length - 1];
+    reader.read_exact(payload.as_mut_slice()).await?;
+
+    match kind {
+        FRAME_DATA => Ok(Some(RPeerFrame::Data(payload))),
+        FRAME_PING => Ok(Some(RPeerFrame::Ping)),
+        FRAME_PONG => Ok(Some(RPeerFrame::Pong)),
+        FRAME_CLOSE => Ok(Some(RPeerFrame::Close)),
+        kind => Err(RTransportError::Protocol(format!("unknown frame type {}", kind))),
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &RPeerFrame) -> Result<(), RTransportError> {
+    let (kind, payload) = match frame {
+        RPeerFrame::Data(data) | RPeerFrame::Transfer(data) => (FRAME_DATA, data.as_slice()),
+        RPeerFrame::Ping => (FRAME_PING, &[][..]),
+        RPeerFrame::Pong => (FRAME_PONG, &[][..]),
+        RPeerFrame::Close => (FRAME_CLOSE, &[][..]),
+    };
+
+    if payload.len() >= u32::MAX as usize {
+        return Err(RTransportError::Oversized(format!("{} bytes", payload.len())));
+    }
+
+    writer.write_u32(payload.len() as u32 + 1).await?;
+    writer.write_u8(kind).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+
+    // Nothing is sent after it.
+    if kind == FRAME_CLOSE {
+        writer.shutdown().await?;
+    }
+
+    Ok(())
+}
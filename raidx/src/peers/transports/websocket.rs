@@ -0,0 +1,136 @@
+use futures::future::{self, BoxFuture};
+use futures::{FutureExt, SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::models::nodes::RNode;
+use crate::peers::transport::{RConnection, RIncoming, RListener, RPeerFrame, RTransport, RTransportError};
+use crate::peers::transports::tcp::{self, RSocketListener};
+use crate::utils::configs::RConfig;
+
+/// Websocket subprotocol spoken between nodes.
+pub const WS_PROTOCOL: &str = "rust-websocket";
+
+pub type RWebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Frame and message size limits of the websocket connections.
+pub fn ws_config(configs: &RConfig) -> WebSocketConfig {
+    WebSocketConfig {
+        max_frame_size: Some(configs.limits.max_frame_size),
+        max_message_size: Some(configs.limits.max_frame_size),
+        ..Default::default()
+    }
+}
+
+/// Accepts only the websocket requests asking for our subprotocol.
+// The signature is the one the websocket handshake expects.
+#[allow(clippy::result_large_err)]
+fn check_protocol(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    let protocols = request
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocols| protocols.to_str().ok())
+        .unwrap_or("");
+
+    if !protocols.split(',').any(|protocol| protocol.trim() == WS_PROTOCOL) {
+        let mut error = ErrorResponse::new(None);
+        *error.status_mut() = StatusCode::BAD_REQUEST;
+        return Err(error);
+    }
+
+    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(WS_PROTOCOL));
+
+    Ok(response)
+}
+
+/// Frames carried as websocket messages, data in binary ones.
+pub struct RWebSocketTransport;
+
+struct RWebSocketListener {
+    socket: RSocketListener,
+    configs: RConfig,
+}
+
+impl RListener for RWebSocketListener {
+    fn accept(&mut self) -> BoxFuture<'_, Result<RIncoming, RTransportError>> {
+        async move {
+            let (peer_ip, pending) = self.socket.accept().await?;
+            let config = ws_config(&self.configs);
+
+            let open = async move {
+                let client = tokio_tungstenite::accept_hdr_async_with_config(pending.await?, check_protocol, Some(config)).await?;
+                Ok(wrap(client))
+            };
+
+            Ok(RIncoming { peer_ip, open: open.boxed() })
+        }
+        .boxed()
+    }
+}
+
+impl RTransport for RWebSocketTransport {
+    fn connect<'a>(&'a self, configs: &'a RConfig, node: &'a RNode) -> BoxFuture<'a, Result<RConnection, RTransportError>> {
+        async move {
+            let stream = tcp::dial(configs, node).await?;
+
+            let mut request = node.connection_url().into_client_request()?;
+            request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(WS_PROTOCOL));
+
+            let (client, _) = tokio_tungstenite::client_async_with_config(request, stream, Some(ws_config(configs))).await?;
+
+            Ok(wrap(client))
+        }
+        .boxed()
+    }
+
+    fn listen<'a>(&'a self, configs: &'a RConfig) -> BoxFuture<'a, Result<Box<dyn RListener>, RTransportError>> {
+        async move {
+            let listener = RWebSocketListener {
+                socket: RSocketListener::bind(configs).await?,
+                configs: configs.clone(),
+            };
+
+            Ok(Box::new(listener) as Box<dyn RListener>)
+        }
+        .boxed()
+    }
+}
+
+fn wrap(client: RWebSocket) -> RConnection {
+    let (sink, stream) = client.split();
+
+    let sender = sink.sink_map_err(RTransportError::from).with(|frame: RPeerFrame| {
+        let message = match frame {
+            RPeerFrame::Data(data) | RPeerFrame::Transfer(data) => Message::Binary(data),
+            RPeerFrame::Ping => Message::Ping(Vec::new()),
+            RPeerFrame::Pong => Message::Pong(Vec::new()),
+            RPeerFrame::Close => Message::Close(None),
+        };
+
+        future::ready(Ok::<_, RTransportError>(message))
+    });
+
+    let receiver = stream.filter_map(|message| {
+        let frame = match message {
+            Ok(Message::Binary(data)) => Some(Ok(RPeerFrame::Data(data))),
+            Ok(Message::Pong(_)) => Some(Ok(RPeerFrame::Pong)),
+            Ok(Message::Close(_)) => Some(Ok(RPeerFrame::Close)),
+            // Pings are answered by the websocket itself.
+            Ok(_) => None,
+            Err(e) => Some(Err(RTransportError::from(e))),
+        };
+
+        future::ready(frame)
+    });
+
+    RConnection {
+        sender: Box::pin(sender),
+        receiver: Box::pin(receiver),
+    }
+}
@@ -1,130 +1,238 @@
-extern crate websocket;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use std::thread;
-
-use diesel::prelude::*;
 use diesel::SqliteConnection;
+use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
 
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::Path;
-
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
-use crate::models::files::{NewRFile, RFile};
+use crate::models::files::{NewRFile, RFile, FILE_STATUS_CORRUPTED};
 use crate::models::nodes::RNode;
-use crate::models::queues::messages::RMessageQueue;
-use crate::models::queues::messages_outgoing::RMessageOutgoing;
-use crate::protocol::message::RMessage;
-use crate::protocol::message::RMessageType;
+use crate::models::utils::connection;
+use crate::peers::synchronizer::{self, ROfflineChanges};
 use crate::utils::configs::RConfig;
 
-pub fn init(configs: RConfig) {
-    thread::spawn(move || {
-        info!("SIZE NEW FILE: {}", std::mem::size_of::<NewRFile>());
+/// Events waiting to be handled. Past it, notify's thread waits for the
+/// watcher to catch up.
+const EVENTS_SIZE: usize = 1024;
 
-        let raidx_path = configs.folder_path.clone();
-        let database_url = configs.database.path.clone();
+/// How long a file stays untouched before it is read: a file being copied
+/// raises an event for every write.
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
 
-        info!("start to watch folder: {}", raidx_path);
+pub fn init(configs: RConfig) {
+    tokio::spawn(async move {
+        info!("start to watch folder: {}", configs.folder_path);
 
-        if let Err(error) = watch(database_url, raidx_path) {
-            println!("{error:?}");
+        if let Err(error) = watch(configs).await {
+            error!("folder not watched: {:?}", error);
         }
     });
 }
 
-
-fn watch<P: AsRef<Path>>(database_url: String, path: P) -> notify::Result<()> {
-    let mut conn =
-        SqliteConnection::establish(database_url.as_str()).unwrap();
+async fn watch(configs: RConfig) -> notify::Result<()> {
+    let mut conn = connection::establish(configs.database.path.as_str()).unwrap();
     let local_node = RNode::get_local_or_create(&mut conn, "0.0.0.0".to_string(), 4000);
 
-    if local_node.is_some() {
-        let local_node = local_node.unwrap();
-        let (tx, rx) = std::sync::mpsc::channel();
-        let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
-    
-        watcher.watch(path.as_ref(), RecursiveMode::Recursive)?;
-    
-        for res in rx {
-            match res {
-                Ok(event) => match event.kind {
-                    notify::EventKind::Modify(notify::event::ModifyKind::Data(
-                        notify::event::DataChange::Any,
-                    )) => {
-                        info!("event{:?}", event);
-    
-                        let paths = event.paths;
-                        let _from: &str = paths[0].to_str().unwrap();
-    
-                    }
-                    notify::EventKind::Create(notify::event::CreateKind::File) => {
-                        let paths = event.paths;
-                        let file_path = paths.get(0).unwrap();
-                        let entry = std::path::Path::new(file_path.to_str().unwrap());
-    
-                        let file = NewRFile::from_entry(&mut conn, &local_node, &entry);
-    
-                        if file.is_ok() {
-                            let file = file.unwrap();
-    
-                            info!("DEAMON: new file was added {}", file.filename);
-                            let data = serde_json::to_vec(&file);
-
-                            if data.is_ok() {
-                                let message = RMessage{
-                                    _type: RMessageType::FileAdded,
-                                    data: Some(data.unwrap()) 
-                                };
-
-                                let nodes = RNode::get_others(&mut conn);
-                                if nodes.is_some() {
-                                    for node in nodes.unwrap() {
-                                        let message = RMessageOutgoing::push(&mut conn, node.uid, message.clone());
-                                        
-                                        info!("new message outgoing: {:?}", message);
-                                    }
-                                } else {
-                                    warn!("can't get nodes");
-                                }
-                            } else {
-                                warn!("error creating new file message");
-                            }
-                        } else {
-                            warn!("error adding new file to db");
-                        }
-                    },
-                    notify::EventKind::Remove(notify::event::RemoveKind::File) => {
-                        let paths = event.paths;
-                        let file_path = paths.get(0).unwrap();
-                        let entry = std::path::Path::new(file_path.to_str().unwrap());
-    
-                        let file = RFile::from_entry(&mut conn, entry);
-    
-                        if file.is_some() {
-                            let file = file.unwrap();
-    
-                            RFile::remove_from_uid(&mut conn, &file.uid);
-    
-                            info!("file removed: {}", entry.as_os_str().to_str().unwrap());
-                        } else {
-                            warn!("not file found: {}", entry.as_os_str().to_str().unwrap());
-                        }
-                    },
-                    notify::EventKind::Remove(notify::event::RemoveKind::Folder) => {
-                        println!("REMOVE FOLDER");
-                    }
-                    e => {
-    
-                        println!("OTHER {:?}", e);
-                    }
-                },
-                Err(error) => println!("Error: {error:?}"),
+    if local_node.is_none() {
+        error!("Not valid local node");
+        return Ok(());
+    }
+
+    let local_node = local_node.unwrap();
+    let (tx, mut rx) = mpsc::channel(EVENTS_SIZE);
+
+    // Called back on a thread of notify's own, free to block.
+    let mut watcher = RecommendedWatcher::new(
+        move |event| {
+            let _ = tx.blocking_send(event);
+        },
+        Config::default(),
+    )?;
+
+    watcher.watch(Path::new(&configs.folder_path), RecursiveMode::Recursive)?;
+
+    // Files created or written to, by when they were last touched.
+    let mut settling = HashMap::<PathBuf, Instant>::new();
+
+    loop {
+        let next = settling.values().min().map(|touched| *touched + SETTLE_DELAY);
+
+        let event = match next {
+            Some(next) => tokio::time::timeout_at(tokio::time::Instant::from_std(next), rx.recv()).await,
+            None => Ok(rx.recv().await),
+        };
+
+        match event {
+            Ok(Some(Ok(event))) => {
+                tokio::task::block_in_place(|| handle_event(&mut conn, &configs, &local_node, &mut settling, event));
+            }
+            Ok(Some(Err(error))) => {
+                error!("watch error: {:?}", error);
+                synchronizer::request_rescan(&configs);
             }
+            Ok(None) => return Ok(()),
+            Err(_) => (),
         }
+
+        let now = Instant::now();
+        let settled: Vec<PathBuf> = settling
+            .iter()
+            .filter(|(_, touched)| now >= **touched + SETTLE_DELAY)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in settled {
+            settling.remove(&path);
+            tokio::task::block_in_place(|| file_changed(&mut conn, &configs, &local_node, &path));
+        }
+    }
+}
+
+fn handle_event(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    local_node: &RNode,
+    settling: &mut HashMap<PathBuf, Instant>,
+    event: Event,
+) {
+    if event.need_rescan() {
+        warn!("watcher missed events, folder rescan asked: {}", configs.folder_path);
+        synchronizer::request_rescan(configs);
+    }
+
+    match event.kind {
+        EventKind::Create(CreateKind::File)
+        | EventKind::Modify(ModifyKind::Data(_))
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            for path in event.paths {
+                settling.insert(path, Instant::now());
+            }
+        }
+        EventKind::Remove(RemoveKind::File) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            for path in event.paths {
+                settling.remove(&path);
+                file_removed(conn, configs, local_node, &path);
+            }
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            settling.remove(&event.paths[0]);
+            file_removed(conn, configs, local_node, &event.paths[0]);
+            settling.insert(event.paths[1].clone(), Instant::now());
+        }
+        EventKind::Create(CreateKind::Folder) => {
+            // Files written before the folder was watched raise no event.
+            synchronizer::request_rescan(configs);
+        }
+        EventKind::Remove(RemoveKind::Folder) => {
+            debug!("folder removed: {:?}", event.paths);
+        }
+        e => {
+            debug!("event ignored: {:?}", e);
+        }
+    }
+}
+
+/// Adds a file created in the folder, or records the new version of a
+/// local file, once it settled.
+fn file_changed(conn: &mut SqliteConnection, configs: &RConfig, local_node: &RNode, entry: &Path) {
+    if entry.is_dir() {
+        // Moved in with its files, which raise no event of their own.
+        synchronizer::request_rescan(configs);
+        return;
+    }
+
+    if !entry.is_file() {
+        return;
+    }
+
+    let mut changes = ROfflineChanges::default();
+    let file = RFile::from_entry(conn, entry);
+
+    if file.is_none() {
+        let file = NewRFile::from_entry(conn, local_node, entry);
+
+        if file.is_err() {
+            warn!("error adding new file to db: {:?}", entry);
+            return;
+        }
+
+        let file = file.unwrap();
+        info!("DEAMON: new file was added {}", file.filename);
+
+        changes.added.push(file);
     } else {
-        error!("Not valid local node");
+        let mut file = file.unwrap();
+
+        // Replicas are written by the handler, after their row.
+        if file.node != local_node.uid || !file.is_modified(entry) {
+            return;
+        }
+
+        let digest = file.digest.clone();
+
+        if file.update_from_entry(conn, entry).is_err() {
+            warn!("error updating file: {:?}", entry);
+            return;
+        }
+
+        // Written back with the same content, as a repaired file is.
+        if file.digest == digest {
+            return;
+        }
+
+        info!("file modified: {}", file.abspath());
+
+        changes.modified.push(file);
+    }
+
+    announce(conn, configs, local_node, &changes);
+}
+
+/// Forgets a local file removed from the folder. A replica removed here is
+/// asked back from its node instead.
+fn file_removed(conn: &mut SqliteConnection, configs: &RConfig, local_node: &RNode, entry: &Path) {
+    // Replaced meanwhile, the new version settles on its own.
+    if entry.exists() {
+        return;
+    }
+
+    let file = RFile::from_entry(conn, entry);
+
+    if file.is_none() {
+        debug!("not tracked file removed: {:?}", entry);
+        return;
+    }
+
+    let mut file = file.unwrap();
+    let mut changes = ROfflineChanges::default();
+
+    if file.node != local_node.uid {
+        if file.status != FILE_STATUS_CORRUPTED && file.set_status(conn, FILE_STATUS_CORRUPTED).is_err() {
+            warn!("error to mark replica corrupted: {:?}", entry);
+            return;
+        }
+
+        info!("replica removed, asked back: {} ({})", file.abspath(), file.uid);
+
+        changes.damaged.push(file);
+    } else {
+        RFile::remove_from_uid(conn, &file.uid);
+        info!("file removed: {} ({})", file.abspath(), file.uid);
+
+        changes.removed.push(file);
     }
-  
-    Ok(())
-}
\ No newline at end of file
+
+    announce(conn, configs, local_node, &changes);
+}
+
+/// Live changes are announced like those found at start. The role may have
+/// been updated from the configs after the watcher started.
+fn announce(conn: &mut SqliteConnection, configs: &RConfig, local_node: &RNode, changes: &ROfflineChanges) {
+    let local_node = RNode::get_local(conn).unwrap_or_else(|| local_node.clone());
+
+    synchronizer::announce_offline_changes(conn, configs, &local_node, changes);
+}
@@ -0,0 +1,757 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use diesel::SqliteConnection;
+
+use log::{error, info, warn};
+
+use crate::models::files::{RFile, FILE_STATUS_CORRUPTED};
+use crate::models::nodes::RNode;
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_outgoing::RMessageOutgoing;
+use crate::models::replicas::RReplica;
+use crate::models::utils::error::RDatabaseError;
+use crate::models::utils::connection;
+use crate::placement::ring::RRing;
+use crate::peers::requests::{self, RReply, RRequestError};
+use crate::peers::scrubber::{self, RRepairRequest};
+use crate::protocol::message::{RMChunk, RMChunkData, RMFileChunks, RMFileDelta, RMFileModified, RMFileTransfer, RMReplicaRemove, RMessage};
+use crate::protocol::parts::{self, RPartWriter};
+use crate::utils::configs::RConfig;
+use crate::utils::crypto::RShareKey;
+use crate::utils::chunks::{self, RChunkCut};
+use crate::utils::delta;
+
+#[derive(Clone, Debug, Default)]
+pub struct RRebalanceProgress {
+    pub files: usize,
+    pub desired: usize,
+    pub confirmed: usize,
+    pub pending: usize,
+    pub scheduled: usize,
+    pub deferred: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub corrupted: usize,
+}
+
+impl RRebalanceProgress {
+    pub fn percent(&self) -> f64 {
+        if self.desired == 0 {
+            return 100.0;
+        }
+
+        (self.confirmed as f64 * 100.0) / self.desired as f64
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.confirmed == self.desired && self.removed == 0 && self.updated == 0 && self.corrupted == 0
+    }
+}
+
+/// Runs one rebalancing round over the files stored by the local node.
+///
+/// Missing replicas are scheduled as `FileTransfer` messages, or offered as
+/// chunks when large enough, at most `rebalancer.max_transfers` in flight at
+/// once. Replicas of an older
+/// version are updated with a delta when possible, see [`schedule_update`].
+/// Copies on nodes that are no longer responsible for a file are only
+/// removed once every replica the ring asks for has been confirmed.
+///
+/// Corrupted files are not copied anywhere, their repair is requested
+/// instead.
+///
+/// A replica still pending after `rebalancer.pending_timeout` seconds, or
+/// refused by its node, is dropped and scheduled again. Only the live ones
+/// count against the budget.
+pub fn rebalance(conn: &mut SqliteConnection, configs: &RConfig) -> Result<RRebalanceProgress, RDatabaseError> {
+    let local_node = RNode::get_local(conn);
+
+    if local_node.is_none() {
+        return Err(RDatabaseError::EntryNotExists);
+    }
+
+    let local_node = local_node.unwrap();
+
+    // A receive-only node keeps its own files local.
+    if !local_node.can_send() {
+        return Ok(RRebalanceProgress::default());
+    }
+
+    let ring = RRing::from_database(conn)?;
+    let files = RFile::get_all(conn).map_err(|_| RDatabaseError::EntryNotExists)?;
+
+    let pending_timeout = configs.rebalancer.pending_timeout;
+    let in_flight = RReplica::count_live_pending(conn, pending_timeout)? as usize;
+    let mut budget = configs.rebalancer.max_transfers.saturating_sub(in_flight);
+
+    let mut progress = RRebalanceProgress::default();
+    let mut updates = Vec::<RReplicaUpdate>::new();
+    let mut transfers = Vec::<RReplicaTransfer>::new();
+    let mut whole = Vec::<RReplicaSent>::new();
+    let mut repairs = Vec::<RRepairRequest>::new();
+
+    for file in files {
+        if file.node != local_node.uid {
+            continue;
+        }
+
+        progress.files += 1;
+
+        // Its copies are the good ones, sending it would spread the damage.
+        if file.status == FILE_STATUS_CORRUPTED {
+            progress.corrupted += 1;
+            repairs.extend(scrubber::request_repair(conn, configs, &file, &local_node));
+            continue;
+        }
+
+        let targets: Vec<String> = ring
+            .owners(&file.uid, configs.placement.replicas)
+            .into_iter()
+            .filter(|node_uid| *node_uid != local_node.uid)
+            .collect();
+
+        let replicas = RReplica::get_by_file(conn, &file.uid)?;
+        let mut all_confirmed = true;
+
+        progress.desired += targets.len();
+
+        for target in targets.iter() {
+            let mut replica = replicas.iter().find(|replica| replica.node == *target);
+
+            if replica.is_some_and(|replica| replica.is_expired(pending_timeout)) {
+                warn!(target: "REBALANCER", "transfer lost: {} -> {}, scheduled again", file.uid, target);
+
+                replica.unwrap().delete(conn)?;
+                replica = None;
+            }
+
+            if let Some(replica) = replica {
+                if replica.is_confirmed() && replica.is_stale(&file) {
+                    all_confirmed = false;
+
+                    if budget == 0 {
+                        progress.deferred += 1;
+                        continue;
+                    }
+
+                    if schedule_update(conn, configs, &file, replica.clone(), &mut updates, &mut whole).is_ok() {
+                        progress.updated += 1;
+                        budget -= 1;
+                    } else {
+                        warn!(target: "REBALANCER", "update not scheduled: {} -> {}", file.uid, target);
+                    }
+                } else if replica.is_confirmed() {
+                    progress.confirmed += 1;
+                } else {
+                    progress.pending += 1;
+                    all_confirmed = false;
+                }
+            } else {
+                all_confirmed = false;
+
+                if budget == 0 {
+                    progress.deferred += 1;
+                    continue;
+                }
+
+                if schedule_transfer(conn, configs, &file, target, &mut transfers, &mut whole).is_ok() {
+                    progress.scheduled += 1;
+                    budget -= 1;
+                } else {
+                    warn!(target: "REBALANCER", "transfer not scheduled: {} -> {}", file.uid, target);
+                }
+            }
+        }
+
+        if !all_confirmed {
+            continue;
+        }
+
+        for replica in replicas.iter() {
+            if targets.contains(&replica.node) {
+                continue;
+            }
+
+            if schedule_removal(conn, configs, &file, replica).is_ok() {
+                progress.removed += 1;
+            } else {
+                warn!(target: "REBALANCER", "removal not scheduled: {} on {}", file.uid, replica.node);
+            }
+        }
+    }
+
+    wait_transfers(conn, configs, transfers, &mut whole);
+    wait_updates(conn, configs, updates, &mut whole);
+    wait_whole(conn, whole);
+    scrubber::wait_repairs(repairs);
+
+    Ok(progress)
+}
+
+fn schedule_transfer(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    file: &RFile,
+    node_uid: &String,
+    transfers: &mut Vec<RReplicaTransfer>,
+    whole: &mut Vec<RReplicaSent>,
+) -> Result<(), RDatabaseError> {
+    let chunking = configs.protocol.chunking;
+    let node = RNode::get_by_uid(conn, node_uid.clone())?;
+
+    // Untrusted nodes hold encrypted copies, their chunks never match.
+    if !node.untrusted && chunking.enabled && (file.size as usize) >= chunking.min_size {
+        return schedule_chunked_transfer(conn, configs, file, &node, transfers);
+    }
+
+    let transfer = build_transfer(conn, configs, file, &node)?;
+
+    RReplica::create_pending(conn, file.uid.clone(), node_uid.clone(), file.digest.clone())?;
+
+    let reply = requests::request(conn, node_uid, RMessage::FileTransfer(transfer), reply_timeout(configs));
+    whole.push(((file.clone(), node), reply));
+
+    info!(target: "REBALANCER", "transfer scheduled: {} -> {}", file.uid, node_uid);
+
+    Ok(())
+}
+
+/// A copy sent to a node, waiting for it to be stored.
+type RReplicaSent = ((RFile, RNode), RReply);
+
+/// How long replies to the copies sent in a round are waited for. A copy
+/// stored later is still confirmed when its reply comes.
+fn reply_timeout(configs: &RConfig) -> Duration {
+    Duration::from_secs(configs.rebalancer.timeout as u64)
+}
+
+/// Content of `file`, as `node` may store it. A content larger than one
+/// message is read a part at a time and queued ahead of it, see
+/// [`parts::RPartWriter`].
+fn build_transfer(conn: &mut SqliteConnection, configs: &RConfig, file: &RFile, node: &RNode) -> Result<RMFileTransfer, RDatabaseError> {
+    let path = file.relative_path(&configs.folder_path);
+    let reader = File::open(file.abspath());
+
+    if path.is_none() || reader.is_err() {
+        return Err(RDatabaseError::EntryNotExists);
+    }
+
+    let mut transfer = RMFileTransfer {
+        file: file.clone(),
+        path: path.unwrap(),
+        content: Vec::new(),
+        compression: None,
+        offset: 0,
+    };
+
+    // Untrusted nodes only ever receive encrypted copies, sealed whole.
+    if node.untrusted {
+        if reader.unwrap().read_to_end(&mut transfer.content).is_err() {
+            return Err(RDatabaseError::EntryNotExists);
+        }
+
+        let share_key = RShareKey::load_or_create(configs);
+
+        transfer = match share_key {
+            Ok(Some(share_key)) => match share_key.seal_transfer(configs, transfer) {
+                Ok(transfer) => transfer,
+                Err(_) => return Err(RDatabaseError::EntryNotInsert),
+            },
+            _ => {
+                warn!(target: "REBALANCER", "encryption not enabled, nothing stored on untrusted node {}", node.uid);
+                return Err(RDatabaseError::EntryNotInsert);
+            }
+        };
+
+        return parts::queue_transfer(conn, configs, &node.uid, transfer);
+    }
+
+    let mut reader = reader.unwrap();
+    let part_size = parts::part_size(configs);
+
+    // One byte more tells whether it fits.
+    if reader.by_ref().take(part_size as u64 + 1).read_to_end(&mut transfer.content).is_err() {
+        return Err(RDatabaseError::EntryNotExists);
+    }
+
+    if transfer.content.len() <= part_size {
+        return Ok(transfer);
+    }
+
+    let mut writer = RPartWriter::new(conn, configs, &node.uid, &file.uid);
+    let mut buffer = vec![0u8; part_size];
+
+    writer.write(transfer.content.as_slice())?;
+    transfer.content = Vec::new();
+
+    loop {
+        let read = reader.read(buffer.as_mut_slice());
+
+        match read {
+            Ok(0) => break,
+            Ok(read) => writer.write(&buffer[..read])?,
+            Err(_) => return Err(RDatabaseError::EntryNotExists),
+        }
+    }
+
+    transfer.offset = writer.finish()?;
+
+    Ok(transfer)
+}
+
+/// A replica being updated with a delta.
+struct RReplicaUpdate {
+    file: RFile,
+    node: RNode,
+    path: String,
+    reply: RReply,
+}
+
+/// Brings a replica of an older version up to date. Trusted nodes are asked
+/// for the signatures of their copy so only the changed blocks are sent,
+/// untrusted ones only hold encrypted copies and get the whole file.
+fn schedule_update(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    file: &RFile,
+    mut replica: RReplica,
+    updates: &mut Vec<RReplicaUpdate>,
+    whole: &mut Vec<RReplicaSent>,
+) -> Result<(), RDatabaseError> {
+    let delta = configs.protocol.delta;
+    let node = RNode::get_by_uid(conn, replica.node.clone())?;
+    let path = file.relative_path(&configs.folder_path);
+
+    if path.is_none() {
+        return Err(RDatabaseError::EntryNotExists);
+    }
+
+    if node.untrusted || !delta.enabled || (file.size as usize) < delta.min_size {
+        let transfer = build_transfer(conn, configs, file, &node)?;
+
+        replica.set_pending(conn, file.digest.clone())?;
+
+        let reply = requests::request(conn, &node.uid, RMessage::FileTransfer(transfer), reply_timeout(configs));
+
+        info!(target: "REBALANCER", "update scheduled: {} -> {}", file.uid, node.uid);
+        whole.push(((file.clone(), node), reply));
+        return Ok(());
+    }
+
+    replica.set_pending(conn, file.digest.clone())?;
+
+    let path = path.unwrap();
+    let message = RMessage::FileModified(RMFileModified {
+        file: file.clone(),
+        path: Some(path.clone()),
+    });
+
+    let reply = requests::request(conn, &node.uid, message, Duration::from_secs(delta.timeout));
+
+    info!(target: "REBALANCER", "delta update requested: {} -> {}", file.uid, node.uid);
+
+    updates.push(RReplicaUpdate { file: file.clone(), node, path, reply });
+
+    Ok(())
+}
+
+/// Answers the signatures received for the updates of a round with their
+/// delta. Updates failing at any step fall back to the whole file.
+fn wait_updates(conn: &mut SqliteConnection, configs: &RConfig, updates: Vec<RReplicaUpdate>, whole: &mut Vec<RReplicaSent>) {
+    if updates.is_empty() {
+        return;
+    }
+
+    let timeout = Duration::from_secs(configs.protocol.delta.timeout);
+
+    let (updates, replies): (Vec<_>, Vec<_>) = updates.into_iter().map(|update| ((update.file, update.node, update.path), update.reply)).unzip();
+    let replies = futures::executor::block_on(futures::future::join_all(replies));
+
+    let mut deltas = Vec::<RReplicaSent>::new();
+
+    for ((file, node, path), reply) in updates.into_iter().zip(replies) {
+        let signatures = match reply {
+            Ok(RMessage::FileSignatures(signatures)) => signatures,
+            Ok(RMessage::Error(error)) => {
+                warn!(target: "REBALANCER", "signatures refused: {} by {}: {}", file.uid, node.uid, error);
+                fall_back(conn, configs, &file, &node, whole);
+                continue;
+            }
+            Ok(reply) => {
+                warn!(target: "REBALANCER", "unexpected reply to signatures request {} from {}: {}", file.uid, node.uid, reply);
+                fall_back(conn, configs, &file, &node, whole);
+                continue;
+            }
+            Err(e) => {
+                warn!(target: "REBALANCER", "signatures not received: {} from {}: {}", file.uid, node.uid, e);
+                fall_back(conn, configs, &file, &node, whole);
+                continue;
+            }
+        };
+
+        let reader = File::open(file.abspath());
+
+        if reader.is_err() {
+            warn!(target: "REBALANCER", "file not readable: {}", file.abspath());
+            continue;
+        }
+
+        // Worth it only when smaller than the file, and sent in one message.
+        let max_size = parts::part_size(configs).min(file.size as usize);
+        let ops = delta::delta(BufReader::new(reader.unwrap()), signatures.signatures.as_slice(), signatures.block_size, max_size);
+
+        if let Err(error) = ops {
+            info!(target: "REBALANCER", "no delta for {} -> {}: {}", file.uid, node.uid, error);
+            fall_back(conn, configs, &file, &node, whole);
+            continue;
+        }
+
+        let ops = ops.unwrap();
+
+        info!(
+            target: "REBALANCER",
+            "delta for {} -> {}: {} of {} bytes sent",
+            file.uid,
+            node.uid,
+            delta::literal_size(ops.as_slice()),
+            file.size
+        );
+
+        let message = RMessage::FileDelta(RMFileDelta {
+            file: file.clone(),
+            path,
+            block_size: signatures.block_size,
+            ops,
+        });
+
+        let reply = requests::request(conn, &node.uid, message, timeout);
+        deltas.push(((file, node), reply));
+    }
+
+    wait_stored(conn, configs, "delta", deltas, whole);
+}
+
+/// A new replica sent as chunks, waiting to know which ones the node lacks.
+struct RReplicaTransfer {
+    file: RFile,
+    node: RNode,
+    path: String,
+    cuts: Vec<RChunkCut>,
+    reply: RReply,
+}
+
+/// Offers the chunks of `file` to `node`, which asks only for the ones it
+/// holds in none of its files.
+fn schedule_chunked_transfer(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    file: &RFile,
+    node: &RNode,
+    transfers: &mut Vec<RReplicaTransfer>,
+) -> Result<(), RDatabaseError> {
+    let path = file.relative_path(&configs.folder_path);
+    let cuts = File::open(file.abspath()).and_then(|reader| chunks::read_cuts(BufReader::new(reader)));
+
+    if path.is_none() || cuts.is_err() {
+        return Err(RDatabaseError::EntryNotExists);
+    }
+
+    let path = path.unwrap();
+    let cuts = cuts.unwrap();
+
+    RReplica::create_pending(conn, file.uid.clone(), node.uid.clone(), file.digest.clone())?;
+
+    let message = RMessage::FileChunks(RMFileChunks {
+        file: file.clone(),
+        path: path.clone(),
+        chunks: cuts.iter().map(|cut| cut.to_ref()).collect(),
+    });
+
+    let timeout = Duration::from_secs(configs.protocol.chunking.timeout);
+    let reply = requests::request(conn, &node.uid, message, timeout);
+
+    info!(target: "REBALANCER", "chunks offered: {} -> {} ({} chunks)", file.uid, node.uid, cuts.len());
+
+    transfers.push(RReplicaTransfer {
+        file: file.clone(),
+        node: node.clone(),
+        path,
+        cuts,
+        reply,
+    });
+
+    Ok(())
+}
+
+/// Sends the chunks wanted for the transfers of a round. Transfers failing
+/// at any step fall back to the whole file.
+fn wait_transfers(conn: &mut SqliteConnection, configs: &RConfig, transfers: Vec<RReplicaTransfer>, whole: &mut Vec<RReplicaSent>) {
+    if transfers.is_empty() {
+        return;
+    }
+
+    let timeout = Duration::from_secs(configs.protocol.chunking.timeout);
+
+    let (transfers, replies): (Vec<_>, Vec<_>) = transfers
+        .into_iter()
+        .map(|transfer| ((transfer.file, transfer.node, transfer.path, transfer.cuts), transfer.reply))
+        .unzip();
+    let replies = futures::executor::block_on(futures::future::join_all(replies));
+
+    let mut sent = Vec::<RReplicaSent>::new();
+
+    for ((file, node, path, cuts), reply) in transfers.into_iter().zip(replies) {
+        let wanted = match reply {
+            Ok(RMessage::ChunksWanted(wanted)) => wanted,
+            Ok(RMessage::Error(error)) => {
+                warn!(target: "REBALANCER", "chunks refused: {} by {}: {}", file.uid, node.uid, error);
+                fall_back(conn, configs, &file, &node, whole);
+                continue;
+            }
+            Ok(reply) => {
+                warn!(target: "REBALANCER", "unexpected reply to chunks {} from {}: {}", file.uid, node.uid, reply);
+                fall_back(conn, configs, &file, &node, whole);
+                continue;
+            }
+            Err(e) => {
+                warn!(target: "REBALANCER", "wanted chunks not received: {} from {}: {}", file.uid, node.uid, e);
+                fall_back(conn, configs, &file, &node, whole);
+                continue;
+            }
+        };
+
+        let chunks = read_chunks(conn, configs, &file, &node, cuts.as_slice(), wanted.missing.as_slice());
+
+        // The file changed since its chunks were offered.
+        if chunks.is_none() {
+            fall_back(conn, configs, &file, &node, whole);
+            continue;
+        }
+
+        let (data, offset) = chunks.unwrap();
+
+        info!(
+            target: "REBALANCER",
+            "chunks for {} -> {}: {} of {} sent, {} of {} bytes",
+            file.uid,
+            node.uid,
+            data.len(),
+            cuts.len(),
+            wanted.missing.iter().filter_map(|index| cuts.get(*index as usize)).map(|cut| cut.size).sum::<usize>(),
+            file.size
+        );
+
+        let message = RMessage::ChunkData(RMChunkData {
+            file: file.clone(),
+            path,
+            chunks: cuts.iter().map(|cut| cut.to_ref()).collect(),
+            data,
+            offset,
+        });
+
+        let reply = requests::request(conn, &node.uid, message, timeout);
+        sent.push(((file, node), reply));
+    }
+
+    wait_stored(conn, configs, "chunks", sent, whole);
+}
+
+/// The chunks wanted by `node`, read from the file one at a time. When too
+/// large together for their `ChunkData` they are queued ahead of it as
+/// parts, and left empty there. None when the file changed since it was cut.
+fn read_chunks(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    file: &RFile,
+    node: &RNode,
+    cuts: &[RChunkCut],
+    missing: &[u32],
+) -> Option<(Vec<RMChunk>, u64)> {
+    let mut reader = File::open(file.abspath()).ok()?;
+    let wanted: Vec<&RChunkCut> = missing.iter().map(|index| cuts.get(*index as usize)).collect::<Option<_>>()?;
+
+    let mut writer = if wanted.iter().map(|cut| cut.size).sum::<usize>() > parts::part_size(configs) {
+        Some(RPartWriter::new(conn, configs, &node.uid, &file.uid))
+    } else {
+        None
+    };
+
+    let mut data = Vec::<RMChunk>::new();
+
+    for (index, cut) in missing.iter().zip(wanted) {
+        let mut content = vec![0u8; cut.size];
+
+        reader.seek(SeekFrom::Start(cut.offset as u64)).ok()?;
+        reader.read_exact(content.as_mut_slice()).ok()?;
+
+        if chunks::hash(content.as_slice()) != cut.hash {
+            return None;
+        }
+
+        if let Some(writer) = writer.as_mut() {
+            writer.write(content.as_slice()).ok()?;
+            content = Vec::new();
+        }
+
+        data.push(RMChunk { index: *index, content });
+    }
+
+    let offset = match writer {
+        Some(writer) => writer.finish().ok()?,
+        None => 0,
+    };
+
+    Some((data, offset))
+}
+
+/// Waits for the nodes to confirm the copies sent as `kind`.
+fn wait_stored(conn: &mut SqliteConnection, configs: &RConfig, kind: &str, sent: Vec<RReplicaSent>, whole: &mut Vec<RReplicaSent>) {
+    let (sent, replies): (Vec<_>, Vec<_>) = sent.into_iter().unzip();
+    let replies = futures::executor::block_on(futures::future::join_all(replies));
+
+    for ((file, node), reply) in sent.into_iter().zip(replies) {
+        match reply {
+            Ok(RMessage::ReplicaStored(_)) => info!(target: "REBALANCER", "{} stored: {} on {}", kind, file.uid, node.uid),
+            Ok(RMessage::Error(error)) => {
+                warn!(target: "REBALANCER", "{} refused: {} by {}: {}", kind, file.uid, node.uid, error);
+                fall_back(conn, configs, &file, &node, whole);
+            }
+            Ok(reply) => {
+                warn!(target: "REBALANCER", "unexpected reply to {} {} from {}: {}", kind, file.uid, node.uid, reply);
+                fall_back(conn, configs, &file, &node, whole);
+            }
+            // Still on its way, a large copy takes a while. It is confirmed
+            // when stored, or sent again once expired.
+            Err(RRequestError::Timeout(_)) => {}
+            Err(e) => {
+                warn!(target: "REBALANCER", "{} not stored: {} on {}: {}", kind, file.uid, node.uid, e);
+                fall_back(conn, configs, &file, &node, whole);
+            }
+        }
+    }
+}
+
+fn fall_back(conn: &mut SqliteConnection, configs: &RConfig, file: &RFile, node: &RNode, whole: &mut Vec<RReplicaSent>) {
+    let transfer = build_transfer(conn, configs, file, node);
+
+    if let Ok(transfer) = transfer {
+        let reply = requests::request(conn, &node.uid, RMessage::FileTransfer(transfer), reply_timeout(configs));
+        whole.push(((file.clone(), node.clone()), reply));
+
+        info!(target: "REBALANCER", "whole file sent instead: {} -> {}", file.uid, node.uid);
+    } else {
+        warn!(target: "REBALANCER", "whole file not sent: {} -> {}", file.uid, node.uid);
+        forget(conn, file, node);
+    }
+}
+
+/// Waits for the nodes to store the whole files sent in a round. Refused
+/// copies are scheduled again next round, the ones not answered in time
+/// stay pending until stored or expired.
+fn wait_whole(conn: &mut SqliteConnection, whole: Vec<RReplicaSent>) {
+    if whole.is_empty() {
+        return;
+    }
+
+    let (sent, replies): (Vec<_>, Vec<_>) = whole.into_iter().unzip();
+    let replies = futures::executor::block_on(futures::future::join_all(replies));
+
+    for ((file, node), reply) in sent.into_iter().zip(replies) {
+        match reply {
+            Ok(RMessage::ReplicaStored(_)) => info!(target: "REBALANCER", "transfer stored: {} on {}", file.uid, node.uid),
+            Ok(RMessage::Error(error)) => {
+                warn!(target: "REBALANCER", "transfer refused: {} by {}: {}", file.uid, node.uid, error);
+                forget(conn, &file, &node);
+            }
+            Ok(reply) => {
+                warn!(target: "REBALANCER", "unexpected reply to transfer {} from {}: {}", file.uid, node.uid, reply);
+                forget(conn, &file, &node);
+            }
+            Err(RRequestError::Timeout(_)) => {}
+            Err(e) => {
+                warn!(target: "REBALANCER", "transfer not sent: {} to {}: {}", file.uid, node.uid, e);
+                forget(conn, &file, &node);
+            }
+        }
+    }
+}
+
+/// Drops the pending replica of `file` on `node`, to send it again.
+fn forget(conn: &mut SqliteConnection, file: &RFile, node: &RNode) {
+    if RReplica::delete_pending(conn, &file.uid, &node.uid).is_err() {
+        warn!(target: "REBALANCER", "pending replica not dropped: {} on {}", file.uid, node.uid);
+    }
+}
+
+fn schedule_removal(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    file: &RFile,
+    replica: &RReplica,
+) -> Result<(), RDatabaseError> {
+    let path = file.relative_path(&configs.folder_path);
+
+    if path.is_none() {
+        return Err(RDatabaseError::EntryNotExists);
+    }
+
+    let mut path = path.unwrap();
+    let node = RNode::get_by_uid(conn, replica.node.clone())?;
+
+    if node.untrusted {
+        if let Ok(Some(share_key)) = RShareKey::load_or_create(configs) {
+            path = share_key.stored_path(configs, &path);
+        } else {
+            return Err(RDatabaseError::EntryNotExists);
+        }
+    }
+
+    let message = RMessage::ReplicaRemove(RMReplicaRemove {
+        uid: file.uid.clone(),
+        path,
+    });
+
+    RMessageOutgoing::push(conn, replica.node.clone(), message)?;
+    replica.delete(conn)?;
+
+    info!(target: "REBALANCER", "removal scheduled: {} on {}", file.uid, replica.node);
+
+    Ok(())
+}
+
+pub fn init(configs: RConfig) {
+    thread::spawn(move || {
+        let database_url = configs.database.path.clone();
+        let mut conn = connection::establish(database_url.as_str()).unwrap();
+
+        loop {
+            let progress = rebalance(&mut conn, &configs);
+
+            if let Ok(progress) = progress {
+                if !progress.is_balanced() {
+                    info!(
+                        target: "REBALANCER",
+                        "{}/{} replicas confirmed ({:.1}%), {} pending, {} scheduled, {} updated, {} deferred, {} removed, {} corrupted",
+                        progress.confirmed,
+                        progress.desired,
+                        progress.percent(),
+                        progress.pending,
+                        progress.scheduled,
+                        progress.updated,
+                        progress.deferred,
+                        progress.removed,
+                        progress.corrupted
+                    );
+                }
+            } else {
+                error!(target: "REBALANCER", "rebalance failed: {:?}", progress.unwrap_err());
+            }
+
+            sleep(Duration::from_secs(configs.rebalancer.timeout as u64));
+        }
+    });
+}
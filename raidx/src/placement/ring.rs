@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap};
+
+use diesel::SqliteConnection;
+use sha1::{Digest, Sha1};
+
+use crate::models::{nodes::RNode, utils::error::RDatabaseError};
+
+/// Virtual nodes placed on the ring for each unit of node weight.
+pub const RING_VNODES_PER_WEIGHT: u32 = 64;
+
+/// Largest weight a node is placed with, so its points stay a few thousand.
+pub const RING_MAX_WEIGHT: u32 = 100;
+
+/// Consistent-hash ring used to place files on nodes.
+///
+/// Every node owns `weight * RING_VNODES_PER_WEIGHT` points on the ring, so a
+/// membership change only moves the keys that fall between the points of the
+/// node that joined or left. A node with weight `0` stays known to the ring
+/// but never owns anything, weights above `RING_MAX_WEIGHT` count as it.
+#[derive(Clone, Debug, Default)]
+pub struct RRing {
+    vnodes: BTreeMap<u64, String>,
+    weights: HashMap<String, u32>,
+}
+
+impl RRing {
+    pub fn new() -> RRing {
+        RRing::default()
+    }
+
+    pub fn from_nodes(nodes: &[RNode]) -> RRing {
+        let mut ring = RRing::new();
+
+        for node in nodes.iter().filter(|node| node.is_approved()) {
+            // Draining and send-only nodes stay on the ring without owning anything.
+            let weight = if node.is_draining() || !node.can_receive() { 0 } else { node.weight.max(0) as u32 };
+            ring.add_node(&node.uid, weight);
+        }
+
+        ring
+    }
+
+    /// The rebalancer builds the ring again on every pass, so nodes approved,
+    /// rejected or drained meanwhile are placed on without a restart.
+    pub fn from_database(conn: &mut SqliteConnection) -> Result<RRing, RDatabaseError> {
+        let nodes = RNode::get_all(conn)?;
+
+        Ok(RRing::from_nodes(&nodes))
+    }
+
+    pub fn hash(key: &str) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+
+        let digest = hasher.finalize();
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+
+        u64::from_be_bytes(bytes)
+    }
+
+    pub fn clamp_weight(weight: u32) -> u32 {
+        weight.min(RING_MAX_WEIGHT)
+    }
+
+    pub fn add_node(&mut self, node_uid: &String, weight: u32) {
+        let weight = RRing::clamp_weight(weight);
+        self.remove_node(node_uid);
+
+        for i in 0..(weight * RING_VNODES_PER_WEIGHT) {
+            let point = RRing::hash(format!("{}#{}", node_uid, i).as_str());
+            self.vnodes.insert(point, node_uid.clone());
+        }
+
+        self.weights.insert(node_uid.clone(), weight);
+    }
+
+    pub fn remove_node(&mut self, node_uid: &String) {
+        if self.weights.remove(node_uid).is_some() {
+            self.vnodes.retain(|_, uid| uid != node_uid);
+        }
+    }
+
+    pub fn contains(&self, node_uid: &String) -> bool {
+        self.weights.contains_key(node_uid)
+    }
+
+    pub fn weight(&self, node_uid: &String) -> Option<u32> {
+        self.weights.get(node_uid).copied()
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self.weights.keys().cloned().collect();
+        nodes.sort();
+
+        nodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vnodes.is_empty()
+    }
+
+    /// Node responsible for the file with the given uid.
+    pub fn owner(&self, file_uid: &str) -> Option<String> {
+        self.owners(file_uid, 1).into_iter().next()
+    }
+
+    /// First `n` distinct nodes found walking clockwise from the file uid.
+    ///
+    /// The first entry is the primary owner, the others are the replicas in
+    /// order of preference.
+    pub fn owners(&self, file_uid: &str, n: usize) -> Vec<String> {
+        let mut owners = Vec::<String>::new();
+
+        if n == 0 || self.vnodes.is_empty() {
+            return owners;
+        }
+
+        let point = RRing::hash(file_uid);
+        let walk = self.vnodes.range(point..).chain(self.vnodes.range(..point));
+
+        for (_, node_uid) in walk {
+            if !owners.contains(node_uid) {
+                owners.push(node_uid.clone());
+
+                if owners.len() == n {
+                    break;
+                }
+            }
+        }
+
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weight_is_clamped() {
+        let mut ring = RRing::new();
+        ring.add_node(&"heavy".to_string(), u32::MAX);
+
+        assert_eq!(ring.weight(&"heavy".to_string()), Some(RING_MAX_WEIGHT));
+        assert!(ring.vnodes.len() <= (RING_MAX_WEIGHT * RING_VNODES_PER_WEIGHT) as usize);
+    }
+
+    #[test]
+    fn weights_split_the_files() {
+        let mut ring = RRing::new();
+        ring.add_node(&"light".to_string(), 1);
+        ring.add_node(&"heavy".to_string(), 3);
+        ring.add_node(&"none".to_string(), 0);
+
+        let heavy = (0..4_000).filter(|i| ring.owner(&format!("file-{}", i)).as_deref() == Some("heavy")).count();
+
+        assert!(ring.contains(&"none".to_string()));
+        assert_eq!(ring.owners("file", 3).len(), 2);
+        assert!((2_600..3_400).contains(&heavy));
+    }
+
+    fn ring(nodes: usize) -> RRing {
+        let mut ring = RRing::new();
+
+        for i in 0..nodes {
+            ring.add_node(&format!("node-{}", i), 1);
+        }
+
+        ring
+    }
+
+    fn owners(ring: &RRing) -> Vec<String> {
+        (0..10_000).map(|i| ring.owner(&format!("file-{}", i)).unwrap()).collect()
+    }
+
+    #[test]
+    fn joining_node_only_takes_its_share() {
+        let before = owners(&ring(4));
+        let after = owners(&ring(5));
+
+        let moved: Vec<(&String, &String)> = before.iter().zip(after.iter()).filter(|(before, after)| before != after).collect();
+
+        // About a fifth moves, and only to the node that joined.
+        assert!((1_500..2_500).contains(&moved.len()), "{} moved", moved.len());
+        assert!(moved.iter().all(|(_, after)| after.as_str() == "node-4"));
+    }
+
+    #[test]
+    fn leaving_node_only_gives_its_share() {
+        let mut ring = ring(5);
+        let before = owners(&ring);
+
+        ring.remove_node(&"node-4".to_string());
+        let after = owners(&ring);
+
+        let moved: Vec<(&String, &String)> = before.iter().zip(after.iter()).filter(|(before, after)| before != after).collect();
+
+        // Only what the leaving node owned moves, about a fifth.
+        assert!((1_500..2_500).contains(&moved.len()), "{} moved", moved.len());
+        assert!(moved.iter().all(|(before, _)| before.as_str() == "node-4"));
+        assert!(!after.contains(&"node-4".to_string()));
+    }
+}
@@ -0,0 +1,308 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use strum::VariantNames;
+
+use crate::peers::transport::RPeerFrame;
+use crate::protocol::message::{REnvelope, RMessage};
+use crate::protocol::version::{RSession, CAP_MSGPACK, PROTOCOL_VERSION};
+
+#[derive(Debug)]
+pub enum RCodecError {
+    Json(serde_json::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl std::fmt::Display for RCodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RCodecError::Json(e) => write!(f, "json: {}", e),
+            RCodecError::Encode(e) => write!(f, "msgpack encode: {}", e),
+            RCodecError::Decode(e) => write!(f, "msgpack decode: {}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for RCodecError {
+    fn from(e: serde_json::Error) -> Self {
+        RCodecError::Json(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for RCodecError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        RCodecError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for RCodecError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        RCodecError::Decode(e)
+    }
+}
+
+/// What goes on the wire: the envelope and the protocol version of the
+/// sender.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RFrame {
+    #[serde(default)]
+    version: u32,
+    #[serde(flatten)]
+    envelope: REnvelope,
+}
+
+/// Enough of a frame to tell its type and reply to it when the message
+/// itself can't be decoded.
+#[derive(Deserialize)]
+struct RFrameHeader {
+    _type: String,
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    in_reply_to: Option<String>,
+}
+
+/// Encoding of the frames exchanged with a peer.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RCodec {
+    /// Readable frames, for debugging.
+    Json,
+    #[default]
+    MessagePack,
+}
+
+impl RCodec {
+    /// Codec of a connection once the handshake is done. The handshake
+    /// itself always runs in JSON.
+    pub fn negotiate(session: &RSession) -> RCodec {
+        if session.has(CAP_MSGPACK) {
+            return RCodec::MessagePack;
+        }
+
+        RCodec::Json
+    }
+
+    pub fn encode_message(&self, envelope: &REnvelope) -> Result<Vec<u8>, RCodecError> {
+        let frame = RFrame { version: PROTOCOL_VERSION, envelope: envelope.clone() };
+
+        match self {
+            RCodec::MessagePack => Ok(rmp_serde::to_vec_named(&frame)?),
+            RCodec::Json => Ok(serde_json::to_vec(&frame)?),
+        }
+    }
+
+    pub fn decode_message(&self, frame: &[u8]) -> Result<REnvelope, RCodecError> {
+        let decoded = self.decode::<RFrame>(frame);
+
+        if let Ok(decoded) = decoded {
+            return Ok(decoded.envelope);
+        }
+
+        // A type added by a newer peer comes with a payload we can't decode,
+        // it is still a valid frame.
+        if let Ok(header) = self.decode::<RFrameHeader>(frame) {
+            if !RMessage::VARIANTS.contains(&header._type.as_str()) {
+                return Ok(REnvelope { id: header.id, in_reply_to: header.in_reply_to, message: RMessage::Unknown });
+            }
+        }
+
+        Err(decoded.unwrap_err())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, frame: &[u8]) -> Result<T, RCodecError> {
+        match self {
+            RCodec::MessagePack => Ok(rmp_serde::from_slice(frame)?),
+            RCodec::Json => Ok(serde_json::from_slice(frame)?),
+        }
+    }
+
+    pub fn to_frame(&self, envelope: &REnvelope) -> Result<RPeerFrame, RCodecError> {
+        let data = self.encode_message(envelope)?;
+
+        if envelope.message.is_transfer() {
+            return Ok(RPeerFrame::Transfer(data));
+        }
+
+        Ok(RPeerFrame::Data(data))
+    }
+}
+
+/// Encodes a value for local storage, such as the message queues.
+pub fn encode<T: Serialize>(content: &T) -> Result<Vec<u8>, RCodecError> {
+    Ok(rmp_serde::to_vec_named(content)?)
+}
+
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, RCodecError> {
+    Ok(rmp_serde::from_slice(data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::files::{NewRFile, RFile};
+    use crate::protocol::compression::RCompression;
+    use crate::protocol::message::*;
+    use crate::utils::chunks::RChunkRef;
+    use crate::utils::delta::{RBlockSignature, RDeltaOp};
+
+    fn file() -> RFile {
+        RFile {
+            id: 7,
+            uid: "0A1B2C".to_string(),
+            node: "node".to_string(),
+            folder: "/data/folder".to_string(),
+            filename: "file.bin".to_string(),
+            size: 5,
+            status: "READY".to_string(),
+            sync: true,
+            created_at: 1,
+            modified_at: 2,
+            updated_at: 3,
+            digest: Some("D1635".to_string()),
+            scrubbed_at: 4,
+        }
+    }
+
+    fn transfer() -> RMFileTransfer {
+        RMFileTransfer {
+            file: file(),
+            path: "folder/file.bin".to_string(),
+            content: vec![0, 1, 2, 255],
+            compression: Some(RCompression::Zstd),
+            offset: 12,
+        }
+    }
+
+    /// One message of every type, with its fields set.
+    fn messages() -> Vec<RMessage> {
+        let chunks = vec![RChunkRef { hash: "ab".repeat(32), size: 5 }];
+
+        vec![
+            RMessage::OK,
+            RMessage::error(RErrorCode::QuotaExceeded, "disk full".to_string()),
+            RMessage::SyncFiles(RMSyncFiles {
+                files: vec![NewRFile {
+                    uid: "0A1B2C".to_string(),
+                    node: "node".to_string(),
+                    folder: "/data".to_string(),
+                    filename: "file.bin".to_string(),
+                    size: 5,
+                    status: "READY".to_string(),
+                    created_at: 1,
+                    modified_at: 2,
+                    updated_at: 3,
+                    digest: None,
+                }],
+            }),
+            RMessage::FileAdded(RMFileAdded { file: file() }),
+            RMessage::UidRequest,
+            RMessage::UidResponse(RMUidRespose { uid: "node-uid".to_string() }),
+            RMessage::Hello(RMHello {
+                port: 4000,
+                version: PROTOCOL_VERSION,
+                capabilities: 31,
+                public_key: "aa".repeat(32),
+                nonce: "bb".repeat(32),
+                signature: "cc".repeat(64),
+                proof: Some("dd".repeat(32)),
+            }),
+            RMessage::FileTransfer(transfer()),
+            RMessage::ReplicaStored(RMReplicaStored { uid: "0A1B2C".to_string() }),
+            RMessage::ReplicaRemove(RMReplicaRemove { uid: "0A1B2C".to_string(), path: "file.bin".to_string() }),
+            RMessage::FileRequest(RMFileRequest { uid: "0A1B2C".to_string(), path: "file.bin".to_string() }),
+            RMessage::FileRepair(transfer()),
+            RMessage::FileModified(RMFileModified { file: file(), path: Some("file.bin".to_string()) }),
+            RMessage::FileRemoved(RMFileRemoved { file: file() }),
+            RMessage::Challenge(RMChallenge {
+                nonce: "bb".repeat(32),
+                version: PROTOCOL_VERSION,
+                capabilities: 31,
+                secret: true,
+                public_key: "aa".repeat(32),
+            }),
+            RMessage::HelloAck(RMHelloAck { public_key: "aa".repeat(32), signature: "cc".repeat(64), proof: None }),
+            RMessage::FileSignatures(RMFileSignatures {
+                uid: "0A1B2C".to_string(),
+                path: "file.bin".to_string(),
+                block_size: 4096,
+                signatures: vec![RBlockSignature { weak: 42, strong: vec![9; 32] }],
+            }),
+            RMessage::FileDelta(RMFileDelta {
+                file: file(),
+                path: "file.bin".to_string(),
+                block_size: 4096,
+                ops: vec![RDeltaOp::Copy { index: 1, count: 2 }, RDeltaOp::Data(vec![3, 4])],
+            }),
+            RMessage::FileChunks(RMFileChunks { file: file(), path: "file.bin".to_string(), chunks: chunks.clone() }),
+            RMessage::ChunksWanted(RMChunksWanted { uid: "0A1B2C".to_string(), missing: vec![0, 3] }),
+            RMessage::ChunkData(RMChunkData {
+                file: file(),
+                path: "file.bin".to_string(),
+                chunks,
+                data: vec![RMChunk { index: 0, content: b"hello".to_vec() }],
+                offset: 5,
+            }),
+            RMessage::NodeDraining(RMNodeDraining { public_key: Some("aa".repeat(32)), host: "host".to_string(), port: 4000 }),
+            RMessage::FilePart(RMFilePart { uid: "0A1B2C".to_string(), offset: 8, content: vec![5, 6], compression: None }),
+            RMessage::Unknown,
+        ]
+    }
+
+    #[test]
+    fn every_message_type_is_covered() {
+        let mut covered: Vec<String> = messages().iter().map(|message| message.to_string()).collect();
+        let mut variants: Vec<String> = RMessage::VARIANTS.iter().map(|variant| variant.to_string()).collect();
+
+        covered.sort();
+        variants.sort();
+
+        assert_eq!(covered, variants);
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for codec in [RCodec::Json, RCodec::MessagePack] {
+            for message in messages() {
+                let envelope = REnvelope { id: "id".to_string(), in_reply_to: Some("request".to_string()), message };
+
+                let frame = codec.encode_message(&envelope).unwrap();
+                let decoded = codec.decode_message(frame.as_slice()).unwrap();
+
+                assert_eq!(decoded.id, envelope.id);
+                assert_eq!(decoded.in_reply_to, envelope.in_reply_to);
+                assert_eq!(
+                    serde_json::to_value(&decoded.message).unwrap(),
+                    serde_json::to_value(&envelope.message).unwrap(),
+                    "{} under {:?}",
+                    envelope.message,
+                    codec
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn error_and_uid_payloads_survive() {
+        for codec in [RCodec::Json, RCodec::MessagePack] {
+            let error = REnvelope::new(RMessage::error(RErrorCode::RateLimited, "slow down".to_string()));
+            let decoded = codec.decode_message(codec.encode_message(&error).unwrap().as_slice()).unwrap();
+
+            match decoded.message {
+                RMessage::Error(error) => {
+                    assert_eq!(error.code, RErrorCode::RateLimited);
+                    assert_eq!(error.text, "slow down");
+                }
+                message => panic!("{} decoded as {}", error.message, message),
+            }
+
+            let uid = REnvelope::new(RMessage::UidResponse(RMUidRespose { uid: "node-uid".to_string() }));
+            let decoded = codec.decode_message(codec.encode_message(&uid).unwrap().as_slice()).unwrap();
+
+            match decoded.message {
+                RMessage::UidResponse(response) => assert_eq!(response.uid, "node-uid"),
+                message => panic!("{} decoded as {}", uid.message, message),
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::message::{RErrorCode, REnvelope, RMessage};
+use crate::protocol::version::{RSession, CAP_ZSTD};
+use crate::utils::configs::RConfigCompression;
+
+/// Bytes compressed to guess whether the rest of a file is worth it.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Samples shrinking less than this are treated as already compressed.
+const SAMPLE_MAX_RATIO: f64 = 0.9;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RCompression {
+    Zstd,
+}
+
+#[derive(Debug)]
+pub enum RCompressionError {
+    Zstd(std::io::Error),
+}
+
+impl std::fmt::Display for RCompressionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RCompressionError::Zstd(e) => write!(f, "content not decompressed: {}", e),
+        }
+    }
+}
+
+impl RCompressionError {
+    /// Error sent back to the peer instead of the dropped message.
+    pub fn to_message(&self) -> RMessage {
+        RMessage::error(RErrorCode::ChecksumMismatch, format!("{}", self))
+    }
+}
+
+/// File contents sent to one peer, and what compression saved on them.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RCompressionStats {
+    pub transfers: u64,
+    pub compressed: u64,
+    pub content_bytes: u64,
+    pub sent_bytes: u64,
+}
+
+impl RCompressionStats {
+    pub fn saved_bytes(&self) -> u64 {
+        self.content_bytes - self.sent_bytes
+    }
+}
+
+impl std::fmt::Display for RCompressionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} transfers ({} compressed), {} bytes sent for {}, {} saved",
+            self.transfers, self.compressed, self.sent_bytes, self.content_bytes, self.saved_bytes()
+        )
+    }
+}
+
+/// Compresses the file contents sent on one connection.
+pub struct RCompressor {
+    configs: RConfigCompression,
+    enabled: bool,
+    stats: RCompressionStats,
+    reported: RCompressionStats,
+}
+
+impl RCompressor {
+    /// Compression is only used when both peers advertised it.
+    pub fn new(configs: &RConfigCompression, session: &RSession) -> RCompressor {
+        RCompressor {
+            configs: configs.clone(),
+            enabled: configs.enabled && session.has(CAP_ZSTD),
+            stats: RCompressionStats::default(),
+            reported: RCompressionStats::default(),
+        }
+    }
+
+    /// Compresses the content carried by `envelope`, if any and if it pays off.
+    pub fn compress(&mut self, envelope: &mut REnvelope) {
+        let (path, content, compression) = match &mut envelope.message {
+            RMessage::FileTransfer(transfer) | RMessage::FileRepair(transfer) => {
+                (Some(transfer.path.as_str()), &mut transfer.content, &mut transfer.compression)
+            }
+            RMessage::FilePart(part) => (None, &mut part.content, &mut part.compression),
+            _ => return,
+        };
+
+        let content_bytes = content.len() as u64;
+
+        if self.enabled && compression.is_none() && self.worth_trying(path, content.as_slice()) {
+            let compressed = zstd::bulk::compress(content.as_slice(), self.configs.level);
+
+            if let Ok(compressed) = compressed {
+                if compressed.len() < content.len() {
+                    *content = compressed;
+                    *compression = Some(RCompression::Zstd);
+                    self.stats.compressed += 1;
+                }
+            }
+        }
+
+        self.stats.transfers += 1;
+        self.stats.content_bytes += content_bytes;
+        self.stats.sent_bytes += content.len() as u64;
+    }
+
+    /// Parts come without their path, so only sampling tells for them.
+    fn worth_trying(&self, path: Option<&str>, content: &[u8]) -> bool {
+        if content.len() < self.configs.min_size {
+            return false;
+        }
+
+        let extension = path.and_then(|path| Path::new(path).extension()).and_then(|extension| extension.to_str());
+
+        if let Some(extension) = extension {
+            let extension = extension.to_lowercase();
+
+            if self.configs.skip_extensions.iter().any(|skip| skip.to_lowercase() == extension) {
+                return false;
+            }
+        }
+
+        // Small files are cheap enough to just try.
+        if content.len() <= SAMPLE_SIZE {
+            return true;
+        }
+
+        let sample = &content[..SAMPLE_SIZE];
+
+        match zstd::bulk::compress(sample, 1) {
+            Ok(compressed) => (compressed.len() as f64) < SAMPLE_SIZE as f64 * SAMPLE_MAX_RATIO,
+            Err(_) => false,
+        }
+    }
+
+    /// Stats since the connection was opened, when they changed since the
+    /// last call.
+    pub fn report(&mut self) -> Option<RCompressionStats> {
+        if self.stats == self.reported {
+            return None;
+        }
+
+        self.reported = self.stats;
+        Some(self.stats)
+    }
+}
+
+/// Restores the content carried by `envelope`. Content expanding past
+/// `max_size` is refused, like a frame that large would be.
+pub fn decompress(envelope: &mut REnvelope, max_size: usize) -> Result<(), RCompressionError> {
+    let (content, compression) = match &mut envelope.message {
+        RMessage::FileTransfer(transfer) | RMessage::FileRepair(transfer) => (&mut transfer.content, &mut transfer.compression),
+        RMessage::FilePart(part) => (&mut part.content, &mut part.compression),
+        _ => return Ok(()),
+    };
+
+    if *compression == Some(RCompression::Zstd) {
+        *content = zstd::bulk::decompress(content.as_slice(), max_size).map_err(RCompressionError::Zstd)?;
+        *compression = None;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+    use crate::models::files::RFile;
+    use crate::protocol::message::{RMFilePart, RMFileTransfer};
+    use crate::protocol::version::PROTOCOL_VERSION;
+
+    fn session(capabilities: u64) -> RSession {
+        RSession::negotiate(CAP_ZSTD, PROTOCOL_VERSION, capabilities).unwrap()
+    }
+
+    fn transfer(path: &str, content: Vec<u8>) -> REnvelope {
+        let file = RFile {
+            id: 1,
+            uid: "0A1B2C".to_string(),
+            node: "node".to_string(),
+            folder: "/data".to_string(),
+            filename: path.to_string(),
+            size: content.len() as i32,
+            status: "READY".to_string(),
+            sync: true,
+            created_at: 0,
+            modified_at: 0,
+            updated_at: 0,
+            digest: None,
+            scrubbed_at: 0,
+        };
+
+        REnvelope::new(RMessage::FileTransfer(RMFileTransfer { file, path: path.to_string(), content, compression: None, offset: 0 }))
+    }
+
+    fn part(content: Vec<u8>) -> REnvelope {
+        REnvelope::new(RMessage::FilePart(RMFilePart { uid: "0A1B2C".to_string(), offset: 0, content, compression: None }))
+    }
+
+    fn compression(envelope: &REnvelope) -> Option<RCompression> {
+        match &envelope.message {
+            RMessage::FileTransfer(transfer) => transfer.compression,
+            RMessage::FilePart(part) => part.compression,
+            message => panic!("no content in {}", message),
+        }
+    }
+
+    fn text(size: usize) -> Vec<u8> {
+        b"all work and no play makes jack a dull boy. ".iter().copied().cycle().take(size).collect()
+    }
+
+    fn noise(size: usize) -> Vec<u8> {
+        let mut content = vec![0u8; size];
+        rand::thread_rng().fill_bytes(content.as_mut_slice());
+
+        content
+    }
+
+    #[test]
+    fn compressed_only_when_both_peers_advertise_it() {
+        let configs = RConfigCompression::default();
+        let disabled = RConfigCompression { enabled: false, ..RConfigCompression::default() };
+
+        for (configs, session, compressed) in
+            [(&configs, session(CAP_ZSTD), true), (&configs, session(0), false), (&disabled, session(CAP_ZSTD), false)]
+        {
+            let mut envelope = transfer("notes.txt", text(10_000));
+            RCompressor::new(configs, &session).compress(&mut envelope);
+
+            assert_eq!(compression(&envelope).is_some(), compressed);
+        }
+    }
+
+    #[test]
+    fn compressed_content_round_trips() {
+        let mut compressor = RCompressor::new(&RConfigCompression::default(), &session(CAP_ZSTD));
+        let mut envelope = transfer("notes.txt", text(100_000));
+
+        compressor.compress(&mut envelope);
+        let stats = compressor.report().unwrap();
+
+        assert_eq!(compression(&envelope), Some(RCompression::Zstd));
+        assert_eq!((stats.transfers, stats.compressed, stats.content_bytes), (1, 1, 100_000));
+        assert!(stats.saved_bytes() > 90_000);
+        assert!(compressor.report().is_none());
+
+        decompress(&mut envelope, 100_000).unwrap();
+
+        match envelope.message {
+            RMessage::FileTransfer(transfer) => {
+                assert_eq!(transfer.content, text(100_000));
+                assert_eq!(transfer.compression, None);
+            }
+            message => panic!("{} decompressed", message),
+        }
+    }
+
+    #[test]
+    fn skipped_by_extension_or_size() {
+        let mut compressor = RCompressor::new(&RConfigCompression::default(), &session(CAP_ZSTD));
+
+        let mut photo = transfer("holidays/PHOTO.JPG", text(10_000));
+        let mut small = transfer("notes.txt", text(100));
+
+        compressor.compress(&mut photo);
+        compressor.compress(&mut small);
+
+        assert_eq!(compression(&photo), None);
+        assert_eq!(compression(&small), None);
+        assert_eq!(compressor.report().unwrap().compressed, 0);
+    }
+
+    #[test]
+    fn skipped_when_the_sample_does_not_shrink() {
+        let mut compressor = RCompressor::new(&RConfigCompression::default(), &session(CAP_ZSTD));
+
+        // Compressible past the sample, which alone decides.
+        let mut content = noise(SAMPLE_SIZE);
+        content.extend(text(SAMPLE_SIZE * 4));
+
+        let mut noisy = part(content);
+        let mut plain = part(text(SAMPLE_SIZE * 2));
+
+        compressor.compress(&mut noisy);
+        compressor.compress(&mut plain);
+
+        assert_eq!(compression(&noisy), None);
+        assert_eq!(compression(&plain), Some(RCompression::Zstd));
+    }
+
+    #[test]
+    fn expanding_past_the_limit_is_refused() {
+        let mut compressor = RCompressor::new(&RConfigCompression::default(), &session(CAP_ZSTD));
+        let mut envelope = part(text(100_000));
+
+        compressor.compress(&mut envelope);
+
+        assert!(decompress(&mut envelope, 10_000).is_err());
+    }
+}
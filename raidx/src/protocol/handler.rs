@@ -0,0 +1,871 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use diesel::SqliteConnection;
+use log::{info, warn};
+
+use crate::models::chunks::RChunk;
+use crate::models::files::{NewRFile, RFile, FILE_STATUS_CORRUPTED, FILE_STATUS_READY, FILE_STATUS_REPLICA};
+use crate::models::nodes::{RNode, NODE_STATUS_DRAINING};
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_outgoing::RMessageOutgoing;
+use crate::models::replicas::RReplica;
+use crate::protocol::message::{
+    RErrorCode, RMChunkData, RMChunksWanted, RMError, RMFileChunks, RMNodeDraining, RMFileDelta, RMFileModified, RMFileRequest, RMFileSignatures,
+    RMFileTransfer, RMReplicaRemove, RMReplicaStored, RMUidRespose, RMessage,
+};
+use crate::protocol::parts;
+use crate::utils::configs::RConfig;
+use crate::utils::crypto::RShareKey;
+use crate::utils::chunks;
+use crate::utils::delta;
+use crate::utils::paths::{self, RPathError};
+
+pub fn handle(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    from: &RNode,
+    message: RMessage,
+) -> Option<RMessage> {
+    if let Err(text) = validate_role(conn, from, &message) {
+        warn!("{} refused from {}: {}", message, from.uid, text);
+        return Some(RMessage::error(RErrorCode::PermissionDenied, text));
+    }
+
+    if let Err(error) = validate_paths(configs, &message) {
+        warn!("not valid path in {} from {}: {}", message, from.uid, error);
+        return Some(RMessage::error(RErrorCode::PermissionDenied, format!("not valid path: {}", error)));
+    }
+
+    match message {
+        RMessage::FileTransfer(transfer) => {
+            let uid = transfer.file.uid.clone();
+            let result = parts::take_transfer(configs, &from.uid, transfer).and_then(|transfer| store_replica(conn, configs, from, transfer));
+
+            match result {
+                Ok(_) => {
+                    info!("replica stored: {} from {}", uid, from.uid);
+                    Some(RMessage::ReplicaStored(RMReplicaStored { uid }))
+                }
+                Err(error) => {
+                    warn!("replica not stored: {} from {}: {}", uid, from.uid, error);
+                    Some(RMessage::Error(error))
+                }
+            }
+        }
+        RMessage::FileModified(modified) if modified.path.is_some() => {
+            let uid = modified.file.uid.clone();
+            let result = read_signatures(conn, configs, from, modified);
+
+            match result {
+                Ok(signatures) => Some(RMessage::FileSignatures(signatures)),
+                Err(error) => {
+                    warn!("signatures not sent: {} to {}: {}", uid, from.uid, error);
+                    Some(RMessage::Error(error))
+                }
+            }
+        }
+        RMessage::FileModified(modified) => {
+            refresh_replica(conn, configs, from, modified.file);
+            None
+        }
+        // Answered by the rebalancer waiting for them.
+        RMessage::FileSignatures(_) => None,
+        RMessage::FileDelta(delta) => {
+            let uid = delta.file.uid.clone();
+            let result = apply_delta(conn, configs, from, delta);
+
+            match result {
+                Ok(_) => {
+                    info!("replica updated: {} from {}", uid, from.uid);
+                    Some(RMessage::ReplicaStored(RMReplicaStored { uid }))
+                }
+                Err(error) => {
+                    warn!("replica not updated: {} from {}: {}", uid, from.uid, error);
+                    Some(RMessage::Error(error))
+                }
+            }
+        }
+        RMessage::FileChunks(manifest) => {
+            let uid = manifest.file.uid.clone();
+            let result = find_missing_chunks(conn, from, manifest);
+
+            match result {
+                Ok(missing) => Some(RMessage::ChunksWanted(missing)),
+                Err(error) => {
+                    warn!("chunks not looked up: {} from {}: {}", uid, from.uid, error);
+                    Some(RMessage::Error(error))
+                }
+            }
+        }
+        // Answered by the rebalancer waiting for them.
+        RMessage::ChunksWanted(_) => None,
+        RMessage::ChunkData(data) => {
+            let uid = data.file.uid.clone();
+            let result = assemble_chunks(conn, configs, from, data);
+
+            match result {
+                Ok(_) => {
+                    info!("replica stored from chunks: {} from {}", uid, from.uid);
+                    Some(RMessage::ReplicaStored(RMReplicaStored { uid }))
+                }
+                Err(error) => {
+                    warn!("replica not stored: {} from {}: {}", uid, from.uid, error);
+                    Some(RMessage::Error(error))
+                }
+            }
+        }
+        RMessage::ReplicaStored(stored) => {
+            if RReplica::confirm(conn, &stored.uid, &from.uid).is_ok() {
+                info!("replica confirmed: {} on {}", stored.uid, from.uid);
+            } else {
+                warn!("replica not tracked: {} on {}", stored.uid, from.uid);
+            }
+            None
+        }
+        RMessage::ReplicaRemove(remove) => {
+            remove_replica(conn, configs, from, remove);
+            None
+        }
+        RMessage::FileAdded(added) => {
+            // Copies of it are sent by the rebalancer of its node.
+            info!("file added on {}: {}", from.uid, added.file.uid);
+            None
+        }
+        RMessage::FileRemoved(removed) => {
+            if let Some(replica) = find_replica(conn, configs, from, &removed.file) {
+                delete_replica(conn, &replica);
+            }
+            None
+        }
+        RMessage::FileRequest(request) => {
+            let result = if from.untrusted {
+                read_for_untrusted(conn, configs, request)
+            } else {
+                read_for_repair(conn, configs, request)
+            };
+
+            // Sent ahead of the reply when too large for it.
+            let result = result.and_then(|transfer| {
+                parts::queue_transfer(conn, configs, &from.uid, transfer)
+                    .map_err(|error| RMError::new(RErrorCode::Internal, format!("{:?}", error)))
+            });
+
+            match result {
+                Ok(repair) => {
+                    unconfirm_replica(conn, from, &repair.file);
+                    Some(RMessage::FileRepair(repair))
+                }
+                Err(error) => {
+                    warn!("file not sent to {}: {}", from.uid, error);
+                    Some(RMessage::Error(error))
+                }
+            }
+        }
+        RMessage::FileRepair(repair) => {
+            let path = repair.path.clone();
+            let uid = repair.file.uid.clone();
+            let result = parts::take_transfer(configs, &from.uid, repair).and_then(|repair| {
+                if from.untrusted {
+                    return open_from_untrusted(conn, configs, repair).and_then(|repair| repair_file(conn, configs, repair));
+                }
+
+                repair_file(conn, configs, repair)
+            });
+
+            match result {
+                // A replica asked back from its node is confirmed to it again.
+                Ok(file) if file.node == from.uid => {
+                    info!("replica repaired from {}: {}", from.uid, path);
+                    Some(RMessage::ReplicaStored(RMReplicaStored { uid }))
+                }
+                Ok(_) => {
+                    info!("file repaired from {}: {}", from.uid, path);
+                    None
+                }
+                Err(error) => {
+                    warn!("file not repaired from {}: {}: {}", from.uid, path, error);
+                    None
+                }
+            }
+        }
+        RMessage::NodeDraining(draining) => {
+            drain_node(conn, from, draining);
+            None
+        }
+        RMessage::FilePart(part) => {
+            // The message it belongs to fails if it is missing.
+            if let Err(error) = parts::stage(configs, &from.uid, &part) {
+                warn!("part of {} from {} not kept: {}", part.uid, from.uid, error);
+            }
+            None
+        }
+        RMessage::UidRequest => {
+            let node = RNode::get_local(conn);
+
+            if let Some(node) = node {
+                Some(RMessage::UidResponse(RMUidRespose { uid: node.uid }))
+            } else {
+                warn!("error to fetch local node from db");
+                None
+            }
+        }
+        RMessage::Error(error) => {
+            warn!("error from {}: {} ({})", from.uid, error, error.code.retry());
+            None
+        }
+        RMessage::Unknown => {
+            warn!("unknown message type from {}", from.uid);
+            Some(RMessage::error(RErrorCode::VersionUnsupported, "unknown message type".to_string()))
+        }
+        message => {
+            info!("message from {}: {:?}", from.uid, message);
+            None
+        }
+    }
+}
+
+/// Refuses changes coming from a receive-only peer or sent to a send-only
+/// local node. Repairs and replica removals are always allowed.
+fn validate_role(conn: &mut SqliteConnection, from: &RNode, message: &RMessage) -> Result<(), String> {
+    let is_change = matches!(
+        message,
+        RMessage::FileTransfer(_)
+            | RMessage::FileDelta(_)
+            | RMessage::FileChunks(_)
+            | RMessage::ChunkData(_)
+            | RMessage::FileAdded(_)
+            | RMessage::FileModified(_)
+            | RMessage::FileRemoved(_)
+            | RMessage::SyncFiles(_)
+    );
+
+    if !is_change {
+        return Ok(());
+    }
+
+    if !from.can_send() {
+        return Err("peer is receive-only".to_string());
+    }
+
+    if let Some(local_node) = RNode::get_local(conn) {
+        if !local_node.can_receive() {
+            return Err("node is send-only".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Refuses any inbound path that could point outside the shared folder.
+fn validate_paths(configs: &RConfig, message: &RMessage) -> Result<(), RPathError> {
+    match message {
+        RMessage::FileTransfer(transfer) | RMessage::FileRepair(transfer) => {
+            paths::validate_remote_file(&transfer.file.folder, &transfer.file.filename)?;
+            paths::resolve(&configs.folder_path, &transfer.path).map(|_| ())
+        }
+        RMessage::FileDelta(delta) => {
+            paths::validate_remote_file(&delta.file.folder, &delta.file.filename)?;
+            paths::resolve(&configs.folder_path, &delta.path).map(|_| ())
+        }
+        RMessage::FileChunks(manifest) => {
+            paths::validate_remote_file(&manifest.file.folder, &manifest.file.filename)?;
+            paths::resolve(&configs.folder_path, &manifest.path).map(|_| ())
+        }
+        RMessage::ChunkData(data) => {
+            paths::validate_remote_file(&data.file.folder, &data.file.filename)?;
+            paths::resolve(&configs.folder_path, &data.path).map(|_| ())
+        }
+        RMessage::ReplicaRemove(remove) => paths::resolve(&configs.folder_path, &remove.path).map(|_| ()),
+        RMessage::FileRequest(request) => paths::resolve(&configs.folder_path, &request.path).map(|_| ()),
+        RMessage::FileAdded(added) => paths::validate_remote_file(&added.file.folder, &added.file.filename),
+        RMessage::FileModified(modified) => {
+            paths::validate_remote_file(&modified.file.folder, &modified.file.filename)?;
+
+            if let Some(path) = modified.path.as_ref() {
+                paths::resolve(&configs.folder_path, path)?;
+            }
+            Ok(())
+        }
+        RMessage::FileRemoved(removed) => paths::validate_remote_file(&removed.file.folder, &removed.file.filename),
+        RMessage::SyncFiles(sync) => {
+            for file in sync.files.iter() {
+                paths::validate_remote_file(&file.folder, &file.filename)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn store_replica(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    from: &RNode,
+    transfer: RMFileTransfer,
+) -> Result<RFile, RMError> {
+    let entry = resolve_path(configs, &transfer.path)?;
+    let entry = entry.as_path();
+
+    let digest = RFile::calc_digest_from_slice(transfer.content.as_slice());
+
+    // Sealed copies for untrusted nodes come without a digest.
+    if transfer.file.digest.is_some() && transfer.file.digest.as_ref() != Some(&digest) {
+        return Err(RMError::new(RErrorCode::ChecksumMismatch, format!("content does not match the digest: {}", transfer.path)));
+    }
+
+    let existing = RFile::from_entry(conn, entry);
+
+    if let Some(file) = existing.as_ref() {
+        if !is_replica_of(conn, file, from) {
+            return Err(RMError::new(RErrorCode::PermissionDenied, format!("not a replica from {}: {}", from.uid, transfer.path)));
+        }
+    }
+
+    let replaced = existing.is_some();
+
+    let mut file = match existing {
+        Some(mut file) if file.node != from.uid => {
+            file.set_node(conn, &from.uid).map_err(|e| RMError::new(RErrorCode::Internal, format!("{:?}", e)))?;
+            info!("replica adopted by {}: {}", from.uid, file.uid);
+
+            file
+        }
+        Some(file) => file,
+        None => {
+            let folder = entry.parent().unwrap().to_str().unwrap().to_string();
+            let filename = entry.file_name().unwrap().to_str().unwrap().to_string();
+            let updated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+            // The row is saved before the content is written so the watcher
+            // does not pick the replica up as a new local file.
+            let file = NewRFile {
+                uid: RFile::calc_uid(entry),
+                node: from.uid.clone(),
+                folder,
+                filename,
+                size: transfer.file.size,
+                status: String::from(FILE_STATUS_REPLICA),
+                created_at: transfer.file.created_at,
+                modified_at: transfer.file.modified_at,
+                updated_at: updated_at as i32,
+                digest: Some(digest),
+            }
+            .save(conn);
+
+            let file = file.map_err(|e| RMError::new(RErrorCode::Internal, format!("{:?}", e)))?;
+
+            file
+        }
+    };
+
+    if let Some(parent) = entry.parent() {
+        if let Err(error) = std::fs::create_dir_all(parent) {
+            return Err(io_error(error));
+        }
+    }
+
+    let cuts = chunks::cut(transfer.content.as_slice());
+
+    if let Err(error) = std::fs::write(entry, transfer.content) {
+        return Err(io_error(error));
+    }
+
+    // An existing row still describes the previous version.
+    if replaced {
+        if let Err(error) = file.update_from_entry(conn, entry) {
+            return Err(RMError::new(RErrorCode::Internal, format!("{:?}", error)));
+        }
+    }
+
+    // Chunks of this copy can be used for the next files sent here.
+    if let Err(error) = RChunk::index(conn, &file, cuts.as_slice()) {
+        warn!("chunks not indexed: {}: {:?}", file.uid, error);
+    }
+
+    Ok(file)
+}
+
+/// Chunks of `manifest` held in none of the local files. Untrusted nodes are
+/// refused: the answer tells which contents this node holds.
+fn find_missing_chunks(conn: &mut SqliteConnection, from: &RNode, manifest: RMFileChunks) -> Result<RMChunksWanted, RMError> {
+    if from.untrusted {
+        return Err(RMError::new(RErrorCode::PermissionDenied, format!("chunks refused from untrusted {}", from.uid)));
+    }
+
+    let hashes: Vec<String> = manifest.chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+    let located = RChunk::locate(conn, hashes.as_slice());
+
+    if let Err(error) = located {
+        return Err(RMError::new(RErrorCode::Internal, format!("{:?}", error)));
+    }
+
+    let located = located.unwrap();
+
+    let missing = hashes
+        .iter()
+        .enumerate()
+        .filter(|(_, hash)| !located.contains_key(*hash))
+        .map(|(index, _)| index as u32)
+        .collect();
+
+    Ok(RMChunksWanted {
+        uid: manifest.file.uid,
+        missing,
+    })
+}
+
+/// Rebuilds a file from the chunks received and the ones already held.
+/// Untrusted nodes only store sealed copies, and can't be handed local
+/// contents by hash.
+fn assemble_chunks(conn: &mut SqliteConnection, configs: &RConfig, from: &RNode, data: RMChunkData) -> Result<RFile, RMError> {
+    if from.untrusted {
+        return Err(RMError::new(RErrorCode::PermissionDenied, format!("chunks refused from untrusted {}", from.uid)));
+    }
+
+    let hashes: Vec<String> = data.chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+    let located = RChunk::locate(conn, hashes.as_slice());
+
+    if let Err(error) = located {
+        return Err(RMError::new(RErrorCode::Internal, format!("{:?}", error)));
+    }
+
+    let located = located.unwrap();
+    let staged = if data.offset > 0 {
+        parts::take(configs, &from.uid, &data.file.uid, data.offset)?
+    } else {
+        Vec::new()
+    };
+
+    // Chunks sent empty were sent ahead as parts, in order.
+    let mut cursor = 0;
+    let mut received = HashMap::<u32, Vec<u8>>::new();
+
+    for chunk in data.data {
+        let size = data.chunks.get(chunk.index as usize).map_or(0, |chunk| chunk.size as usize);
+
+        if !chunk.content.is_empty() || cursor + size > staged.len() {
+            received.insert(chunk.index, chunk.content);
+        } else {
+            received.insert(chunk.index, staged[cursor..cursor + size].to_vec());
+            cursor += size;
+        }
+    }
+
+    if cursor != staged.len() {
+        return Err(RMError::new(RErrorCode::ChecksumMismatch, format!("{} bytes sent ahead, {} used", staged.len(), cursor)));
+    }
+
+    let mut content = Vec::<u8>::new();
+
+    for (index, chunk) in data.chunks.iter().enumerate() {
+        let part = received.remove(&(index as u32)).or_else(|| {
+            let locations = located.get(&chunk.hash)?;
+            locations.iter().find_map(|location| location.read(conn))
+        });
+
+        match part {
+            Some(part) if part.len() == chunk.size as usize && chunks::hash(part.as_slice()) == chunk.hash => {
+                content.extend_from_slice(part.as_slice());
+            }
+            Some(_) => return Err(RMError::new(RErrorCode::ChecksumMismatch, format!("chunk {} does not match {}", index, chunk.hash))),
+            None => return Err(RMError::new(RErrorCode::UnknownFile, format!("chunk {} not held: {}", index, chunk.hash))),
+        }
+    }
+
+    // Checked against the digest when stored.
+    let transfer = RMFileTransfer {
+        file: data.file,
+        path: data.path,
+        content,
+        compression: None,
+        offset: 0,
+    };
+
+    store_replica(conn, configs, from, transfer)
+}
+
+/// Where the replica at `path` is, checked to be a replica of `from` when
+/// this node has one.
+fn find_replica_entry(conn: &mut SqliteConnection, configs: &RConfig, from: &RNode, path: &String) -> Result<PathBuf, RMError> {
+    let entry = resolve_path(configs, path)?;
+
+    if let Some(file) = RFile::from_entry(conn, entry.as_path()) {
+        if !is_replica_of(conn, &file, from) {
+            return Err(RMError::new(RErrorCode::PermissionDenied, format!("not a replica from {}: {}", from.uid, path)));
+        }
+    }
+
+    Ok(entry)
+}
+
+/// Whether `from` may replace the replica held for `file`: its own node, or
+/// a trusted peer adopting the files of a draining node.
+fn is_replica_of(conn: &mut SqliteConnection, file: &RFile, from: &RNode) -> bool {
+    if file.node == from.uid {
+        return true;
+    }
+
+    if from.untrusted || file.status != FILE_STATUS_REPLICA {
+        return false;
+    }
+
+    RNode::get_by_uid(conn, file.node.clone()).is_ok_and(|owner| owner.is_draining())
+}
+
+/// Signatures of the replica, read a block at a time. Empty when this node
+/// has none.
+fn read_signatures(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    from: &RNode,
+    modified: RMFileModified,
+) -> Result<RMFileSignatures, RMError> {
+    let path = modified.path.unwrap_or_default();
+    let entry = find_replica_entry(conn, configs, from, &path)?;
+
+    let (block_size, signatures) = match File::open(entry) {
+        Ok(replica) => {
+            let len = replica.metadata().map_err(io_error)?.len();
+            let block_size = delta::block_size(len as usize);
+
+            (block_size, delta::signatures(BufReader::new(replica), block_size).map_err(io_error)?)
+        }
+        Err(_) => (delta::block_size(0), Vec::new()),
+    };
+
+    Ok(RMFileSignatures {
+        uid: modified.file.uid,
+        path,
+        block_size,
+        signatures,
+    })
+}
+
+fn apply_delta(conn: &mut SqliteConnection, configs: &RConfig, from: &RNode, delta: RMFileDelta) -> Result<RFile, RMError> {
+    let entry = find_replica_entry(conn, configs, from, &delta.path)?;
+    let base = std::fs::read(entry).unwrap_or_default();
+    let content = delta::apply(base.as_slice(), delta.ops.as_slice(), delta.block_size);
+
+    if let Err(error) = content {
+        return Err(RMError::new(RErrorCode::ChecksumMismatch, format!("{}", error)));
+    }
+
+    let content = content.unwrap();
+
+    // Checked against the digest when stored.
+    let transfer = RMFileTransfer {
+        file: delta.file,
+        path: delta.path,
+        content,
+        compression: None,
+        offset: 0,
+    };
+
+    store_replica(conn, configs, from, transfer)
+}
+
+fn remove_replica(conn: &mut SqliteConnection, configs: &RConfig, from: &RNode, remove: RMReplicaRemove) {
+    let entry = resolve_path(configs, &remove.path);
+
+    if let Err(error) = entry {
+        warn!("replica not removed: {}", error);
+        return;
+    }
+
+    let entry = entry.unwrap();
+    let file = RFile::from_entry(conn, entry.as_path());
+
+    if let Some(file) = file {
+        if file.node != from.uid {
+            warn!("replica not owned by {}: {}", from.uid, entry.display());
+            return;
+        }
+
+        delete_replica(conn, &file);
+    } else {
+        warn!("replica not found: {}", entry.display());
+    }
+}
+
+fn delete_replica(conn: &mut SqliteConnection, file: &RFile) {
+    let abspath = file.abspath();
+
+    // The row goes first, so the watcher doesn't take the removal for a
+    // replica lost here.
+    RFile::remove_from_uid(conn, &file.uid);
+
+    if let Err(error) = std::fs::remove_file(abspath.as_str()) {
+        warn!("replica not removed: {}: {}", abspath, error);
+    }
+
+    info!("replica removed: {} ({})", abspath, file.uid);
+}
+
+/// Local replica of `file`, a file of `from` announced by it. The folders
+/// of the nodes differ, so it is the replica whose path within ours ends
+/// the path of the file, the longest one when several do.
+fn find_replica(conn: &mut SqliteConnection, configs: &RConfig, from: &RNode, file: &RFile) -> Option<RFile> {
+    let announced = std::path::PathBuf::from(file.abspath());
+    let files = RFile::get_all(conn).ok()?;
+
+    files
+        .into_iter()
+        .filter(|replica| replica.node == from.uid && replica.filename == file.filename)
+        .filter(|replica| replica.relative_path(&configs.folder_path).is_some_and(|path| announced.ends_with(path)))
+        .max_by_key(|replica| replica.abspath().len())
+}
+
+/// Asks the node of a file modified there for its new version. Meanwhile
+/// the replica no longer matches the digest it should have, as a damaged
+/// one, so it is not used to repair other nodes and the copy received is
+/// checked against the new digest.
+fn refresh_replica(conn: &mut SqliteConnection, configs: &RConfig, from: &RNode, file: RFile) {
+    let replica = find_replica(conn, configs, from, &file);
+
+    if replica.is_none() || file.digest.is_none() {
+        return;
+    }
+
+    let mut replica = replica.unwrap();
+
+    if replica.digest == file.digest {
+        return;
+    }
+
+    let path = replica.relative_path(&configs.folder_path).unwrap_or_default();
+
+    if replica.set_digest(conn, file.digest.clone()).is_err() || replica.set_status(conn, FILE_STATUS_CORRUPTED).is_err() {
+        warn!("replica not marked outdated: {}", replica.abspath());
+        return;
+    }
+
+    let request = RMessage::FileRequest(RMFileRequest { uid: file.uid.clone(), path: path.clone() });
+
+    if RMessageOutgoing::push(conn, from.uid.clone(), request).is_ok() {
+        info!("replica outdated, new version requested: {} from {}", path, from.uid);
+    } else {
+        warn!("new version not requested: {} from {}", path, from.uid);
+    }
+}
+
+/// A node asking for a file it holds a replica of lost or damaged it, so
+/// the replica is no longer counted until it is stored again.
+fn unconfirm_replica(conn: &mut SqliteConnection, from: &RNode, file: &RFile) {
+    let replicas = RReplica::get_by_file(conn, &file.uid).unwrap_or_default();
+
+    for mut replica in replicas {
+        if replica.node != from.uid || !replica.is_confirmed() {
+            continue;
+        }
+
+        if replica.set_pending(conn, file.digest.clone()).is_ok() {
+            info!("replica on {} asked back, confirmation dropped: {}", from.uid, file.uid);
+        } else {
+            warn!("replica confirmation not dropped: {} on {}", file.uid, from.uid);
+        }
+    }
+}
+
+/// Marks a node decommissioned by a trusted peer as draining here too, so
+/// this node stops placing copies on it as well.
+fn drain_node(conn: &mut SqliteConnection, from: &RNode, draining: RMNodeDraining) {
+    if from.untrusted {
+        warn!("drain of {}:{} refused from untrusted {}", draining.host, draining.port, from.uid);
+        return;
+    }
+
+    let node = match draining.public_key.as_ref() {
+        Some(public_key) => RNode::get_by_public_key(conn, public_key),
+        None => RNode::get_by_host_and_port(conn, draining.host.clone(), draining.port),
+    };
+
+    if node.is_none() {
+        warn!("node to drain not found: {}:{}", draining.host, draining.port);
+        return;
+    }
+
+    let mut node = node.unwrap();
+
+    if node.local {
+        warn!("{} reports this node as draining", from.uid);
+        return;
+    }
+
+    if node.is_draining() {
+        return;
+    }
+
+    if node.set_status(conn, NODE_STATUS_DRAINING).is_ok() {
+        info!("node draining, reported by {}: {}:{} ({})", from.uid, node.host, node.port, node.uid);
+    } else {
+        warn!("node not marked draining: {}", node.uid);
+    }
+}
+
+fn read_for_repair(conn: &mut SqliteConnection, configs: &RConfig, request: RMFileRequest) -> Result<RMFileTransfer, RMError> {
+    let entry = resolve_path(configs, &request.path)?;
+    let entry = entry.as_path();
+
+    let file = RFile::from_entry(conn, entry);
+
+    if file.is_none() {
+        return Err(RMError::new(RErrorCode::UnknownFile, request.path));
+    }
+
+    let file = file.unwrap();
+
+    if file.status == FILE_STATUS_CORRUPTED {
+        return Err(RMError::new(RErrorCode::ChecksumMismatch, format!("file corrupted: {}", request.path)));
+    }
+
+    let content = std::fs::read(entry);
+
+    if let Err(error) = content {
+        return Err(io_error(error));
+    }
+
+    let content = content.unwrap();
+
+    // Never hand out a copy that does not match what was recorded for it.
+    if file.digest.is_some() && file.digest != Some(RFile::calc_digest_from_slice(content.as_slice())) {
+        return Err(RMError::new(RErrorCode::ChecksumMismatch, request.path));
+    }
+
+    Ok(RMFileTransfer {
+        file,
+        path: request.path,
+        content,
+        compression: None,
+        offset: 0,
+    })
+}
+
+fn repair_file(conn: &mut SqliteConnection, configs: &RConfig, repair: RMFileTransfer) -> Result<RFile, RMError> {
+    let entry = resolve_path(configs, &repair.path)?;
+    let entry = entry.as_path();
+
+    let file = RFile::from_entry(conn, entry);
+
+    if file.is_none() {
+        return Err(RMError::new(RErrorCode::UnknownFile, repair.path));
+    }
+
+    let mut file = file.unwrap();
+
+    if file.digest != Some(RFile::calc_digest_from_slice(repair.content.as_slice())) {
+        return Err(RMError::new(RErrorCode::ChecksumMismatch, "received copy does not match the stored digest".to_string()));
+    }
+
+    // A replica asked back may have lost its folder too.
+    if let Some(parent) = entry.parent() {
+        if let Err(error) = std::fs::create_dir_all(parent) {
+            return Err(io_error(error));
+        }
+    }
+
+    if let Err(error) = std::fs::write(entry, repair.content) {
+        return Err(io_error(error));
+    }
+
+    let local_node = RNode::get_local(conn);
+    let status = match local_node {
+        Some(local_node) if local_node.uid == file.node => FILE_STATUS_READY,
+        _ => FILE_STATUS_REPLICA,
+    };
+
+    if let Err(error) = file.set_status(conn, status) {
+        return Err(RMError::new(RErrorCode::Internal, format!("{:?}", error)));
+    }
+
+    Ok(file)
+}
+
+/// Path of a file received from a peer within the shared folder, checked
+/// again where it is used since a link can be planted in the meantime.
+fn resolve_path(configs: &RConfig, path: &String) -> Result<PathBuf, RMError> {
+    paths::resolve(&configs.folder_path, path)
+        .map_err(|error| RMError::new(RErrorCode::PermissionDenied, format!("not valid path: {}: {}", path, error)))
+}
+
+fn load_share_key(configs: &RConfig) -> Result<RShareKey, RMError> {
+    match RShareKey::load_or_create(configs) {
+        Ok(Some(share_key)) => Ok(share_key),
+        Ok(None) => Err(RMError::new(RErrorCode::PermissionDenied, "encryption not enabled".to_string())),
+        Err(error) => Err(RMError::new(RErrorCode::Internal, format!("{:?}", error))),
+    }
+}
+
+fn io_error(error: std::io::Error) -> RMError {
+    let code = match error.kind() {
+        std::io::ErrorKind::NotFound => RErrorCode::UnknownFile,
+        std::io::ErrorKind::PermissionDenied => RErrorCode::PermissionDenied,
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => RErrorCode::QuotaExceeded,
+        _ => RErrorCode::Internal,
+    };
+
+    RMError::new(code, format!("{}", error))
+}
+
+/// Maps the path a file is stored under on untrusted nodes back to its
+/// plaintext path. Encrypted names can't be reversed, so they are matched
+/// against the names of the local files.
+fn resolve_stored_path(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    share_key: &RShareKey,
+    path: &String,
+) -> Result<String, RMError> {
+    if !configs.encryption.names {
+        return Ok(path.clone());
+    }
+
+    let files = RFile::get_all(conn);
+
+    if files.is_err() {
+        return Err(RMError::new(RErrorCode::Internal, "not valid result from database".to_string()));
+    }
+
+    for file in files.unwrap() {
+        if let Some(relative) = file.relative_path(&configs.folder_path) {
+            if share_key.encrypt_name(&relative) == *path {
+                return Ok(relative);
+            }
+        }
+    }
+
+    Err(RMError::new(RErrorCode::UnknownFile, path.clone()))
+}
+
+fn read_for_untrusted(conn: &mut SqliteConnection, configs: &RConfig, request: RMFileRequest) -> Result<RMFileTransfer, RMError> {
+    let share_key = load_share_key(configs)?;
+    let path = resolve_stored_path(conn, configs, &share_key, &request.path)?;
+
+    let transfer = read_for_repair(conn, configs, RMFileRequest { uid: request.uid, path })?;
+
+    share_key
+        .seal_transfer(configs, transfer)
+        .map_err(|error| RMError::new(RErrorCode::Internal, format!("{:?}", error)))
+}
+
+fn open_from_untrusted(conn: &mut SqliteConnection, configs: &RConfig, repair: RMFileTransfer) -> Result<RMFileTransfer, RMError> {
+    let share_key = load_share_key(configs)?;
+    let path = resolve_stored_path(conn, configs, &share_key, &repair.path)?;
+
+    let content = share_key.decrypt(repair.content.as_slice());
+
+    if content.is_err() {
+        return Err(RMError::new(RErrorCode::ChecksumMismatch, "received copy can't be decrypted".to_string()));
+    }
+
+    Ok(RMFileTransfer {
+        file: repair.file,
+        path,
+        content: content.unwrap(),
+        compression: None,
+        offset: 0,
+    })
+}
@@ -1,59 +1,149 @@
 extern crate strum_macros;
-use diesel::SqliteConnection;
 use serde::{Deserialize, Serialize};
-use websocket::OwnedMessage;
 
-use crate::models::{files::{NewRFile, RFile}, nodes::RNode};
+use crate::models::files::{NewRFile, RFile};
+use crate::protocol::compression::RCompression;
+use crate::utils::chunks::RChunkRef;
+use crate::utils::delta::{RBlockSignature, RDeltaOp};
 
-#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::Display)]
-pub enum RMessageType {
+/// A message exchanged between nodes. The variant is the message type and
+/// carries its payload, so the same definition is used to encode and to
+/// decode it.
+#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::Display, strum_macros::VariantNames)]
+#[serde(tag = "_type", content = "data")]
+pub enum RMessage {
     OK,
-    Error,
-    SyncFiles,
-    FileAdded,
-    UidRequest,
-    UidResponse
-}
-
-#[derive(Debug)]
-pub enum RContentKind {
-    OK(RMOk),
     Error(RMError),
     SyncFiles(RMSyncFiles),
     FileAdded(RMFileAdded),
-    UidRequest(RMUidRequest),
+    UidRequest,
     UidResponse(RMUidRespose),
+    Hello(RMHello),
+    FileTransfer(RMFileTransfer),
+    ReplicaStored(RMReplicaStored),
+    ReplicaRemove(RMReplicaRemove),
+    FileRequest(RMFileRequest),
+    FileRepair(RMFileTransfer),
+    FileModified(RMFileModified),
+    FileRemoved(RMFileRemoved),
+    Challenge(RMChallenge),
+    HelloAck(RMHelloAck),
+    FileSignatures(RMFileSignatures),
+    FileDelta(RMFileDelta),
+    FileChunks(RMFileChunks),
+    ChunksWanted(RMChunksWanted),
+    ChunkData(RMChunkData),
+    NodeDraining(RMNodeDraining),
+    FilePart(RMFilePart),
+    /// Any type this build doesn't know, sent by a newer peer.
+    #[serde(other)]
+    Unknown
 }
 
-pub trait RMessageTrait<T> {
-    fn from_slice(data: Vec<u8>) -> Result<T, serde_json::Error>;
-    fn to_slice(&self) -> Result<Vec<u8>, serde_json::error::Error>;
+impl RMessage {
+    pub fn error(code: RErrorCode, text: String) -> RMessage {
+        return RMessage::Error(RMError { code, text });
+    }
+
+    /// Carries file contents, so it can be large.
+    pub fn is_transfer(&self) -> bool {
+        return matches!(
+            self,
+            RMessage::FileTransfer(_) | RMessage::FileRepair(_) | RMessage::FileDelta(_) | RMessage::ChunkData(_) | RMessage::FilePart(_)
+        );
+    }
 }
 
+/// A message with the ids used to match a reply to its request.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct RMessage {
-    pub _type: RMessageType,
-    pub data: Option<Vec<u8>>
+pub struct REnvelope {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    #[serde(flatten)]
+    pub message: RMessage
 }
 
-impl RMessageTrait<RMessage> for RMessage {
-    fn from_slice(data: Vec<u8>) -> Result<RMessage, serde_json::Error> {
-        return serde_json::from_slice(data.as_slice());
+impl REnvelope {
+    pub fn new(message: RMessage) -> REnvelope {
+        return REnvelope { id: uuid::Uuid::new_v4().to_string(), in_reply_to: None, message };
     }
 
-    fn to_slice(&self) -> Result<Vec<u8>, serde_json::error::Error>{
-        return serde_json::to_vec(self);
+    /// Wraps `message` as the reply to this envelope.
+    pub fn reply(&self, message: RMessage) -> REnvelope {
+        let mut reply = REnvelope::new(message);
+        reply.in_reply_to = Some(self.id.clone());
+
+        return reply;
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct RMOk;
+/// Why a request failed, so the sender can tell what to do next and errors
+/// can be counted by kind.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, strum_macros::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RErrorCode {
+    UnknownFile,
+    PermissionDenied,
+    ChecksumMismatch,
+    VersionUnsupported,
+    QuotaExceeded,
+    RateLimited,
+    #[default]
+    Internal,
+    /// Any code this build doesn't know, sent by a newer peer.
+    #[serde(other)]
+    Unknown
+}
+
+/// What the sender of a failed request should do about it.
+#[derive(Debug, Clone, Copy, PartialEq, strum_macros::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum RRetry {
+    /// Try again, the failure is likely transient.
+    Retry,
+    /// Try again, but slower: the peer is overloaded.
+    BackOff,
+    /// The same request will fail again.
+    GiveUp
+}
+
+impl RErrorCode {
+    pub fn retry(&self) -> RRetry {
+        return match self {
+            RErrorCode::RateLimited | RErrorCode::QuotaExceeded => RRetry::BackOff,
+            RErrorCode::Internal | RErrorCode::Unknown => RRetry::Retry,
+            _ => RRetry::GiveUp,
+        };
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RMError {
+    #[serde(default)]
+    pub code: RErrorCode,
+    /// Details for humans, may be empty.
+    #[serde(default)]
     pub text: String
 }
 
+impl RMError {
+    pub fn new(code: RErrorCode, text: String) -> RMError {
+        return RMError { code, text };
+    }
+}
+
+impl std::fmt::Display for RMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.text.is_empty() {
+            return write!(f, "{}", self.code);
+        }
+
+        return write!(f, "{}: {}", self.code, self.text);
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RMSyncFiles {
     pub files: Vec<NewRFile>
@@ -64,105 +154,203 @@ pub struct RMFileAdded {
     pub file: RFile
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMFileModified {
+    pub file: RFile,
+    /// Set when sent to a node holding a replica, which answers with the
+    /// `FileSignatures` of its copy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct RMUidRequest;
+pub struct RMFileRemoved {
+    pub file: RFile
+}
 
-impl RMessageTrait<RMUidRequest> for RMUidRequest {
-    fn from_slice(data: Vec<u8>) -> Result<RMUidRequest, serde_json::Error> {
-        return serde_json::from_slice(data.as_slice());
-    }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMUidRespose {
+    pub uid: String
+}
 
-    fn to_slice(&self) -> Result<Vec<u8>, serde_json::error::Error>{
-        return serde_json::to_vec(self);
-    }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMChallenge {
+    pub nonce: String,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub capabilities: u64,
+    #[serde(default)]
+    pub secret: bool,
+    /// Key the server signs the handshake with, covered by both signatures.
+    #[serde(default)]
+    pub public_key: String
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMHello {
+    pub port: usize,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub capabilities: u64,
+    pub public_key: String,
+    pub nonce: String,
+    pub signature: String,
+    #[serde(default)]
+    pub proof: Option<String>
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct RMUidRespose {
+pub struct RMHelloAck {
+    pub public_key: String,
+    pub signature: String,
+    #[serde(default)]
+    pub proof: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMFileTransfer {
+    pub file: RFile,
+    pub path: String,
+    #[serde(with = "serde_bytes")]
+    pub content: Vec<u8>,
+    /// Set when `content` was compressed for the wire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<RCompression>,
+    /// Bytes of the content sent ahead as `FilePart`s, `content` follows them.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMReplicaStored {
     pub uid: String
 }
 
-impl RMessageTrait<RMUidRespose> for RMUidRespose {
-    fn from_slice(data: Vec<u8>) -> Result<RMUidRespose, serde_json::Error> {
-        return serde_json::from_slice(data.as_slice());
-    }
+/// A node being decommissioned, so every peer stops placing copies on it.
+/// It is found by public key, by address when it has none yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMNodeDraining {
+    pub public_key: Option<String>,
+    pub host: String,
+    pub port: i32
+}
 
-    fn to_slice(&self) -> Result<Vec<u8>, serde_json::error::Error>{
-        return serde_json::to_vec(self);
-    }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMReplicaRemove {
+    pub uid: String,
+    pub path: String
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMFileRequest {
+    pub uid: String,
+    pub path: String
+}
 
+/// Block signatures of the copy held by the receiver, empty if it has none.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMFileSignatures {
+    pub uid: String,
+    pub path: String,
+    pub block_size: usize,
+    pub signatures: Vec<RBlockSignature>
+}
 
-impl RMessage {
-    pub fn to_ws_message(&self) -> Result<OwnedMessage, serde_json::Error> {
-        let message = self.to_slice();
+/// Changes rebuilding the current version from the copy signed in
+/// `FileSignatures`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMFileDelta {
+    pub file: RFile,
+    pub path: String,
+    pub block_size: usize,
+    pub ops: Vec<RDeltaOp>
+}
+
+/// Chunks making up a file about to be sent, so the receiver can tell which
+/// ones it already holds in any of its files.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMFileChunks {
+    pub file: RFile,
+    pub path: String,
+    pub chunks: Vec<RChunkRef>
+}
 
-        if let Ok(message) = message {
-            return Ok(OwnedMessage::Binary(message));
-        } else {
-            return Err(message.unwrap_err());
+/// Indexes, in `FileChunks`, of the chunks the receiver doesn't hold.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMChunksWanted {
+    pub uid: String,
+    pub missing: Vec<u32>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMChunk {
+    pub index: u32,
+    #[serde(with = "serde_bytes")]
+    pub content: Vec<u8>
+}
+
+/// The file again, with the content of the chunks wanted only.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMChunkData {
+    pub file: RFile,
+    pub path: String,
+    pub chunks: Vec<RChunkRef>,
+    pub data: Vec<RMChunk>,
+    /// Bytes of chunk contents sent ahead as `FilePart`s. They fill, in
+    /// order, the chunks of `data` sent empty.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: u64
+}
+
+/// A piece of a content too large for one frame, sent ahead of the message
+/// carrying the rest. The receiver keeps it until that message comes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RMFilePart {
+    pub uid: String,
+    pub offset: u64,
+    #[serde(with = "serde_bytes")]
+    pub content: Vec<u8>,
+    /// Set when `content` was compressed for the wire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<RCompression>
+}
+
+fn is_zero(value: &u64) -> bool {
+    return *value == 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_error_code_tells_what_to_do() {
+        let codes = [
+            (RErrorCode::UnknownFile, RRetry::GiveUp),
+            (RErrorCode::PermissionDenied, RRetry::GiveUp),
+            (RErrorCode::ChecksumMismatch, RRetry::GiveUp),
+            (RErrorCode::VersionUnsupported, RRetry::GiveUp),
+            (RErrorCode::QuotaExceeded, RRetry::BackOff),
+            (RErrorCode::RateLimited, RRetry::BackOff),
+            (RErrorCode::Internal, RRetry::Retry),
+            (RErrorCode::Unknown, RRetry::Retry),
+        ];
+
+        for (code, retry) in codes {
+            assert_eq!(code.retry(), retry, "{}", code);
         }
     }
 
-    pub fn has_data(self) -> bool {
-        return self.data.is_some();
-    }
- 
-    pub fn get_content(self, conn: &mut SqliteConnection) -> RContentKind {
-        return match self._type {
-            RMessageType::OK => RContentKind::OK(RMOk{}),
-            RMessageType::FileAdded => {
-                if self.clone().has_data() {
-                    let data = self.data.unwrap();
-                    let file = serde_json::from_slice(data.as_slice());
-
-                    if file.is_ok() {
-                        let content = RContentKind::FileAdded(RMFileAdded{
-                            file: file.unwrap()
-                        });
-
-                        return content;
-                    } else {
-                        return RContentKind::Error(RMError { text: format!("{}", file.unwrap_err()) });
-                    }
-                } else {
-                    return RContentKind::Error(RMError { text: format!("data can't be None") })
-                }
-            },
-            RMessageType::UidRequest => RContentKind::UidRequest(RMUidRequest{}),
-            RMessageType::UidResponse => {
-                let node = RNode::get_local(conn);
-
-                if node.is_some() {
-                    let node = node.unwrap();
-                    
-                    return RContentKind::UidResponse(RMUidRespose { uid: node.uid });
-                } else {
-                    return RContentKind::Error(RMError { text: format!("error to fetch local node from db") });
-                }
-            },
-            RMessageType::Error => RContentKind::Error(RMError{
-                text: String::from("dada")
-            }),
-            RMessageType::SyncFiles => {
-
-                if self.data.is_some() {
-                    let data = self.data.unwrap();
-
-                    let content = serde_json::from_slice(data.as_slice());
-
-                    if content.is_ok() {
-                        return RContentKind::SyncFiles(content.unwrap());
-                    } else {
-                        return RContentKind::Error(RMError { text: format!("{}", content.unwrap_err()) })
-                    }
-                } else {
-                    return RContentKind::Error(RMError { text: format!("data can't be None") })
-                }
-            },
-        };
+    #[test]
+    fn unknown_and_missing_codes_are_retried() {
+        let newer: RMError = serde_json::from_str(r#"{"code": "disk_on_fire", "text": "newer peer"}"#).unwrap();
+        let older: RMError = serde_json::from_str(r#"{"text": "older peer"}"#).unwrap();
+
+        assert_eq!(newer.code, RErrorCode::Unknown);
+        assert_eq!(older.code, RErrorCode::Internal);
+        assert_eq!(newer.code.retry(), RRetry::Retry);
+        assert_eq!(older.code.retry(), RRetry::Retry);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,257 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use diesel::SqliteConnection;
+
+use crate::models::queues::messages::RMessageQueue;
+use crate::models::queues::messages_outgoing::RMessageOutgoing;
+use crate::models::utils::error::RDatabaseError;
+use crate::protocol::message::{RErrorCode, RMError, RMFilePart, RMFileTransfer, RMessage};
+use crate::utils::configs::RConfig;
+
+/// Largest piece of content sent in one message, whatever the frame size,
+/// so a queued message stays small.
+const MAX_PART_SIZE: usize = 4 * 1024 * 1024;
+
+/// Bytes of content carried by one message. Without MessagePack a frame
+/// encodes them as a JSON array, up to four characters each, so an eighth of
+/// `limits.max_frame_size` leaves room for that and the rest of the message.
+pub fn part_size(configs: &RConfig) -> usize {
+    (configs.limits.max_frame_size / 8).clamp(1, MAX_PART_SIZE)
+}
+
+/// Queues a content for node `node_uid` as `FilePart`s of [`part_size`]
+/// bytes, ahead of the message it belongs to.
+pub struct RPartWriter<'a> {
+    conn: &'a mut SqliteConnection,
+    node_uid: String,
+    uid: String,
+    part_size: usize,
+    offset: u64,
+    buffer: Vec<u8>,
+}
+
+impl<'a> RPartWriter<'a> {
+    pub fn new(conn: &'a mut SqliteConnection, configs: &RConfig, node_uid: &str, uid: &str) -> RPartWriter<'a> {
+        RPartWriter {
+            conn,
+            node_uid: node_uid.to_string(),
+            uid: uid.to_string(),
+            part_size: part_size(configs),
+            offset: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), RDatabaseError> {
+        while !data.is_empty() {
+            let taken = data.len().min(self.part_size - self.buffer.len());
+
+            self.buffer.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+
+            if self.buffer.len() == self.part_size {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RDatabaseError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let part = RMFilePart {
+            uid: self.uid.clone(),
+            offset: self.offset,
+            content: std::mem::take(&mut self.buffer),
+            compression: None,
+        };
+
+        self.offset += part.content.len() as u64;
+        RMessageOutgoing::push(self.conn, self.node_uid.clone(), RMessage::FilePart(part))?;
+
+        Ok(())
+    }
+
+    /// Queues what is left, and returns how many bytes were sent ahead.
+    pub fn finish(mut self) -> Result<u64, RDatabaseError> {
+        self.flush()?;
+        Ok(self.offset)
+    }
+}
+
+/// `transfer` as sent to node `node_uid`: a content too large for one
+/// message is queued ahead as parts and left out of it.
+pub fn queue_transfer(
+    conn: &mut SqliteConnection,
+    configs: &RConfig,
+    node_uid: &str,
+    mut transfer: RMFileTransfer,
+) -> Result<RMFileTransfer, RDatabaseError> {
+    if transfer.content.len() <= part_size(configs) {
+        return Ok(transfer);
+    }
+
+    let mut writer = RPartWriter::new(conn, configs, node_uid, &transfer.file.uid);
+
+    writer.write(transfer.content.as_slice())?;
+    transfer.offset = writer.finish()?;
+    transfer.content = Vec::new();
+
+    Ok(transfer)
+}
+
+/// Where the parts received from node `from_uid` for `uid` are kept. The
+/// uid comes from the peer, so it has to be a plain name.
+fn staged_path(configs: &RConfig, from_uid: &str, uid: &str) -> Result<PathBuf, RMError> {
+    if uid.is_empty() || !uid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(RMError::new(RErrorCode::PermissionDenied, format!("not valid uid for a part: {}", uid)));
+    }
+
+    Ok(PathBuf::from(configs.parts_path()).join(format!("{}-{}", from_uid, uid)))
+}
+
+fn io_error(error: std::io::Error) -> RMError {
+    RMError::new(RErrorCode::Internal, format!("part not kept: {}", error))
+}
+
+/// Keeps a part received from node `from_uid` until the message it belongs
+/// to comes. Parts come in order, the first one starts the content over.
+pub fn stage(configs: &RConfig, from_uid: &str, part: &RMFilePart) -> Result<(), RMError> {
+    let path = staged_path(configs, from_uid, &part.uid)?;
+
+    if let Err(error) = std::fs::create_dir_all(configs.parts_path()) {
+        return Err(io_error(error));
+    }
+
+    let staged = if part.offset == 0 {
+        File::create(&path)
+    } else {
+        OpenOptions::new().append(true).open(&path)
+    };
+
+    let mut staged = staged.map_err(io_error)?;
+    let received = staged.metadata().map_err(io_error)?.len();
+
+    // A part was lost, the rest can't be used.
+    if received != part.offset {
+        let _ = std::fs::remove_file(&path);
+        return Err(RMError::new(
+            RErrorCode::ChecksumMismatch,
+            format!("part at {} of {}, {} bytes received", part.offset, part.uid, received),
+        ));
+    }
+
+    staged.write_all(part.content.as_slice()).map_err(io_error)
+}
+
+/// The `offset` bytes received from node `from_uid` as parts for `uid`.
+/// They are only taken once.
+pub fn take(configs: &RConfig, from_uid: &str, uid: &str, offset: u64) -> Result<Vec<u8>, RMError> {
+    let path = staged_path(configs, from_uid, uid)?;
+    let content = std::fs::read(&path);
+
+    let _ = std::fs::remove_file(&path);
+
+    if content.is_err() {
+        return Err(RMError::new(RErrorCode::UnknownFile, format!("parts of {} not received", uid)));
+    }
+
+    let content = content.unwrap();
+
+    if content.len() as u64 != offset {
+        return Err(RMError::new(
+            RErrorCode::ChecksumMismatch,
+            format!("{} bytes received as parts of {}, {} sent", content.len(), uid, offset),
+        ));
+    }
+
+    Ok(content)
+}
+
+/// `transfer` with the content sent ahead of it by node `from_uid` put back.
+pub fn take_transfer(configs: &RConfig, from_uid: &str, mut transfer: RMFileTransfer) -> Result<RMFileTransfer, RMError> {
+    if transfer.offset == 0 {
+        return Ok(transfer);
+    }
+
+    let mut content = take(configs, from_uid, &transfer.file.uid, transfer.offset)?;
+
+    content.extend_from_slice(transfer.content.as_slice());
+    transfer.content = content;
+    transfer.offset = 0;
+
+    Ok(transfer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configs(name: &str) -> RConfig {
+        let root = std::env::temp_dir().join(format!("raidx-parts-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        let mut configs = RConfig::get_default(root.to_str().unwrap().to_string());
+        configs.database.path = root.join("db.sqlite").to_str().unwrap().to_string();
+
+        configs
+    }
+
+    fn part(offset: u64, content: &[u8]) -> RMFilePart {
+        RMFilePart {
+            uid: "file-1".to_string(),
+            offset,
+            content: content.to_vec(),
+            compression: None,
+        }
+    }
+
+    #[test]
+    fn parts_in_order_are_put_back_together() {
+        let configs = configs("order");
+
+        stage(&configs, "node", &part(0, b"first ")).unwrap();
+        stage(&configs, "node", &part(6, b"second")).unwrap();
+
+        assert_eq!(take(&configs, "node", "file-1", 12).unwrap(), b"first second");
+
+        // Taken once only.
+        assert!(take(&configs, "node", "file-1", 12).is_err());
+    }
+
+    #[test]
+    fn lost_part_fails_the_content() {
+        let configs = configs("lost");
+
+        stage(&configs, "node", &part(0, b"first ")).unwrap();
+
+        assert!(stage(&configs, "node", &part(12, b"third")).is_err());
+        assert!(take(&configs, "node", "file-1", 17).is_err());
+    }
+
+    #[test]
+    fn parts_are_kept_apart_by_node() {
+        let configs = configs("nodes");
+
+        stage(&configs, "node-a", &part(0, b"from a")).unwrap();
+        stage(&configs, "node-b", &part(0, b"b")).unwrap();
+
+        assert!(take(&configs, "node-b", "file-1", 6).is_err());
+        assert_eq!(take(&configs, "node-a", "file-1", 6).unwrap(), b"from a");
+    }
+
+    #[test]
+    fn uid_outside_the_folder_is_refused() {
+        let configs = configs("uid");
+        let mut escaping = part(0, b"content");
+        escaping.uid = "../../escape".to_string();
+
+        assert_eq!(stage(&configs, "node", &escaping).unwrap_err().code, RErrorCode::PermissionDenied);
+    }
+}
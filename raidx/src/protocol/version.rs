@@ -0,0 +1,69 @@
+use crate::protocol::codec::RCodec;
+use crate::utils::configs::RConfig;
+
+/// Version of the wire protocol spoken by this build. Version 4 sends
+/// contents larger than a frame as `FilePart`s, a version 3 peer refuses
+/// them.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Oldest peer version this build can still talk to. Version 3 signs the
+/// whole handshake transcript, earlier signatures no longer verify.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// Replica placement messages (`FileTransfer`, `ReplicaStored`, `ReplicaRemove`).
+pub const CAP_REPLICAS: u64 = 1 << 0;
+/// Scrubber repairs (`FileRequest`, `FileRepair`).
+pub const CAP_REPAIR: u64 = 1 << 1;
+/// Share key encryption for untrusted nodes is enabled.
+pub const CAP_ENCRYPTION: u64 = 1 << 2;
+/// Frames are encoded as MessagePack once the handshake is done.
+pub const CAP_MSGPACK: u64 = 1 << 3;
+/// File contents may be sent zstd compressed.
+pub const CAP_ZSTD: u64 = 1 << 4;
+
+/// Capabilities advertised to peers on connect.
+pub fn local_capabilities(configs: &RConfig) -> u64 {
+    let mut capabilities = CAP_REPLICAS | CAP_REPAIR;
+
+    if configs.encryption.enabled {
+        capabilities |= CAP_ENCRYPTION;
+    }
+
+    if configs.protocol.codec == RCodec::MessagePack {
+        capabilities |= CAP_MSGPACK;
+    }
+
+    if configs.protocol.compression.enabled {
+        capabilities |= CAP_ZSTD;
+    }
+
+    capabilities
+}
+
+/// What both ends of a connection agreed on during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RSession {
+    pub version: u32,
+    pub capabilities: u64,
+}
+
+impl RSession {
+    /// Settles on the highest version both peers speak and the capabilities
+    /// both advertise. Fails with the peer version when it is too old.
+    pub fn negotiate(local_capabilities: u64, peer_version: u32, peer_capabilities: u64) -> Result<RSession, u32> {
+        let version = peer_version.min(PROTOCOL_VERSION);
+
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(peer_version);
+        }
+
+        Ok(RSession {
+            version,
+            capabilities: local_capabilities & peer_capabilities,
+        })
+    }
+
+    pub fn has(&self, capability: u64) -> bool {
+        self.capabilities & capability == capability
+    }
+}
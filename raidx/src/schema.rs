@@ -1,5 +1,16 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    chunks (id) {
+        id -> Integer,
+        hash -> Text,
+        file -> Text,
+        digest -> Text,
+        position -> BigInt,
+        size -> Integer,
+    }
+}
+
 diesel::table! {
     files (id) {
         id -> Integer,
@@ -13,6 +24,8 @@ diesel::table! {
         created_at -> Integer,
         modified_at -> Integer,
         updated_at -> Integer,
+        digest -> Nullable<Text>,
+        scrubbed_at -> Integer,
     }
 }
 
@@ -44,16 +57,39 @@ diesel::table! {
         host -> Text,
         port -> Integer,
         local -> Bool,
+        weight -> Integer,
+        status -> Text,
+        ssl -> Bool,
+        public_key -> Nullable<Text>,
+        untrusted -> Bool,
+        role -> Text,
+        online -> Bool,
+        last_seen -> Integer,
+    }
+}
+
+diesel::table! {
+    replicas (id) {
+        id -> Integer,
+        file -> Text,
+        node -> Text,
+        status -> Text,
+        created_at -> Integer,
+        updated_at -> Integer,
+        digest -> Nullable<Text>,
     }
 }
 
 diesel::joinable!(files -> nodes (node));
 diesel::joinable!(messages_incoming -> nodes (from));
 diesel::joinable!(messages_outgoing -> nodes (to));
+diesel::joinable!(replicas -> nodes (node));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    chunks,
     files,
     messages_incoming,
     messages_outgoing,
     nodes,
+    replicas,
 );
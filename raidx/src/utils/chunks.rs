@@ -0,0 +1,216 @@
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// No cut point before this many bytes.
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chunk size aimed for, a power of two.
+const AVG_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks are cut here whatever the content.
+const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Harder to match before the average size, easier after, so chunk sizes
+/// stay close to it (FastCDC normalized chunking).
+const MASK_SMALL: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() + 2);
+const MASK_LARGE: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() - 2);
+
+/// Random values mixed in for each byte, fixed so every node cuts the same
+/// content at the same places.
+const GEAR: [u64; 256] = gear();
+
+/// `bits` bits set, taken from the top of the hash which depends on the
+/// last 64 bytes rather than the last few.
+const fn mask(bits: u32) -> u64 {
+    ((1u64 << bits) - 1) << (64 - bits)
+}
+
+const fn gear() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5241_4944_5843_4443;
+    let mut i = 0;
+
+    // splitmix64
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+}
+
+/// A chunk of a file, named by the hash of its content.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RChunkRef {
+    pub hash: String,
+    pub size: u32,
+}
+
+/// Where a chunk was cut in the content it comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RChunkCut {
+    pub offset: usize,
+    pub size: usize,
+    pub hash: String,
+}
+
+impl RChunkCut {
+    pub fn to_ref(&self) -> RChunkRef {
+        RChunkRef {
+            hash: self.hash.clone(),
+            size: self.size as u32,
+        }
+    }
+}
+
+pub fn hash(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+/// Length of the chunk starting at the beginning of `content`.
+fn cut_point(content: &[u8]) -> usize {
+    if content.len() <= MIN_CHUNK_SIZE {
+        return content.len();
+    }
+
+    let end = content.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut fingerprint: u64 = 0;
+    let mut i = MIN_CHUNK_SIZE;
+
+    while i < normal {
+        fingerprint = (fingerprint << 1).wrapping_add(GEAR[content[i] as usize]);
+
+        if fingerprint & MASK_SMALL == 0 {
+            return i;
+        }
+
+        i += 1;
+    }
+
+    while i < end {
+        fingerprint = (fingerprint << 1).wrapping_add(GEAR[content[i] as usize]);
+
+        if fingerprint & MASK_LARGE == 0 {
+            return i;
+        }
+
+        i += 1;
+    }
+
+    end
+}
+
+/// Splits `content` into chunks cut where the content itself says so, so an
+/// insertion only changes the chunks around it.
+pub fn cut(content: &[u8]) -> Vec<RChunkCut> {
+    // Reading a slice doesn't fail.
+    read_cuts(content).unwrap_or_default()
+}
+
+/// Chunks of the content read from `reader`, see [`cut`]. No more than the
+/// largest chunk is held at once.
+pub fn read_cuts<R: Read>(mut reader: R) -> std::io::Result<Vec<RChunkCut>> {
+    let mut chunks = Vec::<RChunkCut>::new();
+    let mut buffer = Vec::<u8>::with_capacity(MAX_CHUNK_SIZE);
+    let mut offset = 0;
+
+    loop {
+        reader.by_ref().take((MAX_CHUNK_SIZE - buffer.len()) as u64).read_to_end(&mut buffer)?;
+
+        if buffer.is_empty() {
+            return Ok(chunks);
+        }
+
+        let size = cut_point(buffer.as_slice());
+
+        chunks.push(RChunkCut {
+            offset,
+            size,
+            hash: hash(&buffer[..size]),
+        });
+
+        buffer.drain(..size);
+        offset += size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Content that doesn't repeat, the same on every run.
+    fn content(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_cover_the_content() {
+        let content = content(2_000_000, 1);
+        let chunks = cut(&content);
+        let mut offset = 0;
+
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.offset, offset);
+            assert!(chunk.size <= MAX_CHUNK_SIZE);
+            assert!(chunk.size >= MIN_CHUNK_SIZE || index == chunks.len() - 1);
+            assert_eq!(chunk.hash, hash(&content[chunk.offset..chunk.offset + chunk.size]));
+
+            offset += chunk.size;
+        }
+
+        assert_eq!(offset, content.len());
+    }
+
+    #[test]
+    fn small_or_empty_content_is_one_chunk_or_none() {
+        assert!(cut(&[]).is_empty());
+        assert_eq!(cut(&content(100, 2)).len(), 1);
+    }
+
+    #[test]
+    fn cut_is_stable_on_insert_before_a_boundary() {
+        let original = content(2_000_000, 3);
+        let chunks = cut(&original);
+
+        assert!(chunks.len() > 6);
+
+        // Bytes inserted at the end of the third chunk, before its boundary.
+        let boundary = chunks[2].offset + chunks[2].size;
+        let inserted = content(10, 4);
+        let mut changed = original.clone();
+        changed.splice(boundary - 1_000..boundary - 1_000, inserted.iter().copied());
+
+        let hashes = |chunks: &[RChunkCut]| chunks.iter().map(|chunk| chunk.hash.clone()).collect::<Vec<String>>();
+        let changed_chunks = cut(&changed);
+
+        assert_eq!(changed_chunks.len(), chunks.len());
+        assert_eq!(hashes(&changed_chunks[..2]), hashes(&chunks[..2]));
+        assert_ne!(changed_chunks[2].hash, chunks[2].hash);
+        assert_eq!(changed_chunks[2].size, chunks[2].size + inserted.len());
+        assert_eq!(hashes(&changed_chunks[3..]), hashes(&chunks[3..]));
+    }
+
+    #[test]
+    fn content_read_in_pieces_is_cut_the_same() {
+        let content = content(1_000_000, 5);
+        let mut reader = std::io::BufReader::with_capacity(1_000, content.as_slice());
+
+        assert_eq!(read_cuts(&mut reader).unwrap(), cut(&content));
+    }
+}
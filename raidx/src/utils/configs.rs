@@ -1,14 +1,20 @@
 use serde::{Serialize, Deserialize};
 
+use crate::models::nodes::{NODE_ROLE_RECEIVE_ONLY, NODE_ROLE_SEND_ONLY, NODE_ROLE_SEND_RECEIVE};
+use crate::protocol::codec::RCodec;
+use crate::peers::transport::RTransportKind;
+
 #[derive(Debug)]
 pub enum ErrorRConfigs {
     Io(std::io::Error),
     SerdeJson(serde_json::Error)
 }
 
+/// The folder is rescanned at start and when the watcher asks for it, a
+/// `timeout` left in older configs is ignored.
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct RConfigSynchronizer {
-    pub timeout: usize
+    
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
@@ -16,16 +22,250 @@ pub struct RConfigWatcher {
     
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RConfigPlacement {
+    pub replicas: usize
+}
+
+impl Default for RConfigPlacement {
+    fn default() -> Self {
+        return RConfigPlacement { replicas: 2 };
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RConfigRebalancer {
+    pub timeout: usize,
+    pub max_transfers: usize,
+    /// Seconds a replica may stay pending before its transfer is taken as
+    /// lost and scheduled again.
+    #[serde(default = "RConfigRebalancer::default_pending_timeout")]
+    pub pending_timeout: u64
+}
+
+impl RConfigRebalancer {
+    pub fn default_pending_timeout() -> u64 {
+        return 600;
+    }
+}
+
+impl Default for RConfigRebalancer {
+    fn default() -> Self {
+        return RConfigRebalancer {
+            timeout: 30,
+            max_transfers: 16,
+            pending_timeout: RConfigRebalancer::default_pending_timeout()
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RConfigScrubber {
+    pub timeout: usize,
+    pub max_files: usize,
+    pub max_bytes_per_second: u64,
+    #[serde(default = "RConfigScrubber::default_repair_timeout")]
+    pub repair_timeout: u64
+}
+
+impl RConfigScrubber {
+    pub fn default_repair_timeout() -> u64 {
+        return 60;
+    }
+}
+
+impl Default for RConfigScrubber {
+    fn default() -> Self {
+        return RConfigScrubber {
+            timeout: 3600,
+            max_files: 100,
+            max_bytes_per_second: 10 * 1024 * 1024,
+            repair_timeout: RConfigScrubber::default_repair_timeout()
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RConfigLimits {
+    pub max_frame_size: usize,
+    pub max_messages_per_second: u64,
+    pub max_incoming: i64,
+    pub ban_seconds: u64
+}
+
+impl Default for RConfigLimits {
+    fn default() -> Self {
+        return RConfigLimits {
+            max_frame_size: 256 * 1024 * 1024,
+            max_messages_per_second: 200,
+            max_incoming: 10000,
+            ban_seconds: 600
+        };
+    }
+}
+
+/// Outgoing connections to the other nodes, in seconds.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RConfigConnections {
+    pub ping_interval: u64,
+    /// A node silent for this long is offline and is dialed again.
+    pub ping_timeout: u64,
+    /// Wait after the first failed attempt, doubled after each next one.
+    pub min_backoff: u64,
+    pub max_backoff: u64
+}
+
+impl Default for RConfigConnections {
+    fn default() -> Self {
+        return RConfigConnections { ping_interval: 15, ping_timeout: 60, min_backoff: 1, max_backoff: 300 };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RConfigTls {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RConfigIdentity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RConfigEncryption {
+    pub enabled: bool,
+    #[serde(default)]
+    pub names: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RConfigCompression {
+    pub enabled: bool,
+    /// zstd level, 1 (fast) to 22 (small).
+    pub level: i32,
+    /// Smaller files are sent as they are.
+    pub min_size: usize,
+    /// Extensions of files already compressed, never worth another pass.
+    pub skip_extensions: Vec<String>
+}
+
+impl Default for RConfigCompression {
+    fn default() -> Self {
+        let skip_extensions = [
+            "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "m4a", "mkv", "mov",
+            "mp3", "mp4", "ogg", "pdf", "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+        ];
+
+        return RConfigCompression {
+            enabled: true,
+            level: 3,
+            min_size: 512,
+            skip_extensions: skip_extensions.iter().map(|extension| extension.to_string()).collect()
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RConfigDelta {
+    pub enabled: bool,
+    /// Smaller files are sent whole when they change.
+    pub min_size: usize,
+    /// Seconds to wait for each step before sending the whole file.
+    pub timeout: u64
+}
+
+impl Default for RConfigDelta {
+    fn default() -> Self {
+        return RConfigDelta { enabled: true, min_size: 64 * 1024, timeout: 60 };
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RConfigChunking {
+    pub enabled: bool,
+    /// Smaller files are sent whole.
+    pub min_size: usize,
+    /// Seconds to wait for each step before sending the whole file.
+    pub timeout: u64
+}
+
+impl Default for RConfigChunking {
+    fn default() -> Self {
+        return RConfigChunking { enabled: true, min_size: 256 * 1024, timeout: 60 };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RConfigProtocol {
+    /// `json` keeps the frames readable, for debugging only.
+    #[serde(default)]
+    pub codec: RCodec,
+    #[serde(default)]
+    pub compression: RConfigCompression,
+    #[serde(default)]
+    pub delta: RConfigDelta,
+    #[serde(default)]
+    pub chunking: RConfigChunking
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RConfigDatabase {
     pub path: String    
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RConfigRole {
+    SendOnly,
+    ReceiveOnly,
+    #[default]
+    SendReceive
+}
+
+impl RConfigRole {
+    /// Value stored in the `role` column of the `nodes` table.
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            RConfigRole::SendOnly => NODE_ROLE_SEND_ONLY,
+            RConfigRole::ReceiveOnly => NODE_ROLE_RECEIVE_ONLY,
+            RConfigRole::SendReceive => NODE_ROLE_SEND_RECEIVE,
+        };
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RConfigNode {
     pub host: String,
     pub port: usize,
-    pub ssl: bool
+    pub ssl: bool,
+    #[serde(default = "RConfigNode::default_weight")]
+    pub weight: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub untrusted: bool,
+    #[serde(default)]
+    pub role: RConfigRole,
+    #[serde(default)]
+    pub transport: RTransportKind
+}
+
+impl RConfigNode {
+    pub fn default_weight() -> u32 {
+        return 1;
+    }
+
+    pub fn is(&self, host: &String, port: i32) -> bool {
+        return self.host == *host && self.port as i32 == port;
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -35,6 +275,26 @@ pub struct RConfig {
     pub synchronizer: RConfigSynchronizer,
     pub watcher: RConfigWatcher,
     pub database: RConfigDatabase,
+    #[serde(default)]
+    pub placement: RConfigPlacement,
+    #[serde(default)]
+    pub rebalancer: RConfigRebalancer,
+    #[serde(default)]
+    pub scrubber: RConfigScrubber,
+    #[serde(default)]
+    pub limits: RConfigLimits,
+    #[serde(default)]
+    pub connections: RConfigConnections,
+    #[serde(default)]
+    pub tls: RConfigTls,
+    #[serde(default)]
+    pub identity: RConfigIdentity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster_secret: Option<String>,
+    #[serde(default)]
+    pub encryption: RConfigEncryption,
+    #[serde(default)]
+    pub protocol: RConfigProtocol,
     pub nodes: Vec<RConfigNode>
 }
 
@@ -42,12 +302,22 @@ impl RConfig {
     pub fn get_default(folder_path: String) -> RConfig {
         return RConfig{
             folder_path: folder_path,
-            server: RConfigNode { host: "0.0.0.0".to_string(), port: 4000, ssl: false },
-            synchronizer: RConfigSynchronizer { timeout: 2 },
+            server: RConfigNode { host: "0.0.0.0".to_string(), port: 4000, ssl: false, weight: 1, certificate: None, key: None, public_key: None, untrusted: false, role: RConfigRole::SendReceive, transport: RTransportKind::WebSocket },
+            synchronizer: RConfigSynchronizer {  },
             watcher: RConfigWatcher {  },
             database: RConfigDatabase{
                 path: "/home/roothunter/Dev/raidx/config/raidx.database.db".to_string()
             },
+            placement: RConfigPlacement::default(),
+            rebalancer: RConfigRebalancer::default(),
+            scrubber: RConfigScrubber::default(),
+            limits: RConfigLimits::default(),
+            connections: RConfigConnections::default(),
+            tls: RConfigTls::default(),
+            identity: RConfigIdentity::default(),
+            cluster_secret: None,
+            encryption: RConfigEncryption::default(),
+            protocol: RConfigProtocol::default(),
             nodes: Vec::new()
           };
     }
@@ -116,4 +386,31 @@ impl RConfig {
     pub fn get_info(self) -> String {
         return serde_json::to_string_pretty(&self).unwrap();
     }
+
+    /// Path of the node private key, next to the database unless configured.
+    pub fn identity_key_path(&self) -> String {
+        return match self.identity.key_path.clone() {
+            Some(path) => path,
+            None => format!("{}.key", self.database.path),
+        };
+    }
+
+    /// Path of the share key, next to the database unless configured. The
+    /// same key has to be copied to every trusted node.
+    pub fn share_key_path(&self) -> String {
+        return match self.encryption.key_path.clone() {
+            Some(path) => path,
+            None => format!("{}.share.key", self.database.path),
+        };
+    }
+
+    /// Folder keeping the parts of contents received ahead of their
+    /// message, next to the database.
+    pub fn parts_path(&self) -> String {
+        return format!("{}.parts", self.database.path);
+    }
+
+    pub fn get_node(&self, host: &String, port: i32) -> Option<&RConfigNode> {
+        return self.nodes.iter().find(|node| node.is(host, port));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,252 @@
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use log::info;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::protocol::message::RMFileTransfer;
+use crate::utils::configs::RConfig;
+
+const NONCE_SIZE: usize = 24;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum RCryptoError {
+    Io(std::io::Error),
+    InvalidKey,
+    Encrypt,
+    Decrypt,
+}
+
+/// Symmetric key of the share, used to encrypt what is stored on untrusted
+/// nodes.
+///
+/// Encryption is deterministic: the nonce is derived from the path and the
+/// content, so the same file always seals to the same bytes. An untrusted
+/// node can then check and repair its copy by digest like any other replica.
+pub struct RShareKey {
+    key: [u8; 32],
+}
+
+impl RShareKey {
+    /// Loads the share key when encryption is enabled, generating it on first
+    /// start.
+    pub fn load_or_create(configs: &RConfig) -> Result<Option<RShareKey>, RCryptoError> {
+        if !configs.encryption.enabled {
+            return Ok(None);
+        }
+
+        let path = configs.share_key_path();
+
+        if std::path::Path::new(path.as_str()).exists() {
+            let content = std::fs::read_to_string(path.as_str());
+
+            let content = content.map_err(RCryptoError::Io)?;
+
+            let key: Option<[u8; 32]> = hex::decode(content.trim()).ok().and_then(|bytes| bytes.try_into().ok());
+
+            if key.is_none() {
+                return Err(RCryptoError::InvalidKey);
+            }
+
+            return Ok(Some(RShareKey { key: key.unwrap() }));
+        }
+
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path.as_str());
+
+        let mut file = file.map_err(RCryptoError::Io)?;
+
+        if let Err(e) = file.write_all(hex::encode(key).as_bytes()) {
+            return Err(RCryptoError::Io(e));
+        }
+
+        info!("share key generated: {}, copy it to every trusted node", path);
+
+        Ok(Some(RShareKey { key }))
+    }
+
+    fn derive(&self, purpose: &str, data: &[&[u8]]) -> [u8; 32] {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(purpose.as_bytes());
+
+        for part in data {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+
+        mac.finalize().into_bytes().into()
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.derive("raidx-content-key-v1", &[]).into())
+    }
+
+    /// Encrypts a file content, the nonce is prepended to the ciphertext.
+    pub fn encrypt(&self, path: &String, content: &[u8]) -> Result<Vec<u8>, RCryptoError> {
+        let nonce = self.derive("raidx-nonce-v1", &[path.as_bytes(), content]);
+        let nonce = XNonce::from_slice(&nonce[..NONCE_SIZE]);
+
+        let ciphertext = self.cipher().encrypt(nonce, content);
+
+        if ciphertext.is_err() {
+            return Err(RCryptoError::Encrypt);
+        }
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(ciphertext.unwrap().as_slice());
+
+        Ok(sealed)
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, RCryptoError> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(RCryptoError::Decrypt);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+
+        self
+            .cipher()
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| RCryptoError::Decrypt)
+    }
+
+    /// Name a file is stored under on untrusted nodes, it can't be reversed.
+    pub fn encrypt_name(&self, path: &String) -> String {
+        format!("{}.rxe", hex::encode(self.derive("raidx-name-v1", &[path.as_bytes()])))
+    }
+
+    /// Path a file is known by on an untrusted node.
+    pub fn stored_path(&self, configs: &RConfig, path: &String) -> String {
+        if configs.encryption.names {
+            return self.encrypt_name(path);
+        }
+
+        path.clone()
+    }
+
+    /// Prepares a transfer for an untrusted node: the content is encrypted
+    /// and, with `encryption.names`, the path too. Metadata that would leak
+    /// the plaintext is dropped.
+    pub fn seal_transfer(&self, configs: &RConfig, transfer: RMFileTransfer) -> Result<RMFileTransfer, RCryptoError> {
+        let content = self.encrypt(&transfer.path, transfer.content.as_slice())?;
+        let path = self.stored_path(configs, &transfer.path);
+
+        let mut file = transfer.file;
+        file.size = content.len() as i32;
+        file.digest = None;
+
+        if configs.encryption.names {
+            file.folder = String::new();
+            file.filename = path.clone();
+        }
+
+        Ok(RMFileTransfer { file, path, content, compression: None, offset: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::files::RFile;
+
+    fn share_key() -> RShareKey {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+
+        RShareKey { key }
+    }
+
+    fn transfer(content: &[u8]) -> RMFileTransfer {
+        let file = RFile {
+            id: 0,
+            uid: "0A1B2C".to_string(),
+            node: "node".to_string(),
+            folder: "/data/folder".to_string(),
+            filename: "file.bin".to_string(),
+            size: content.len() as i32,
+            status: "READY".to_string(),
+            sync: false,
+            created_at: 0,
+            modified_at: 0,
+            updated_at: 0,
+            digest: Some(RFile::calc_digest_from_slice(content)),
+            scrubbed_at: 0,
+        };
+
+        RMFileTransfer { file, path: "folder/file.bin".to_string(), content: content.to_vec(), compression: None, offset: 0 }
+    }
+
+    #[test]
+    fn content_round_trips() {
+        let share_key = share_key();
+        let path = "folder/file.bin".to_string();
+
+        let sealed = share_key.encrypt(&path, b"secret content").unwrap();
+
+        assert_ne!(&sealed[NONCE_SIZE..], b"secret content");
+        assert_eq!(share_key.decrypt(sealed.as_slice()).unwrap(), b"secret content");
+    }
+
+    #[test]
+    fn sealed_transfer_round_trips_without_plaintext_metadata() {
+        let share_key = share_key();
+        let mut configs = RConfig::get_default("/data".to_string());
+        configs.encryption.names = true;
+
+        let sealed = share_key.seal_transfer(&configs, transfer(b"secret content")).unwrap();
+
+        assert_eq!(sealed.path, share_key.encrypt_name(&"folder/file.bin".to_string()));
+        assert_eq!(sealed.file.filename, sealed.path);
+        assert!(sealed.file.folder.is_empty());
+        assert!(sealed.file.digest.is_none());
+        assert_eq!(sealed.file.size as usize, sealed.content.len());
+        assert_eq!(share_key.decrypt(sealed.content.as_slice()).unwrap(), b"secret content");
+    }
+
+    #[test]
+    fn tampered_content_is_refused() {
+        let share_key = share_key();
+        let sealed = share_key.encrypt(&"file.bin".to_string(), b"secret content").unwrap();
+
+        // In the nonce, the ciphertext and the tag at its end.
+        for index in [0, NONCE_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+
+            assert!(matches!(share_key.decrypt(tampered.as_slice()), Err(RCryptoError::Decrypt)));
+        }
+
+        assert!(matches!(share_key.decrypt(&sealed[..NONCE_SIZE - 1]), Err(RCryptoError::Decrypt)));
+    }
+
+    #[test]
+    fn another_share_key_is_refused() {
+        let sealed = share_key().encrypt(&"file.bin".to_string(), b"secret content").unwrap();
+
+        assert!(matches!(share_key().decrypt(sealed.as_slice()), Err(RCryptoError::Decrypt)));
+    }
+
+    #[test]
+    fn encryption_is_deterministic() {
+        let share_key = share_key();
+        let path = "folder/file.bin".to_string();
+
+        assert_eq!(share_key.encrypt_name(&path), share_key.encrypt_name(&path));
+        assert_ne!(share_key.encrypt_name(&path), share_key.encrypt_name(&"folder/other.bin".to_string()));
+        assert_eq!(share_key.encrypt(&path, b"content").unwrap(), share_key.encrypt(&path, b"content").unwrap());
+    }
+}
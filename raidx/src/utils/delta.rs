@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Smallest block compared, so small files don't send a signature per line.
+const MIN_BLOCK_SIZE: usize = 1024;
+
+/// Largest block compared, so a few changed bytes don't resend too much.
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Bytes of the SHA-256 kept to confirm a rolling checksum match.
+const STRONG_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RDeltaError {
+    BlockOutOfRange(u32),
+    BlockSizeMismatch(usize),
+    TooLarge(usize),
+    Read(std::io::ErrorKind),
+}
+
+impl std::fmt::Display for RDeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RDeltaError::BlockOutOfRange(index) => write!(f, "block {} not in the current version", index),
+            RDeltaError::BlockSizeMismatch(size) => write!(f, "delta made for {} bytes blocks, the current version changed", size),
+            RDeltaError::TooLarge(size) => write!(f, "delta larger than {} bytes", size),
+            RDeltaError::Read(kind) => write!(f, "content not read: {}", kind),
+        }
+    }
+}
+
+/// Checksums of one block of the version held by the receiver.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RBlockSignature {
+    pub weak: u32,
+    #[serde(with = "serde_bytes")]
+    pub strong: Vec<u8>,
+}
+
+/// One step rebuilding the new version from the old one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RDeltaOp {
+    /// `count` blocks of the old version, starting from block `index`.
+    Copy { index: u32, count: u32 },
+    /// Bytes not found in the old version.
+    Data(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+/// Adler-32 like checksum, cheap to slide one byte forward.
+#[derive(Debug, Clone, Copy)]
+struct RRollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RRollingChecksum {
+    fn new(block: &[u8]) -> RRollingChecksum {
+        let len = block.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+
+        RRollingChecksum { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_checksum(block: &[u8]) -> Vec<u8> {
+    Sha256::digest(block)[..STRONG_SIZE].to_vec()
+}
+
+/// Block size for a version of `len` bytes, about its square root.
+pub fn block_size(len: usize) -> usize {
+    let size = (len as f64).sqrt() as usize;
+    size.next_power_of_two().clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Signatures of every full block read from `reader`. A shorter last block
+/// is never matched, so it is left out.
+pub fn signatures<R: Read>(mut reader: R, block_size: usize) -> std::io::Result<Vec<RBlockSignature>> {
+    let mut signatures = Vec::<RBlockSignature>::new();
+    let mut block = Vec::<u8>::with_capacity(block_size);
+
+    if block_size == 0 {
+        return Ok(signatures);
+    }
+
+    loop {
+        block.clear();
+        reader.by_ref().take(block_size as u64).read_to_end(&mut block)?;
+
+        if block.len() < block_size {
+            return Ok(signatures);
+        }
+
+        signatures.push(RBlockSignature {
+            weak: RRollingChecksum::new(&block).digest(),
+            strong: strong_checksum(&block),
+        });
+    }
+}
+
+/// Bytes `ops` take once encoded: their data, and about this much for
+/// each of them.
+const OP_SIZE: usize = 32;
+
+pub fn encoded_size(ops: &[RDeltaOp]) -> usize {
+    literal_size(ops) + ops.len() * OP_SIZE
+}
+
+/// Appends a copy of `block`, and returns the bytes it adds once encoded.
+fn push_copy(ops: &mut Vec<RDeltaOp>, block: u32) -> usize {
+    if let Some(RDeltaOp::Copy { index, count }) = ops.last_mut() {
+        if *index + *count == block {
+            *count += 1;
+            return 0;
+        }
+    }
+
+    ops.push(RDeltaOp::Copy { index: block, count: 1 });
+    OP_SIZE
+}
+
+/// Appends `data`, and returns the bytes it adds once encoded.
+fn push_data(ops: &mut Vec<RDeltaOp>, data: &[u8]) -> usize {
+    if data.is_empty() {
+        return 0;
+    }
+
+    if let Some(RDeltaOp::Data(last)) = ops.last_mut() {
+        last.extend_from_slice(data);
+        return data.len();
+    }
+
+    ops.push(RDeltaOp::Data(data.to_vec()));
+    data.len() + OP_SIZE
+}
+
+/// Bytes read from `reader` at once.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Operations rebuilding the content read from `reader` from the version
+/// `signatures` were computed on. Only the window compared and the bytes
+/// not matched yet are held, and it stops once the ops would take more than
+/// `max_size` bytes.
+pub fn delta<R: Read>(mut reader: R, signatures: &[RBlockSignature], block_size: usize, max_size: usize) -> Result<Vec<RDeltaOp>, RDeltaError> {
+    let mut ops = Vec::<RDeltaOp>::new();
+    let mut size = 0;
+
+    let mut read = |buffer: &mut Vec<u8>, len: usize| -> Result<(), RDeltaError> {
+        if buffer.len() < len {
+            let missing = (len - buffer.len()).max(READ_SIZE);
+            reader.by_ref().take(missing as u64).read_to_end(buffer).map_err(|error| RDeltaError::Read(error.kind()))?;
+        }
+
+        Ok(())
+    };
+
+    if block_size > MAX_BLOCK_SIZE {
+        return Err(RDeltaError::BlockSizeMismatch(block_size));
+    }
+
+    // Bytes from the first one not matched yet.
+    let mut buffer = Vec::<u8>::new();
+
+    if block_size == 0 || signatures.is_empty() {
+        loop {
+            read(&mut buffer, READ_SIZE)?;
+
+            if buffer.is_empty() {
+                return Ok(ops);
+            }
+
+            size += push_data(&mut ops, buffer.as_slice());
+            buffer.clear();
+
+            if size > max_size {
+                return Err(RDeltaError::TooLarge(max_size));
+            }
+        }
+    }
+
+    let mut blocks = HashMap::<u32, Vec<u32>>::new();
+
+    for (index, signature) in signatures.iter().enumerate() {
+        blocks.entry(signature.weak).or_default().push(index as u32);
+    }
+
+    // Start of the window compared in `buffer`.
+    let mut pos = 0;
+    let mut checksum: Option<RRollingChecksum> = None;
+
+    loop {
+        // One byte past the window, to roll into.
+        read(&mut buffer, pos + block_size + 1)?;
+
+        if buffer.len() < pos + block_size {
+            break;
+        }
+
+        let window = &buffer[pos..pos + block_size];
+        let mut rolling = checksum.unwrap_or_else(|| RRollingChecksum::new(window));
+
+        let matched = blocks.get(&rolling.digest()).and_then(|candidates| {
+            let strong = strong_checksum(window);
+            candidates.iter().find(|index| signatures[**index as usize].strong == strong).copied()
+        });
+
+        if let Some(index) = matched {
+            size += push_data(&mut ops, &buffer[..pos]);
+            size += push_copy(&mut ops, index);
+
+            buffer.drain(..pos + block_size);
+            pos = 0;
+            checksum = None;
+        } else {
+            if buffer.len() == pos + block_size {
+                break;
+            }
+
+            rolling.roll(buffer[pos], buffer[pos + block_size]);
+            checksum = Some(rolling);
+            pos += 1;
+
+            // Bytes left behind the window can't match anymore.
+            if pos >= READ_SIZE {
+                size += push_data(&mut ops, &buffer[..pos]);
+                buffer.drain(..pos);
+                pos = 0;
+            }
+        }
+
+        if size > max_size {
+            return Err(RDeltaError::TooLarge(max_size));
+        }
+    }
+
+    size += push_data(&mut ops, buffer.as_slice());
+
+    if size > max_size {
+        return Err(RDeltaError::TooLarge(max_size));
+    }
+
+    Ok(ops)
+}
+
+/// Rebuilds the new version from `base`, the old one. `block_size` is the
+/// one the signatures were sent with.
+pub fn apply(base: &[u8], ops: &[RDeltaOp], block_size: usize) -> Result<Vec<u8>, RDeltaError> {
+    if block_size != self::block_size(base.len()) {
+        return Err(RDeltaError::BlockSizeMismatch(block_size));
+    }
+
+    let mut content = Vec::<u8>::with_capacity(base.len());
+
+    for op in ops {
+        match op {
+            RDeltaOp::Copy { index, count } => {
+                let start = *index as usize * block_size;
+                let end = start + *count as usize * block_size;
+
+                if end > base.len() {
+                    return Err(RDeltaError::BlockOutOfRange(index.saturating_add(*count)));
+                }
+
+                content.extend_from_slice(&base[start..end]);
+            }
+            RDeltaOp::Data(data) => content.extend_from_slice(data),
+        }
+    }
+
+    Ok(content)
+}
+
+/// Bytes of the new version actually carried by `ops`.
+pub fn literal_size(ops: &[RDeltaOp]) -> usize {
+    ops
+        .iter()
+        .map(|op| match op {
+            RDeltaOp::Data(data) => data.len(),
+            RDeltaOp::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Content that doesn't repeat, the same on every run.
+    fn content(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// What the receiver rebuilds from `base` with the delta to `new`.
+    fn round_trip(base: &[u8], new: &[u8]) -> (Vec<u8>, Vec<RDeltaOp>) {
+        let block_size = block_size(base.len());
+        let ops = delta(new, signatures(base, block_size).unwrap().as_slice(), block_size, usize::MAX).unwrap();
+
+        (apply(base, ops.as_slice(), block_size).unwrap(), ops)
+    }
+
+    #[test]
+    fn unchanged_content_is_copied() {
+        let base = content(200_000, 1);
+        let (rebuilt, ops) = round_trip(&base, &base);
+
+        assert_eq!(rebuilt, base);
+        assert!(literal_size(ops.as_slice()) < block_size(base.len()));
+    }
+
+    #[test]
+    fn insert_round_trips() {
+        let base = content(200_000, 2);
+        let mut new = base.clone();
+        new.splice(70_000..70_000, content(300, 3));
+
+        let (rebuilt, ops) = round_trip(&base, &new);
+
+        assert_eq!(rebuilt, new);
+        assert!(literal_size(ops.as_slice()) < 300 + 2 * block_size(base.len()));
+    }
+
+    #[test]
+    fn delete_round_trips() {
+        let base = content(200_000, 4);
+        let mut new = base.clone();
+        new.drain(50_000..53_000);
+
+        let (rebuilt, ops) = round_trip(&base, &new);
+
+        assert_eq!(rebuilt, new);
+        assert!(literal_size(ops.as_slice()) < 2 * block_size(base.len()));
+    }
+
+    #[test]
+    fn append_and_prepend_round_trip() {
+        let base = content(100_000, 5);
+        let new = [content(10, 6), base.clone(), content(5_000, 7)].concat();
+
+        assert_eq!(round_trip(&base, &new).0, new);
+    }
+
+    #[test]
+    fn empty_content_round_trips() {
+        let base = content(100_000, 8);
+
+        assert_eq!(round_trip(&base, &[]).0, Vec::<u8>::new());
+        assert_eq!(round_trip(&[], &base).0, base);
+        assert_eq!(round_trip(&[], &[]).0, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn content_shorter_than_a_block_round_trips() {
+        let base = content(100, 9);
+        let new = content(200, 10);
+
+        assert!(signatures(base.as_slice(), block_size(base.len())).unwrap().is_empty());
+        assert_eq!(round_trip(&base, &new).0, new);
+
+        let base = content(100_000, 11);
+        let new = base[..100].to_vec();
+
+        assert_eq!(round_trip(&base, &new).0, new);
+    }
+
+    #[test]
+    fn apply_refuses_stale_deltas() {
+        let base = content(100_000, 12);
+        let block_size = block_size(base.len());
+        let ops = vec![RDeltaOp::Copy { index: 1_000, count: 1 }];
+
+        assert_eq!(apply(&base, &[], block_size * 2), Err(RDeltaError::BlockSizeMismatch(block_size * 2)));
+        assert_eq!(apply(&base, ops.as_slice(), block_size), Err(RDeltaError::BlockOutOfRange(1_001)));
+    }
+
+    /// Hands out a few bytes at a time, as a file being read may.
+    struct RTrickle<'a>(&'a [u8]);
+
+    impl Read for RTrickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(7);
+
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn content_read_in_pieces_gives_the_same_delta() {
+        let base = content(300_000, 13);
+        let mut new = base.clone();
+        new.splice(150_000..150_100, content(1_000, 14));
+
+        let block_size = block_size(base.len());
+        let signatures = signatures(RTrickle(&base), block_size).unwrap();
+
+        assert_eq!(signatures, super::signatures(base.as_slice(), block_size).unwrap());
+
+        let ops = delta(RTrickle(&new), signatures.as_slice(), block_size, usize::MAX).unwrap();
+
+        assert_eq!(ops, round_trip(&base, &new).1);
+        assert_eq!(apply(&base, ops.as_slice(), block_size).unwrap(), new);
+    }
+
+    #[test]
+    fn delta_larger_than_allowed_is_refused() {
+        let base = content(200_000, 15);
+        let new = content(200_000, 16);
+        let block_size = block_size(base.len());
+        let signatures = signatures(base.as_slice(), block_size).unwrap();
+
+        assert_eq!(delta(new.as_slice(), signatures.as_slice(), block_size, 100_000), Err(RDeltaError::TooLarge(100_000)));
+
+        // A small change fits, with room for its ops.
+        let mut changed = base.clone();
+        changed[100_000] ^= 1;
+
+        let ops = delta(changed.as_slice(), signatures.as_slice(), block_size, 10_000).unwrap();
+
+        assert!(encoded_size(ops.as_slice()) <= 10_000);
+        assert_eq!(apply(&base, ops.as_slice(), block_size).unwrap(), changed);
+    }
+}
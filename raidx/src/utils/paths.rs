@@ -0,0 +1,228 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Reasons an inbound path is refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RPathError {
+    Empty,
+    NulByte,
+    Absolute,
+    ParentDir,
+    NotAFileName,
+    SymlinkEscape,
+    RootNotFound,
+}
+
+impl std::fmt::Display for RPathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RPathError::Empty => write!(f, "empty path"),
+            RPathError::NulByte => write!(f, "path contains a NUL byte"),
+            RPathError::Absolute => write!(f, "absolute path"),
+            RPathError::ParentDir => write!(f, "path contains a `..` component"),
+            RPathError::NotAFileName => write!(f, "file name is not a single path component"),
+            RPathError::SymlinkEscape => write!(f, "path leaves the shared folder through a symlink"),
+            RPathError::RootNotFound => write!(f, "shared folder not found"),
+        }
+    }
+}
+
+/// Lexical checks on a path received from a peer, relative to the shared
+/// folder.
+pub fn validate_relative(path: &str) -> Result<(), RPathError> {
+    if path.is_empty() {
+        return Err(RPathError::Empty);
+    }
+
+    if path.contains('\0') {
+        return Err(RPathError::NulByte);
+    }
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => (),
+            Component::ParentDir => return Err(RPathError::ParentDir),
+            Component::RootDir | Component::Prefix(_) => return Err(RPathError::Absolute),
+        }
+    }
+
+    Ok(())
+}
+
+/// Joins a path received from a peer to the shared folder.
+///
+/// Besides the lexical checks, the deepest part of the result that already
+/// exists must still be inside the folder once symlinks are resolved, so a
+/// link planted in the folder can't redirect a write elsewhere. The result
+/// is joined to `root` as configured, the way the files are recorded.
+pub fn resolve(root: &str, path: &str) -> Result<PathBuf, RPathError> {
+    validate_relative(path)?;
+
+    let canonical = Path::new(root).canonicalize();
+
+    if canonical.is_err() {
+        return Err(RPathError::RootNotFound);
+    }
+
+    let canonical = canonical.unwrap();
+    let joined = canonical.join(path);
+
+    let mut existing = Some(joined.as_path());
+
+    while let Some(candidate) = existing {
+        if candidate.symlink_metadata().is_ok() {
+            break;
+        }
+
+        existing = candidate.parent();
+    }
+
+    if let Some(existing) = existing {
+        // A dangling link can't be canonicalized and is refused as well.
+        match existing.canonicalize() {
+            Ok(resolved) if resolved.starts_with(&canonical) => (),
+            _ => return Err(RPathError::SymlinkEscape),
+        }
+    }
+
+    Ok(Path::new(root).join(path))
+}
+
+/// Checks the `folder`/`filename` pair of a file described by a peer. The
+/// folder is an absolute path on the peer, the file name a single component.
+pub fn validate_remote_file(folder: &str, filename: &str) -> Result<(), RPathError> {
+    if folder.contains('\0') || filename.contains('\0') {
+        return Err(RPathError::NulByte);
+    }
+
+    if Path::new(folder).components().any(|component| component == Component::ParentDir) {
+        return Err(RPathError::ParentDir);
+    }
+
+    let mut components = Path::new(filename).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        (None, _) => Err(RPathError::Empty),
+        _ => Err(RPathError::NotAFileName),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty shared folder of a test, with a sibling folder outside of it.
+    fn folders(name: &str) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("raidx-paths-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+
+        let root = base.join("root");
+        let outside = base.join("outside");
+
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+
+        (root, outside)
+    }
+
+    #[test]
+    fn relative_paths_are_accepted() {
+        assert_eq!(validate_relative("a.txt"), Ok(()));
+        assert_eq!(validate_relative("dir/a.txt"), Ok(()));
+        assert_eq!(validate_relative("./dir/a.txt"), Ok(()));
+    }
+
+    #[test]
+    fn relative_refuses_traversal() {
+        assert_eq!(validate_relative(".."), Err(RPathError::ParentDir));
+        assert_eq!(validate_relative("../a.txt"), Err(RPathError::ParentDir));
+        assert_eq!(validate_relative("dir/../../a.txt"), Err(RPathError::ParentDir));
+    }
+
+    #[test]
+    fn relative_refuses_absolute_paths() {
+        assert_eq!(validate_relative("/etc/passwd"), Err(RPathError::Absolute));
+        assert_eq!(validate_relative("/"), Err(RPathError::Absolute));
+    }
+
+    #[test]
+    fn relative_refuses_nul_bytes() {
+        assert_eq!(validate_relative("a\0.txt"), Err(RPathError::NulByte));
+    }
+
+    #[test]
+    fn relative_refuses_empty_paths() {
+        assert_eq!(validate_relative(""), Err(RPathError::Empty));
+    }
+
+    #[test]
+    fn relative_ignores_empty_components() {
+        assert_eq!(validate_relative("dir//a.txt"), Ok(()));
+        assert_eq!(validate_relative("dir//../a.txt"), Err(RPathError::ParentDir));
+    }
+
+    #[test]
+    fn resolve_joins_to_the_root_as_configured() {
+        let (root, _) = folders("join");
+        let configured = root.to_str().unwrap().to_string();
+
+        assert_eq!(resolve(&configured, "dir/a.txt"), Ok(root.join("dir/a.txt")));
+        assert_eq!(resolve(&configured, "../a.txt"), Err(RPathError::ParentDir));
+        assert_eq!(resolve(&configured, "/etc/passwd"), Err(RPathError::Absolute));
+        assert_eq!(resolve(&configured, "a\0.txt"), Err(RPathError::NulByte));
+        assert_eq!(resolve(&configured, ""), Err(RPathError::Empty));
+    }
+
+    #[test]
+    fn resolve_refuses_missing_root() {
+        let (root, _) = folders("missing");
+        let missing = root.join("missing").to_str().unwrap().to_string();
+
+        assert_eq!(resolve(&missing, "a.txt"), Err(RPathError::RootNotFound));
+    }
+
+    #[test]
+    fn resolve_refuses_symlink_escape() {
+        let (root, outside) = folders("symlink");
+        let configured = root.to_str().unwrap().to_string();
+
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("target.txt"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(root.join("inner"), root.join("inner-link")).unwrap();
+        std::fs::create_dir_all(root.join("inner")).unwrap();
+
+        assert_eq!(resolve(&configured, "link/a.txt"), Err(RPathError::SymlinkEscape));
+        assert_eq!(resolve(&configured, "link"), Err(RPathError::SymlinkEscape));
+        assert_eq!(resolve(&configured, "dangling"), Err(RPathError::SymlinkEscape));
+        assert_eq!(resolve(&configured, "inner-link/a.txt"), Ok(root.join("inner-link/a.txt")));
+    }
+
+    #[test]
+    fn remote_file_accepts_a_single_file_name() {
+        assert_eq!(validate_remote_file("/home/peer/raidx/dir", "a.txt"), Ok(()));
+    }
+
+    #[test]
+    fn remote_file_refuses_traversal() {
+        assert_eq!(validate_remote_file("/home/peer/../etc", "a.txt"), Err(RPathError::ParentDir));
+        assert_eq!(validate_remote_file("/home/peer", ".."), Err(RPathError::NotAFileName));
+    }
+
+    #[test]
+    fn remote_file_refuses_paths_as_file_name() {
+        assert_eq!(validate_remote_file("/home/peer", "dir/a.txt"), Err(RPathError::NotAFileName));
+        assert_eq!(validate_remote_file("/home/peer", "/etc/passwd"), Err(RPathError::NotAFileName));
+    }
+
+    #[test]
+    fn remote_file_refuses_nul_bytes() {
+        assert_eq!(validate_remote_file("/home/\0peer", "a.txt"), Err(RPathError::NulByte));
+        assert_eq!(validate_remote_file("/home/peer", "a\0.txt"), Err(RPathError::NulByte));
+    }
+
+    #[test]
+    fn remote_file_refuses_empty_file_name() {
+        assert_eq!(validate_remote_file("/home/peer", ""), Err(RPathError::Empty));
+        assert_eq!(validate_remote_file("/home/peer", "."), Err(RPathError::NotAFileName));
+    }
+}
@@ -0,0 +1,65 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Token bucket refilled at `rate` tokens per second, holding at most one
+/// second worth of tokens. A rate of `0` disables the limit.
+#[derive(Debug, Clone)]
+pub struct RRateLimiter {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RRateLimiter {
+    pub fn new(rate: u64) -> RRateLimiter {
+        RRateLimiter {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate == 0
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// Takes `n` tokens if they are available right now.
+    pub fn try_acquire(&mut self, n: u64) -> bool {
+        if self.is_unlimited() {
+            return true;
+        }
+
+        self.refill();
+
+        if self.tokens >= n as f64 {
+            self.tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes `n` tokens, sleeping until the bucket has paid them back.
+    ///
+    /// Requests larger than the bucket are allowed and leave it in debt.
+    pub fn acquire(&mut self, n: u64) {
+        if self.is_unlimited() {
+            return;
+        }
+
+        self.refill();
+        self.tokens -= n as f64;
+
+        if self.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-self.tokens / self.rate as f64));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+#![allow(dead_code)]
+
+//! Helpers shared by the integration tests, each using only some of them.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use diesel::connection::SimpleConnection;
+use diesel::SqliteConnection;
+
+use raidx::models::files::{NewRFile, RFile, FILE_STATUS_REPLICA};
+use raidx::models::nodes::RNode;
+use raidx::models::utils::connection;
+use raidx::peers::transport::RTransportKind;
+use raidx::utils::configs::{RConfig, RConfigNode};
+use raidx::{peers, placement};
+
+pub const NODES: usize = 3;
+const PORT: usize = 4000;
+
+/// Creates the database with every migration applied.
+pub fn create_database(path: &Path) {
+    let mut conn = connection::establish(path.to_str().unwrap()).unwrap();
+    let mut migrations: Vec<PathBuf> = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+
+    migrations.sort();
+
+    for migration in migrations {
+        let sql = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+        conn.batch_execute(sql.as_str()).unwrap();
+    }
+}
+
+/// A database and an empty shared folder of their own for test `name`.
+pub fn database(test: &str, name: &str) -> (RConfig, SqliteConnection) {
+    let root = std::env::temp_dir().join(format!("raidx-{}-{}-{}", test, name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let folder = root.join("data");
+    let database = root.join("db.sqlite");
+
+    std::fs::create_dir_all(&folder).unwrap();
+    create_database(&database);
+
+    let mut configs = RConfig::get_default(folder.to_str().unwrap().to_string());
+    configs.database.path = database.to_str().unwrap().to_string();
+
+    let conn = connection::establish(configs.database.path.as_str()).unwrap();
+
+    (configs, conn)
+}
+
+/// A replica of a file of `node`, stored with `content`.
+pub fn replica(conn: &mut SqliteConnection, folder: &str, node: &RNode, filename: &str, content: &[u8]) -> RFile {
+    let entry = std::path::Path::new(folder).join(filename);
+
+    NewRFile {
+        uid: RFile::calc_uid(&entry),
+        node: node.uid.clone(),
+        folder: folder.to_string(),
+        filename: filename.to_string(),
+        size: content.len() as i32,
+        status: FILE_STATUS_REPLICA.to_string(),
+        created_at: 0,
+        modified_at: 0,
+        updated_at: 0,
+        digest: Some(RFile::calc_digest_from_slice(content)),
+    }
+    .save(conn)
+    .unwrap()
+}
+
+pub fn node_configs(cluster: &str, index: usize) -> RConfigNode {
+    let mut node = RConfig::get_default(String::new()).server;
+
+    // Each node in the process is known by a host of its own.
+    node.host = format!("{}-{}", cluster, index);
+    node.port = PORT;
+    node.transport = RTransportKind::Memory;
+
+    node
+}
+
+pub fn configs(root: &Path, cluster: &str, index: usize) -> RConfig {
+    let folder = root.join(format!("data-{}", index));
+    let database = root.join(format!("db-{}.sqlite", index));
+
+    std::fs::create_dir_all(&folder).unwrap();
+    create_database(&database);
+
+    let mut configs = RConfig::get_default(folder.to_str().unwrap().to_string());
+
+    configs.server = node_configs(cluster, index);
+    configs.database.path = database.to_str().unwrap().to_string();
+    configs.nodes = (0..NODES).filter(|other| *other != index).map(|other| node_configs(cluster, other)).collect();
+    configs.cluster_secret = Some("memory-test".to_string());
+    configs.placement.replicas = NODES;
+    configs.rebalancer.timeout = 1;
+    configs.connections.ping_interval = 1;
+    configs.connections.min_backoff = 1;
+    configs.connections.max_backoff = 2;
+
+    configs
+}
+
+pub fn start(configs: &RConfig) {
+    raidx::peers::auth::RIdentity::load_or_create(configs).unwrap();
+
+    peers::watcher::init(configs.clone());
+    peers::synchronizer::init(configs.clone());
+    peers::nodes::init(configs.clone());
+    peers::dispatcher::init(configs.clone());
+    placement::rebalancer::init(configs.clone());
+    peers::scrubber::init(configs.clone());
+    peers::server::init(configs.clone());
+}
+
+/// Content that doesn't repeat, the same on every run.
+pub fn content(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Waits for every other node to hold `filename` with `content`.
+pub async fn wait_replicated(nodes: &[RConfig], filename: &str, content: &[u8]) {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let replicas: Vec<PathBuf> = nodes[1..].iter().map(|node| Path::new(&node.folder_path).join(filename)).collect();
+
+    while replicas.iter().any(|replica| std::fs::read(replica).ok().as_deref() != Some(content)) {
+        assert!(Instant::now() < deadline, "{} not replicated to every node", filename);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
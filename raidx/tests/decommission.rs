@@ -0,0 +1,75 @@
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use raidx::models::files::RFile;
+use raidx::models::nodes::{RDecommissionStatus, RNode};
+use raidx::models::replicas::RReplica;
+use raidx::models::utils::connection;
+use raidx::utils::configs::RConfig;
+
+use common::{configs, content, start, NODES};
+
+/// Whether node `index` holds `filename` with `content`.
+fn holds(nodes: &[RConfig], index: usize, filename: &str, content: &[u8]) -> bool {
+    std::fs::read(Path::new(&nodes[index].folder_path).join(filename)).ok().as_deref() == Some(content)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn draining_node_files_are_placed_before_it_is_removed() {
+    let root = std::env::temp_dir().join(format!("raidx-draining-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let nodes: Vec<RConfig> = (0..NODES).map(|index| configs(&root, "draining", index)).collect();
+    let drained = NODES - 1;
+    let content = content(10_000, 1);
+
+    // Only the drained node has it to begin with.
+    std::fs::write(Path::new(&nodes[drained].folder_path).join("unique.bin"), &content).unwrap();
+
+    for node in nodes.iter() {
+        start(node);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+
+    while !(0..drained).all(|index| holds(&nodes, index, "unique.bin", &content)) {
+        assert!(Instant::now() < deadline, "unique.bin not replicated");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    // Decommissioned from the first node, as `raidx decommission` does.
+    let mut conn = connection::establish(nodes[0].database.path.as_str()).unwrap();
+    let host = nodes[drained].server.host.clone();
+    let port = nodes[drained].server.port as i32;
+
+    loop {
+        let mut node = RNode::get_by_host_and_port(&mut conn, host.clone(), port).expect("drained node removed too early");
+
+        match node.decommission(&mut conn, &nodes[0]).unwrap() {
+            RDecommissionStatus::Removed => break,
+            RDecommissionStatus::Draining { .. } => {}
+        }
+
+        assert!(Instant::now() < deadline, "node not decommissioned");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    // Adopted here and confirmed on every other node left.
+    let local = RNode::get_local(&mut conn).unwrap();
+    let file = RFile::get_all(&mut conn).unwrap().into_iter().find(|file| file.filename == "unique.bin").unwrap();
+
+    assert_eq!(file.node, local.uid);
+
+    let replicas = RReplica::get_by_file(&mut conn, &file.uid).unwrap();
+
+    for index in 1..drained {
+        let node = RNode::get_by_host_and_port(&mut conn, nodes[index].server.host.clone(), nodes[index].server.port as i32).unwrap();
+
+        assert!(replicas.iter().any(|replica| replica.node == node.uid && replica.is_confirmed()));
+        assert!(holds(&nodes, index, "unique.bin", &content));
+    }
+
+    let _ = std::fs::remove_dir_all(&root);
+}
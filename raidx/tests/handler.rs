@@ -0,0 +1,96 @@
+mod common;
+
+use raidx::models::files::{NewRFile, RFile, FILE_STATUS_READY};
+use raidx::models::nodes::RNode;
+use raidx::models::replicas::RReplica;
+use raidx::protocol::handler;
+use raidx::protocol::message::{RErrorCode, RMChunk, RMChunkData, RMFileChunks, RMFileRequest, RMReplicaStored, RMessage};
+use raidx::utils::chunks::RChunkRef;
+use raidx::utils::configs::RConfig;
+
+/// A file of `node`, as announced by it.
+fn file(configs: &RConfig, node: &RNode) -> RFile {
+    RFile {
+        id: 0,
+        uid: "0a1b2c".to_string(),
+        node: node.uid.clone(),
+        folder: configs.folder_path.clone(),
+        filename: "file.bin".to_string(),
+        size: 5,
+        status: FILE_STATUS_READY.to_string(),
+        sync: false,
+        created_at: 0,
+        modified_at: 0,
+        updated_at: 0,
+        digest: None,
+        scrubbed_at: 0,
+    }
+}
+
+fn error_code(reply: Option<RMessage>) -> Option<RErrorCode> {
+    match reply {
+        Some(RMessage::Error(error)) => Some(error.code),
+        _ => None,
+    }
+}
+
+#[test]
+fn chunks_from_untrusted_nodes_are_refused() {
+    let (configs, mut conn) = common::database("handler", "chunks");
+
+    RNode::create_local(&mut conn, "local".to_string(), 4000).unwrap();
+
+    let trusted = RNode::create_other(&mut conn, "trusted".to_string(), 4001, 1, false).unwrap();
+    let mut untrusted = RNode::create_other(&mut conn, "untrusted".to_string(), 4002, 1, false).unwrap();
+    untrusted.set_untrusted(&mut conn, true).unwrap();
+
+    let chunks = vec![RChunkRef { hash: "00".repeat(32), size: 5 }];
+
+    let manifest = |node: &RNode| {
+        RMessage::FileChunks(RMFileChunks {
+            file: file(&configs, node),
+            path: "file.bin".to_string(),
+            chunks: chunks.clone(),
+        })
+    };
+
+    assert!(matches!(handler::handle(&mut conn, &configs, &trusted, manifest(&trusted)), Some(RMessage::ChunksWanted(_))));
+    assert_eq!(error_code(handler::handle(&mut conn, &configs, &untrusted, manifest(&untrusted))), Some(RErrorCode::PermissionDenied));
+
+    let data = RMessage::ChunkData(RMChunkData {
+        file: file(&configs, &untrusted),
+        path: "file.bin".to_string(),
+        chunks: chunks.clone(),
+        data: vec![RMChunk { index: 0, content: b"hello".to_vec() }],
+        offset: 0,
+    });
+
+    assert_eq!(error_code(handler::handle(&mut conn, &configs, &untrusted, data)), Some(RErrorCode::PermissionDenied));
+    assert!(!std::path::Path::new(&configs.folder_path).join("file.bin").exists());
+}
+
+#[test]
+fn replica_asked_back_is_unconfirmed_until_stored_again() {
+    let (configs, mut conn) = common::database("handler", "asked-back");
+
+    let local = RNode::create_local(&mut conn, "local".to_string(), 4000).unwrap();
+    let holder = RNode::create_other(&mut conn, "holder".to_string(), 4001, 1, false).unwrap();
+
+    let entry = std::path::Path::new(&configs.folder_path).join("file.bin");
+    std::fs::write(&entry, b"hello").unwrap();
+
+    let file = NewRFile::from_entry(&mut conn, &local, &entry).unwrap();
+
+    RReplica::create_pending(&mut conn, file.uid.clone(), holder.uid.clone(), file.digest.clone()).unwrap();
+    RReplica::confirm(&mut conn, &file.uid, &holder.uid).unwrap();
+
+    let request = RMessage::FileRequest(RMFileRequest { uid: "replica".to_string(), path: "file.bin".to_string() });
+
+    assert!(matches!(handler::handle(&mut conn, &configs, &holder, request), Some(RMessage::FileRepair(_))));
+    assert!(!RReplica::get_by_file(&mut conn, &file.uid).unwrap()[0].is_confirmed());
+
+    let stored = RMessage::ReplicaStored(RMReplicaStored { uid: file.uid.clone() });
+
+    assert!(handler::handle(&mut conn, &configs, &holder, stored).is_none());
+    assert!(RReplica::get_by_file(&mut conn, &file.uid).unwrap()[0].is_confirmed());
+}
@@ -0,0 +1,188 @@
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use diesel::SqliteConnection;
+
+use raidx::models::nodes::RNode;
+use raidx::models::queues::messages::RMessageQueue;
+use raidx::models::queues::messages_incoming::RMessagesIncoming;
+use raidx::models::utils::connection;
+use raidx::peers::auth::{self, RIdentity};
+use raidx::peers::transport::{self, RConnection, RPeerFrame};
+use raidx::peers::{limits, nodes, server};
+use raidx::protocol::codec::RCodec;
+use raidx::protocol::message::{REnvelope, RErrorCode, RMReplicaStored, RMessage};
+use raidx::utils::configs::{RConfig, RConfigLimits};
+
+use common::configs;
+
+/// A peer connected to the server of node 0, driven by hand instead of by a
+/// node of its own.
+struct RPeer {
+    server: RConfig,
+    conn: SqliteConnection,
+    client: RConnection,
+    codec: RCodec,
+    /// Uid of the peer in the database of node 0.
+    uid: String,
+}
+
+impl RPeer {
+    async fn connect(cluster: &str, limits: RConfigLimits) -> RPeer {
+        let root = std::env::temp_dir().join(format!("raidx-{}-{}", cluster, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let mut server = configs(&root, cluster, 0);
+        let peer = configs(&root, cluster, 1);
+
+        server.limits = limits;
+
+        // Only the server runs on node 0, nothing takes from its queue.
+        for node in [&server, &peer] {
+            RIdentity::load_or_create(node).unwrap();
+            nodes::load_nodes_from_configs(node);
+            nodes::load_local_node_from_configs(node);
+        }
+
+        server::init(server.clone());
+
+        let identity = RIdentity::load_or_create(&peer).unwrap();
+        let mut peer_conn = connection::establish(peer.database.path.as_str()).unwrap();
+        let target = RNode::get_by_host_and_port(&mut peer_conn, server.server.host.clone(), server.server.port as i32).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        let mut client = loop {
+            match transport::connect(&peer, &target).await {
+                Ok(client) => break client,
+                Err(e) => assert!(Instant::now() < deadline, "server not listening: {}", e),
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+
+        let session = auth::client_handshake(&mut client, &mut peer_conn, &peer, &identity, &target).await.unwrap();
+
+        let mut conn = connection::establish(server.database.path.as_str()).unwrap();
+        let uid = RNode::get_by_host_and_port(&mut conn, peer.server.host.clone(), peer.server.port as i32).unwrap().uid;
+
+        RPeer { server, conn, client, codec: RCodec::negotiate(&session), uid }
+    }
+
+    fn frame(&self, uid: usize) -> RPeerFrame {
+        let message = RMessage::ReplicaStored(RMReplicaStored { uid: format!("{:06}", uid) });
+
+        self.codec.to_frame(&REnvelope::new(message)).unwrap()
+    }
+
+    /// Frames sent back by the server until it closes the connection.
+    async fn replies(&mut self) -> Vec<RMessage> {
+        let mut replies = Vec::<RMessage>::new();
+
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(10), self.client.recv()).await.expect("connection left open");
+
+            match frame {
+                Some(Ok(RPeerFrame::Data(data))) => replies.push(self.codec.decode_message(data.as_slice()).unwrap().message),
+                Some(Ok(RPeerFrame::Close)) | Some(Err(_)) | None => return replies,
+                Some(Ok(_)) => (),
+            }
+        }
+    }
+
+    fn queued(&mut self) -> i64 {
+        RMessagesIncoming::count_by_node(&mut self.conn, &self.uid).unwrap()
+    }
+
+    fn cleanup(&self) {
+        let _ = std::fs::remove_dir_all(Path::new(&self.server.folder_path).parent().unwrap());
+    }
+}
+
+fn refused_with(replies: &[RMessage], code: RErrorCode) -> bool {
+    replies.iter().any(|reply| matches!(reply, RMessage::Error(error) if error.code == code))
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn flooding_peer_is_disconnected_and_banned() {
+    let limits = RConfigLimits { max_messages_per_second: 5, ..RConfigLimits::default() };
+    let mut peer = RPeer::connect("flooding", limits).await;
+
+    for uid in 0..50 {
+        let frame = peer.frame(uid);
+
+        if peer.client.send(frame).await.is_err() {
+            break;
+        }
+    }
+
+    let replies = peer.replies().await;
+
+    assert!(refused_with(&replies, RErrorCode::RateLimited), "{:?}", replies);
+    assert!(limits::is_banned(&peer.uid));
+    assert!(peer.queued() < 50);
+
+    peer.cleanup();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn oversized_frame_is_refused() {
+    let limits = RConfigLimits { max_frame_size: 1024, ..RConfigLimits::default() };
+    let mut peer = RPeer::connect("oversized", limits).await;
+
+    let small = peer.frame(0);
+    peer.client.send(small).await.unwrap();
+    peer.client.send(RPeerFrame::Data(vec![0u8; 4096])).await.unwrap();
+
+    let replies = peer.replies().await;
+
+    assert!(refused_with(&replies, RErrorCode::QuotaExceeded), "{:?}", replies);
+    assert!(limits::is_banned(&peer.uid));
+    assert_eq!(peer.queued(), 1);
+
+    peer.cleanup();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn incoming_queue_stays_bounded() {
+    let limits = RConfigLimits { max_incoming: 3, ..RConfigLimits::default() };
+    let mut peer = RPeer::connect("bounded", limits).await;
+
+    for uid in 0..10 {
+        let frame = peer.frame(uid);
+        peer.client.send(frame).await.unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    while peer.queued() < 3 {
+        assert!(Instant::now() < deadline, "messages not queued");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // Reading stays paused while the queue is full.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(peer.queued(), 3);
+
+    // Emptied the way the dispatcher would, the rest comes in.
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut handled = 0;
+
+    while handled < 10 {
+        assert!(Instant::now() < deadline, "only {} messages handled", handled);
+
+        if RMessagesIncoming::pop(&mut peer.conn).is_ok() {
+            handled += 1;
+        } else {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert!(peer.queued() <= 3);
+    }
+
+    assert!(!limits::is_banned(&peer.uid));
+
+    peer.cleanup();
+}
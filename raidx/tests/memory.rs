@@ -0,0 +1,89 @@
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use raidx::utils::configs::RConfig;
+
+use common::{configs, content, start, wait_replicated, NODES};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn file_replicates_over_memory_transport() {
+    let root = std::env::temp_dir().join(format!("raidx-memory-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let nodes: Vec<RConfig> = (0..NODES).map(|index| configs(&root, "node", index)).collect();
+    let content = b"replicated over channels".repeat(64);
+
+    std::fs::write(Path::new(&nodes[0].folder_path).join("hello.txt"), &content).unwrap();
+
+    for node in nodes.iter() {
+        start(node);
+    }
+
+    wait_replicated(&nodes, "hello.txt", &content).await;
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn file_larger_than_a_frame_replicates_in_parts() {
+    let root = std::env::temp_dir().join(format!("raidx-parts-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let mut nodes: Vec<RConfig> = (0..NODES).map(|index| configs(&root, "parts", index)).collect();
+
+    for node in nodes.iter_mut() {
+        node.limits.max_frame_size = 64 * 1024;
+    }
+
+    // Sent as chunks, and whole as it is below `chunking.min_size`.
+    let chunked = content(700_000, 1);
+    let whole = content(200_000, 2);
+
+    std::fs::write(Path::new(&nodes[0].folder_path).join("chunked.bin"), &chunked).unwrap();
+    std::fs::write(Path::new(&nodes[0].folder_path).join("whole.bin"), &whole).unwrap();
+
+    for node in nodes.iter() {
+        start(node);
+    }
+
+    wait_replicated(&nodes, "chunked.bin", &chunked).await;
+    wait_replicated(&nodes, "whole.bin", &whole).await;
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn live_changes_reach_every_node() {
+    let root = std::env::temp_dir().join(format!("raidx-live-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let nodes: Vec<RConfig> = (0..NODES).map(|index| configs(&root, "live", index)).collect();
+    let entry = Path::new(&nodes[0].folder_path).join("live.bin");
+    let mut live = content(300_000, 3);
+
+    for node in nodes.iter() {
+        start(node);
+    }
+
+    // Created, then edited in place while the nodes run: the edit is sent
+    // as a delta against the replicas.
+    std::fs::write(&entry, &live).unwrap();
+    wait_replicated(&nodes, "live.bin", &live).await;
+
+    live[150_000..150_100].copy_from_slice(&[7u8; 100]);
+    std::fs::write(&entry, &live).unwrap();
+    wait_replicated(&nodes, "live.bin", &live).await;
+
+    std::fs::remove_file(&entry).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+
+    while nodes[1..].iter().any(|node| Path::new(&node.folder_path).join("live.bin").exists()) {
+        assert!(Instant::now() < deadline, "live.bin not removed from every node");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    let _ = std::fs::remove_dir_all(&root);
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use raidx::models::files::NewRFile;
+use raidx::models::nodes::RNode;
+use raidx::models::queues::messages::RMessageQueue;
+use raidx::models::queues::messages_outgoing::RMessageOutgoing;
+use raidx::models::replicas::{RReplica, REPLICA_STATUS_PENDING};
+use raidx::models::utils::connection;
+use raidx::placement::rebalancer;
+use raidx::protocol::handler;
+use raidx::protocol::message::{RMReplicaStored, RMessage};
+use raidx::utils::configs::RConfig;
+
+use common::{configs, content, start, wait_replicated, NODES};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn new_node_receives_its_files() {
+    let root = std::env::temp_dir().join(format!("raidx-joining-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let nodes: Vec<RConfig> = (0..NODES).map(|index| configs(&root, "joining", index)).collect();
+    let content = content(10_000, 1);
+
+    std::fs::write(Path::new(&nodes[0].folder_path).join("early.bin"), &content).unwrap();
+
+    for node in nodes[..NODES - 1].iter() {
+        start(node);
+    }
+
+    wait_replicated(&nodes[..NODES - 1], "early.bin", &content).await;
+
+    // The last node comes once the others are balanced.
+    start(&nodes[NODES - 1]);
+
+    wait_replicated(&nodes, "early.bin", &content).await;
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn throttling_caps_transfers_in_flight() {
+    let root = std::env::temp_dir().join(format!("raidx-throttled-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let mut nodes: Vec<RConfig> = (0..NODES).map(|index| configs(&root, "throttled", index)).collect();
+    nodes[0].rebalancer.max_transfers = 1;
+
+    let files: Vec<(String, Vec<u8>)> = (0..4).map(|index| (format!("file-{}.bin", index), content(10_000, index))).collect();
+
+    for (filename, content) in files.iter() {
+        std::fs::write(Path::new(&nodes[0].folder_path).join(filename), content).unwrap();
+    }
+
+    for node in nodes.iter() {
+        start(node);
+    }
+
+    let mut conn = connection::establish(nodes[0].database.path.as_str()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(60);
+
+    loop {
+        let pending = RReplica::count_by_status(&mut conn, REPLICA_STATUS_PENDING).unwrap();
+        assert!(pending <= 1, "{} transfers in flight", pending);
+
+        let replicated = files.iter().all(|(filename, content)| {
+            nodes[1..].iter().all(|node| std::fs::read(Path::new(&node.folder_path).join(filename)).ok().as_ref() == Some(content))
+        });
+
+        if replicated {
+            break;
+        }
+
+        assert!(Instant::now() < deadline, "files not replicated to every node");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn surplus_copy_removed_only_after_replica_stored() {
+    let (mut configs, mut conn) = common::database("rebalancer", "surplus");
+
+    configs.placement.replicas = 2;
+    configs.rebalancer.timeout = 0;
+
+    RNode::create_local(&mut conn, "local".to_string(), 4000).unwrap();
+
+    // The ring places the file here and on `target` only, `surplus` holds
+    // a copy from an older placement.
+    let target = RNode::create_other(&mut conn, "target".to_string(), 4001, 1, false).unwrap();
+    let surplus = RNode::create_other(&mut conn, "surplus".to_string(), 4002, 0, false).unwrap();
+    let local = RNode::get_local(&mut conn).unwrap();
+
+    let entry = Path::new(&configs.folder_path).join("file.bin");
+    std::fs::write(&entry, b"placed twice").unwrap();
+
+    let file = NewRFile::from_entry(&mut conn, &local, &entry).unwrap();
+
+    RReplica::create_pending(&mut conn, file.uid.clone(), surplus.uid.clone(), file.digest.clone()).unwrap();
+    RReplica::confirm(&mut conn, &file.uid, &surplus.uid).unwrap();
+
+    let progress = rebalancer::rebalance(&mut conn, &configs).unwrap();
+
+    assert_eq!(progress.scheduled, 1);
+    assert_eq!(progress.removed, 0);
+    assert_eq!(RMessageOutgoing::count_by_node(&mut conn, &surplus.uid).unwrap(), 0);
+
+    // Still waiting for the copy on `target`.
+    let progress = rebalancer::rebalance(&mut conn, &configs).unwrap();
+
+    assert_eq!(progress.pending, 1);
+    assert_eq!(progress.removed, 0);
+
+    let stored = RMessage::ReplicaStored(RMReplicaStored { uid: file.uid.clone() });
+    handler::handle(&mut conn, &configs, &target, stored);
+
+    let progress = rebalancer::rebalance(&mut conn, &configs).unwrap();
+
+    assert_eq!(progress.removed, 1);
+    assert_eq!(RMessageOutgoing::count_by_node(&mut conn, &surplus.uid).unwrap(), 1);
+    assert!(RReplica::get_by_file(&mut conn, &file.uid).unwrap().iter().all(|replica| replica.node == target.uid));
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use raidx::models::files::{RFile, FILE_STATUS_CORRUPTED};
+use raidx::models::nodes::RNode;
+use raidx::models::queues::messages::RMessageQueue;
+use raidx::models::queues::messages_outgoing::RMessageOutgoing;
+use raidx::peers::scrubber::{self, RScrubResult};
+use raidx::utils::rate::RRateLimiter;
+
+#[test]
+fn missing_file_is_asked_back() {
+    let (configs, mut conn) = common::database("scrubber", "missing");
+
+    let local = RNode::create_local(&mut conn, "local".to_string(), 4000).unwrap();
+    let owner = RNode::create_other(&mut conn, "owner".to_string(), 4001, 1, false).unwrap();
+
+    let mut file = common::replica(&mut conn, &configs.folder_path, &owner, "missing.bin", b"missing");
+    let mut limiter = RRateLimiter::new(0);
+    let mut repairs = Vec::new();
+
+    let result = scrubber::scrub_file(&mut conn, &configs, &mut limiter, &mut file, &local, &mut repairs);
+
+    assert_eq!(result, RScrubResult::Missing);
+    assert_eq!(repairs.len(), 1);
+    assert_eq!(RMessageOutgoing::count_by_node(&mut conn, &owner.uid).unwrap(), 1);
+
+    // Scrubbed, so the next rounds move on to other files.
+    let kept = RFile::get_by_uid(&mut conn, file.uid.clone()).unwrap();
+
+    assert_eq!(kept.status, FILE_STATUS_CORRUPTED);
+    assert!(kept.scrubbed_at > 0);
+}
+
+#[test]
+fn changed_replica_is_corrupted_whatever_its_time() {
+    let (configs, mut conn) = common::database("scrubber", "changed");
+
+    let local = RNode::create_local(&mut conn, "local".to_string(), 4000).unwrap();
+    let owner = RNode::create_other(&mut conn, "owner".to_string(), 4001, 1, false).unwrap();
+
+    // Its row says 0, the file is written now.
+    let mut file = common::replica(&mut conn, &configs.folder_path, &owner, "changed.bin", b"changed");
+    std::fs::write(file.abspath(), b"changed here").unwrap();
+
+    let digest = file.digest.clone();
+    let mut limiter = RRateLimiter::new(0);
+    let mut repairs = Vec::new();
+
+    let result = scrubber::scrub_file(&mut conn, &configs, &mut limiter, &mut file, &local, &mut repairs);
+
+    assert_eq!(result, RScrubResult::Corrupted);
+    assert_eq!(RFile::get_by_uid(&mut conn, file.uid.clone()).unwrap().digest, digest);
+    assert_eq!(repairs.len(), 1);
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use raidx::models::files::{RFile, FILE_STATUS_CORRUPTED, FILE_STATUS_REPLICA};
+use raidx::models::nodes::RNode;
+use raidx::models::queues::messages::RMessageQueue;
+use raidx::models::queues::messages_outgoing::RMessageOutgoing;
+use raidx::peers::synchronizer;
+
+#[test]
+fn damaged_replicas_are_asked_back_from_their_node() {
+    let (configs, mut conn) = common::database("synchronizer", "damaged");
+    let folder = configs.folder_path.clone();
+
+    let local = RNode::create_local(&mut conn, "local".to_string(), 4000).unwrap();
+    let owner = RNode::create_other(&mut conn, "owner".to_string(), 4001, 1, false).unwrap();
+
+    // Written after its row, as the handler stores them.
+    let intact = common::replica(&mut conn, &folder, &owner, "intact.bin", b"intact");
+    std::fs::write(intact.abspath(), b"intact").unwrap();
+
+    let changed = common::replica(&mut conn, &folder, &owner, "changed.bin", b"changed");
+    std::fs::write(changed.abspath(), b"changed here").unwrap();
+
+    let missing = common::replica(&mut conn, &folder, &owner, "missing.bin", b"missing");
+
+    let changes = synchronizer::detect_offline_changes(&mut conn, &configs, &local);
+
+    let mut damaged: Vec<String> = changes.damaged.iter().map(|file| file.filename.clone()).collect();
+    damaged.sort();
+
+    assert_eq!(damaged, vec!["changed.bin", "missing.bin"]);
+    assert!(changes.removed.is_empty() && changes.modified.is_empty());
+
+    // Rows kept with the digest of their node, out of use until repaired.
+    for file in [&changed, &missing] {
+        let kept = RFile::get_by_uid(&mut conn, file.uid.clone()).unwrap();
+
+        assert_eq!(kept.status, FILE_STATUS_CORRUPTED);
+        assert_eq!(kept.digest, file.digest);
+    }
+
+    assert_eq!(RFile::get_by_uid(&mut conn, intact.uid.clone()).unwrap().status, FILE_STATUS_REPLICA);
+
+    synchronizer::announce_offline_changes(&mut conn, &configs, &local, &changes);
+
+    assert_eq!(RMessageOutgoing::count_by_node(&mut conn, &owner.uid).unwrap(), 2);
+}